@@ -1,7 +1,17 @@
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use std::{cell::RefCell, rc::Rc};
+
+use gloo_timers::future::TimeoutFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::channel;
 use wasm_bindgen_futures::spawn_local;
 
-#[derive(Clone, Copy, Debug)]
+use crate::{
+    queue::{send_event, take_unreported, EventSender, DEFAULT_EVENT_CAPACITY},
+    upload::{codec, on_page_hide, upload_on_page_hide, upload_with_headers},
+    wire::DROPPED_HEADER,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum CaptureEvent {
     /// X Y position of a mousemove event.
     MouseMove {
@@ -23,28 +33,105 @@ pub enum CaptureEvent {
         y: i32,
     },
     Scoll {},
+    /// An uncaught error or unhandled promise rejection, at the line and column of the script it was thrown in.
+    /// Both are 0 for rejections and errors the browser doesn't locate.
+    Error {
+        line: u32,
+        column: u32,
+    },
+    /// A click with the node it landed on, recorders before it sent MouseClick. x and y are relative to the
+    /// top window's viewport like MouseClick's, page_x and page_y to its document. Clicks in an iframe also count
+    /// what the iframe was scrolled by, their page position is in the document with its frames unscrolled.
+    Click {
+        x: i32,
+        y: i32,
+        page_x: i32,
+        page_y: i32,
+        target: Option<u32>,
+    },
 }
 
+/// Events are posted once this many have been captured.
+const EVENT_CHUNK_LEN: usize = 50;
+/// Fewer events are posted after this long, so the last ones of a quiet page aren't held back.
+const EVENT_INTERVAL_MILLIS: u32 = 5_000;
+
+type Chunk = RefCell<Vec<(f64, CaptureEvent)>>;
+
 pub struct EventStream {
-    sender: UnboundedSender<CaptureEvent>,
+    sender: EventSender,
 }
 
 impl EventStream {
-    pub fn new() -> Self {
-        let (sender, mut receiver) = unbounded_channel();
+    /// Posts captured events to event_endpoint in chunks, each event with the performance.now() millis it was captured at.
+    /// Each chunk also reports what the recorder dropped since the last one, see DROPPED_HEADER.
+    /// Chunks are posted when they're full, every EVENT_INTERVAL_MILLIS and when the page is hidden or unloaded.
+    pub fn new<S: AsRef<str>>(event_endpoint: S) -> Self {
+        Self::with_capacity(event_endpoint, DEFAULT_EVENT_CAPACITY)
+    }
+    /// Holds at most capacity events that haven't been chunked yet, see send_event for what's dropped past that.
+    pub fn with_capacity<S: AsRef<str>>(event_endpoint: S, capacity: usize) -> Self {
+        let (sender, mut receiver) = channel(capacity);
+        let endpoint: Rc<str> = event_endpoint.as_ref().into();
+        let chunk = Rc::new(Chunk::default());
+        {
+            let endpoint = endpoint.clone();
+            let chunk = chunk.clone();
+            spawn_local(async move {
+                loop {
+                    TimeoutFuture::new(EVENT_INTERVAL_MILLIS).await;
+                    post_chunk(&endpoint, &chunk);
+                }
+            });
+        }
+        {
+            let endpoint = endpoint.clone();
+            let chunk = chunk.clone();
+            on_page_hide(move || {
+                if let Some(body) = take_chunk(&chunk) {
+                    upload_on_page_hide(&endpoint, codec().content_type(), body, dropped_headers());
+                }
+            });
+        }
         spawn_local(async move {
-            let mut chunk = Vec::new();
             while let Some(event) = receiver.recv().await {
-                chunk.push(event);
-                if chunk.len() > 5 {
-                    // web_sys::console::log_1(&format!("{chunk:#?}").into());
-                    chunk = Vec::new();
+                let is_full = {
+                    let mut chunk = chunk.borrow_mut();
+                    chunk.push(event);
+                    chunk.len() >= EVENT_CHUNK_LEN
+                };
+                if is_full {
+                    post_chunk(&endpoint, &chunk);
                 }
             }
         });
         EventStream { sender }
     }
     pub fn send(&self, event: CaptureEvent) {
-        self.sender.send(event).expect("Send to always be ok.")
+        send_event(&self.sender, event)
+    }
+    /// For capture_mouse.
+    pub fn sender(&self) -> EventSender {
+        self.sender.clone()
+    }
+}
+
+/// Takes the current chunk, if it isn't empty.
+fn take_chunk(chunk: &Chunk) -> Option<Vec<u8>> {
+    let events = std::mem::take(&mut *chunk.borrow_mut());
+    (!events.is_empty()).then(|| codec().encode_events(&events))
+}
+
+/// Reports what was dropped since the last chunk that did.
+fn dropped_headers() -> Vec<(String, String)> {
+    take_unreported()
+        .and_then(|dropped| serde_json::to_string(&dropped).ok())
+        .map(|dropped| vec![(DROPPED_HEADER.to_string(), dropped)])
+        .unwrap_or_default()
+}
+
+fn post_chunk(endpoint: &str, chunk: &Chunk) {
+    if let Some(body) = take_chunk(chunk) {
+        upload_with_headers(endpoint, codec().content_type(), body, dropped_headers());
     }
 }
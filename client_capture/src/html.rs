@@ -0,0 +1,444 @@
+//! Renders a VirtualDom as a static HTML document, a frozen view of the page that doesn't need the player.
+//! Scripts and event handlers are left out and relative URLs are made absolute against the recorded page.
+use crate::{vdom::VirtualDom, DocumentTypeNode, ElementNode, FormState, SerializedNode};
+
+/// Elements that can't have children, and don't get a closing tag.
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Attributes that hold a single URL.
+const URL_ATTRIBUTES: [&str; 8] = [
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "poster",
+    "background",
+    "data",
+    "xlink:href",
+];
+
+/// The document at the root of dom. page_url is the URL of the recorded page, relative URLs resolve against it.
+pub fn render(dom: &VirtualDom, page_url: Option<&str>) -> String {
+    let mut html = String::new();
+    if dom.root().is_some() {
+        render_children(dom, 0, page_url, &mut html);
+    }
+    html
+}
+
+fn render_children(dom: &VirtualDom, id: u32, page_url: Option<&str>, html: &mut String) {
+    for child in dom.children(id) {
+        render_node(dom, child, page_url, html);
+    }
+}
+
+fn render_node(dom: &VirtualDom, id: u32, page_url: Option<&str>, html: &mut String) {
+    let Some(node) = dom.get(id) else {
+        return;
+    };
+    match node {
+        SerializedNode::DocumentNode(_) => render_children(dom, id, page_url, html),
+        SerializedNode::DocumentTypeNode(DocumentTypeNode {
+            name,
+            public_id,
+            system_id,
+            ..
+        }) => {
+            html.push_str("<!DOCTYPE ");
+            html.push_str(if is_name(name) { name } else { "html" });
+            if !public_id.is_empty() {
+                html.push_str(&format!(" PUBLIC \"{}\"", escape_attribute(public_id)));
+            }
+            if !system_id.is_empty() {
+                if public_id.is_empty() {
+                    html.push_str(" SYSTEM");
+                }
+                html.push_str(&format!(" \"{}\"", escape_attribute(system_id)));
+            }
+            html.push('>');
+        }
+        SerializedNode::TextNode(this) => {
+            let text = this.text_content.as_deref().unwrap_or_default();
+            // text of a style element is css, escaping it would change the rules.
+            let in_style = matches!(
+                dom.parent(id).and_then(|parent| dom.get(parent)),
+                Some(SerializedNode::ElementNode(parent)) if parent.tag_name.eq_ignore_ascii_case("style")
+            );
+            if in_style {
+                html.push_str(&text.replace("</", "<\\/"));
+            } else {
+                html.push_str(&escape_text(text));
+            }
+        }
+        SerializedNode::CommentNode(this) => {
+            let text = this.text_content.as_deref().unwrap_or_default();
+            html.push_str(&format!("<!--{}-->", text.replace("-->", "-- >")));
+        }
+        SerializedNode::CDataNode(_) => {}
+        SerializedNode::ElementNode(el) => render_element(dom, el, page_url, html),
+    }
+}
+
+fn render_element(dom: &VirtualDom, el: &ElementNode, page_url: Option<&str>, html: &mut String) {
+    let tag_name = if el.is_svg {
+        el.tag_name.clone()
+    } else {
+        el.tag_name.to_lowercase()
+    };
+    if tag_name == "script" || is_refresh(el) {
+        return;
+    }
+    // custom elements aren't defined without their scripts, like rebuild they're shown as divs.
+    // so are elements whose tag name can't be written, createElementNS takes names html can't parse.
+    let tag_name = if (el.is_custom && !el.is_svg) || !is_tag_name(&tag_name) {
+        "div".to_string()
+    } else {
+        tag_name
+    };
+    // a captured iframe document is shown through srcdoc instead of loading the iframe's source again.
+    let frame_document = (tag_name == "iframe")
+        .then(|| {
+            dom.children(el.id)
+                .into_iter()
+                .find(|child| matches!(dom.get(*child), Some(SerializedNode::DocumentNode(_))))
+        })
+        .flatten();
+    let mut attributes = Vec::new();
+    for (name, value) in el.attributes.iter().flatten() {
+        let lower = name.to_lowercase();
+        if !is_name(name) || lower.starts_with("on") || (frame_document.is_some() && lower == "src")
+        {
+            continue;
+        }
+        if URL_ATTRIBUTES.contains(&lower.as_str()) {
+            if is_script_url(value) {
+                continue;
+            }
+            attributes.push((name.clone(), resolve(page_url, value)));
+        } else if lower == "srcset" {
+            attributes.push((name.clone(), resolve_srcset(page_url, value)));
+        } else if lower == "srcdoc" && frame_document.is_some() {
+            continue;
+        } else {
+            attributes.push((name.clone(), value.clone()));
+        }
+    }
+    if let Some(document) = frame_document {
+        let mut srcdoc = String::new();
+        render_node(dom, document, page_url, &mut srcdoc);
+        attributes.push(("srcdoc".to_string(), srcdoc));
+    }
+    let mut text_area_value = None;
+    if let Some(form_state) = &el.form_state {
+        form_attributes(&tag_name, form_state, &mut attributes, &mut text_area_value);
+    }
+    html.push('<');
+    html.push_str(&tag_name);
+    for (name, value) in attributes {
+        html.push_str(&format!(" {name}=\"{}\"", escape_attribute(&value)));
+    }
+    html.push('>');
+    if VOID_ELEMENTS.contains(&tag_name.as_str()) {
+        return;
+    }
+    // urls in inline styles are resolved against the document's base, which would be the server's.
+    if let Some(page_url) = page_url.filter(|_| tag_name == "head" && !has_base(dom, el.id)) {
+        html.push_str(&format!("<base href=\"{}\">", escape_attribute(page_url)));
+    }
+    match text_area_value {
+        Some(value) => html.push_str(&escape_text(&value)),
+        None if frame_document.is_none() => render_children(dom, el.id, page_url, html),
+        None => {}
+    }
+    html.push_str(&format!("</{tag_name}>"));
+}
+
+/// Live form state as the attributes that show it, a textarea's value is its text.
+fn form_attributes(
+    tag_name: &str,
+    form_state: &FormState,
+    attributes: &mut Vec<(String, String)>,
+    text_area_value: &mut Option<String>,
+) {
+    let mut set = |name: &str, value: Option<String>| {
+        attributes.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        if let Some(value) = value {
+            attributes.push((name.to_string(), value));
+        }
+    };
+    match tag_name {
+        "textarea" => *text_area_value = form_state.value.clone(),
+        "input" => {
+            if let Some(value) = &form_state.value {
+                set("value", Some(value.clone()));
+            }
+            if let Some(checked) = form_state.checked {
+                set("checked", checked.then(String::new));
+            }
+        }
+        "option" => {
+            if let Some(selected) = form_state.selected {
+                set("selected", selected.then(String::new));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_base(dom: &VirtualDom, head: u32) -> bool {
+    dom.children(head).into_iter().any(|child| {
+        matches!(dom.get(child), Some(SerializedNode::ElementNode(el)) if el.tag_name.eq_ignore_ascii_case("base"))
+    })
+}
+
+/// Whether name can be written as an attribute name as is, setAttribute takes names that would end the tag
+/// or start another attribute.
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|char| char.is_whitespace() || char.is_control() || "\"'<>/=".contains(char))
+}
+
+/// Like is_name, and starting with a letter like the html parser expects after <.
+fn is_tag_name(name: &str) -> bool {
+    is_name(name) && name.starts_with(|char: char| char.is_ascii_alphabetic())
+}
+
+/// meta http-equiv="refresh" would navigate away from the frozen page.
+fn is_refresh(el: &ElementNode) -> bool {
+    el.tag_name.eq_ignore_ascii_case("meta")
+        && el.attributes.iter().flatten().any(|(name, value)| {
+            name.eq_ignore_ascii_case("http-equiv") && value.eq_ignore_ascii_case("refresh")
+        })
+}
+
+fn is_script_url(value: &str) -> bool {
+    let value = value
+        .chars()
+        .filter(|char| !char.is_ascii_whitespace() && !char.is_ascii_control())
+        .collect::<String>()
+        .to_lowercase();
+    value.starts_with("javascript:") || value.starts_with("vbscript:")
+}
+
+/// The scheme of url, if it has one.
+fn scheme(url: &str) -> Option<&str> {
+    let (scheme, _) = url.split_once(':')?;
+    let mut chars = scheme.chars();
+    (chars.next()?.is_ascii_alphabetic()
+        && chars.all(|char| char.is_ascii_alphanumeric() || "+-.".contains(char)))
+    .then_some(scheme)
+}
+
+/// url made absolute against base. Dot segments are left for the browser to resolve.
+fn resolve(base: Option<&str>, url: &str) -> String {
+    let url = url.trim();
+    let Some(base) = base.filter(|base| scheme(base).is_some()) else {
+        return url.to_string();
+    };
+    if url.is_empty() || url.starts_with('#') || scheme(url).is_some() {
+        return url.to_string();
+    }
+    let scheme = scheme(base).unwrap_or_default();
+    let rest = &base[scheme.len() + 1..];
+    if let Some(url) = url.strip_prefix("//") {
+        return format!("{scheme}://{url}");
+    }
+    // scheme://authority, everything before the path.
+    let origin_len = rest
+        .strip_prefix("//")
+        .map(|authority| {
+            scheme.len() + 3 + authority.find(['/', '?', '#']).unwrap_or(authority.len())
+        })
+        .unwrap_or(scheme.len() + 1);
+    let origin = &base[..origin_len];
+    let path_end = base[origin_len..]
+        .find(['?', '#'])
+        .map_or(base.len(), |end| origin_len + end);
+    if url.starts_with('/') {
+        format!("{origin}{url}")
+    } else if url.starts_with('?') {
+        format!("{}{url}", &base[..path_end])
+    } else {
+        let directory_end = base[origin_len..path_end]
+            .rfind('/')
+            .map_or(origin_len, |slash| origin_len + slash + 1);
+        let directory = &base[..directory_end];
+        if directory_end == origin_len {
+            format!("{directory}/{url}")
+        } else {
+            format!("{directory}{url}")
+        }
+    }
+}
+
+/// Every URL of a srcset made absolute, their descriptors are kept.
+fn resolve_srcset(base: Option<&str>, srcset: &str) -> String {
+    srcset
+        .split(',')
+        .map(|candidate| {
+            let candidate = candidate.trim();
+            match candidate.split_once(char::is_whitespace) {
+                Some((url, descriptor)) => format!("{} {}", resolve(base, url), descriptor.trim()),
+                None => resolve(base, candidate),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attribute(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::DocumentNode;
+
+    const PAGE: Option<&str> = Some("https://shop.test/en/cart/items?page=2#top");
+
+    #[test]
+    fn resolves_against_the_page() {
+        let cases = [
+            ("img.png", "https://shop.test/en/cart/img.png"),
+            ("../up.css", "https://shop.test/en/cart/../up.css"),
+            ("/root.js", "https://shop.test/root.js"),
+            ("//cdn.test/a.png", "https://cdn.test/a.png"),
+            ("?page=3", "https://shop.test/en/cart/items?page=3"),
+            ("  padded.png ", "https://shop.test/en/cart/padded.png"),
+        ];
+        for (url, resolved) in cases {
+            assert_eq!(resolve(PAGE, url), resolved, "{url}");
+        }
+    }
+
+    #[test]
+    fn leaves_absolute_and_fragment_urls() {
+        for url in [
+            "#reviews",
+            "",
+            "http://other.test/x",
+            "data:image/png;base64,AA",
+            "mailto:a@b.test",
+        ] {
+            assert_eq!(resolve(PAGE, url), url);
+        }
+        // without a page, or a page url without a scheme, there's nothing to resolve against.
+        assert_eq!(resolve(None, "img.png"), "img.png");
+        assert_eq!(resolve(Some("/en/cart"), "img.png"), "img.png");
+    }
+
+    #[test]
+    fn resolves_against_pages_at_the_root() {
+        assert_eq!(
+            resolve(Some("https://shop.test"), "a.png"),
+            "https://shop.test/a.png"
+        );
+        assert_eq!(
+            resolve(Some("https://shop.test/"), "a.png"),
+            "https://shop.test/a.png"
+        );
+        assert_eq!(
+            resolve(Some("https://shop.test?q=1"), "?q=2"),
+            "https://shop.test?q=2"
+        );
+        assert_eq!(
+            resolve(Some("file:/tmp/page.html"), "a.png"),
+            "file:/tmp/a.png"
+        );
+    }
+
+    #[test]
+    fn resolves_every_srcset_candidate() {
+        assert_eq!(
+            resolve_srcset(PAGE, "small.png 480w,  /large.png   1080w, https://cdn.test/x.png 2x"),
+            "https://shop.test/en/cart/small.png 480w, https://shop.test/large.png 1080w, https://cdn.test/x.png 2x"
+        );
+        assert_eq!(
+            resolve_srcset(PAGE, "only.png"),
+            "https://shop.test/en/cart/only.png"
+        );
+    }
+
+    fn page(tag_name: &str, attributes: &[(&str, &str)]) -> VirtualDom {
+        let document = SerializedNode::DocumentNode(DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(vec![1]),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: None,
+            scroll: None,
+        });
+        let element = SerializedNode::ElementNode(ElementNode {
+            id: 1,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: tag_name.to_string(),
+            attributes: Some(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            child_nodes: Some(Vec::new()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        });
+        VirtualDom::new(HashMap::from([(0, document), (1, element)]))
+    }
+
+    #[test]
+    fn leaves_out_attribute_names_that_cant_be_written() {
+        let dom = page(
+            "p",
+            &[
+                ("class", "a"),
+                ("x onclick", "alert(1)"),
+                ("a\"b", "1"),
+                ("c'd", "1"),
+                ("<e", "1"),
+                ("f>", "1"),
+                ("g/h", "1"),
+                ("i=j", "1"),
+                ("", "1"),
+                ("k\tl", "1"),
+                ("data-ok", "\"quoted\""),
+            ],
+        );
+        assert_eq!(
+            render(&dom, None),
+            "<p class=\"a\" data-ok=\"&quot;quoted&quot;\"></p>"
+        );
+    }
+
+    #[test]
+    fn shows_tag_names_that_cant_be_written_as_divs() {
+        for tag_name in ["img src=x", "a>b", "p/", "1x", "x\"y", ""] {
+            assert_eq!(
+                render(&page(tag_name, &[]), None),
+                "<div></div>",
+                "{tag_name:?}"
+            );
+        }
+        assert_eq!(render(&page("my:tag", &[]), None), "<my:tag></my:tag>");
+    }
+}
@@ -1,4 +1,5 @@
 pub mod event_stream;
+pub mod html;
 use std::{cell::RefCell, collections::HashMap};
 
 pub use event_stream::*;
@@ -14,8 +15,17 @@ pub mod rebuild;
 pub use mutation_stream::*;
 pub mod replay;
 pub use replay::*;
+pub mod live;
+pub mod player;
+pub mod queue;
+pub mod rrweb;
+pub mod socket;
+pub mod upload;
+pub mod validate;
+pub mod vdom;
+pub mod wire;
 
-use web_sys::{Node, Window};
+use web_sys::{HtmlIFrameElement, MutationObserver, Node, Window};
 
 pub fn window() -> Window {
     WINDOW.with(Clone::clone)
@@ -31,9 +41,25 @@ thread_local! {
     pub static REVERSE_NODE_MAP : js_sys::Map = js_sys::Map::new();
     pub static SERIALIZED_NODE_MAP: RefCell<HashMap<u32, SerializedNode>> = RefCell::new(HashMap::new());
     pub static SERIALIZED_NODE_MAP_REPLAY: RefCell<HashMap<u32, SerializedNode>> = RefCell::new(HashMap::new());
-    pub static NODE_ID : RefCell<u32> = RefCell::new(0);
-    pub static ROOTS : RefCell<Vec<(Node,u32)>> = RefCell::new(Vec::new());
+    pub static NODE_ID : RefCell<u32> = const { RefCell::new(0) };
+    pub static ROOTS : RefCell<Vec<(Node,u32)>> = const { RefCell::new(Vec::new()) };
     pub static WINDOW: web_sys::Window = web_sys::window().expect("valid window");
     pub static SNAPSHOT_TIME : f64 = timestamp();
-    pub static TIME_OF_LAST_MUTATION : RefCell<f64> = RefCell::new(0.);
+    pub static TIME_OF_LAST_MUTATION : RefCell<f64> = const { RefCell::new(0.) };
+    /// Same-origin iframes whose documents were serialized but aren't observed yet.
+    pub static PENDING_FRAMES : RefCell<Vec<HtmlIFrameElement>> = const { RefCell::new(Vec::new()) };
+    pub static OBSERVED_FRAMES : js_sys::WeakSet = js_sys::WeakSet::new();
+    /// Set by capture_mouse so iframes found later can send their events to the same stream.
+    pub static CAPTURE_EVENT_SENDER : RefCell<Option<queue::EventSender>> = const { RefCell::new(None) };
+    pub static SESSION_ID : String = upload::new_session_id();
+    pub static UPLOADS : RefCell<upload::Uploads> = RefCell::new(upload::Uploads::default());
+    pub static SOCKET : RefCell<socket::Socket> = RefCell::new(socket::Socket::default());
+    pub static BACKPRESSURE : RefCell<queue::Backpressure> = RefCell::new(queue::Backpressure::default());
+    /// Every observer observe created with the node it observes, so a keyframe can discard the records they haven't
+    /// delivered yet and a removed frame's observer can be disconnected.
+    pub static OBSERVERS : RefCell<Vec<(Node, MutationObserver)>> = const { RefCell::new(Vec::new()) };
+    /// The node and endpoint snapshot was called with, keyframes are taken from and uploaded to the same.
+    pub static KEYFRAME_SOURCE : RefCell<Option<(Node, String)>> = const { RefCell::new(None) };
+    /// The replay iframe and the recorded viewport it's currently sized to.
+    pub static REPLAY_VIEWPORT : RefCell<Option<(HtmlIFrameElement, Viewport)>> = const { RefCell::new(None) };
 }
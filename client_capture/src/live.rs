@@ -0,0 +1,104 @@
+//! Follows a session while it's being recorded, from replay-server's /api/projects/:project/sessions/:id/live.
+use std::{cell::RefCell, rc::Rc};
+
+use gloo_timers::future::TimeoutFuture;
+use js_sys::Uint8Array;
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{BinaryType, Event, MessageEvent, WebSocket};
+
+use crate::{
+    rebuild::rebuild,
+    utils::log,
+    wire::{Bincode, Codec, PayloadKind, WireError},
+};
+
+/// How long to wait before reconnecting when the live socket closes.
+const RECONNECT_MILLIS: u32 = 1_000;
+
+/// A session being followed, see spectate. Clones stop the same following.
+#[derive(Clone, Default)]
+pub struct Spectator {
+    state: Rc<RefCell<SpectatorState>>,
+}
+
+#[derive(Default)]
+struct SpectatorState {
+    socket: Option<WebSocket>,
+    is_stopped: bool,
+}
+
+impl Spectator {
+    /// Closes the socket and stops reconnecting, pages call this when the iframe is taken off the page.
+    pub fn stop(&self) {
+        let mut state = self.state.borrow_mut();
+        state.is_stopped = true;
+        if let Some(socket) = state.socket.take() {
+            _ = socket.close();
+        }
+    }
+}
+
+/// Rebuilds every keyframe the server sends into the iframe and applies mutations and events as soon as they arrive.
+/// The server starts with the latest keyframe and the mutations since, and starts over the same way on reconnect.
+/// Following goes on, through reconnects, until the returned Spectator is stopped.
+pub fn spectate<S: AsRef<str>>(iframe_id: S, url: S) -> Result<Spectator, JsValue> {
+    let spectator = Spectator::default();
+    connect(
+        spectator.clone(),
+        iframe_id.as_ref().to_string(),
+        url.as_ref().to_string(),
+    )?;
+    Ok(spectator)
+}
+
+fn connect(spectator: Spectator, iframe_id: String, url: String) -> Result<(), JsValue> {
+    let socket = WebSocket::new(&url)?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+    let on_message = {
+        let iframe_id = iframe_id.clone();
+        Closure::wrap(Box::new(move |event: MessageEvent| {
+            let bytes = Uint8Array::new(&event.data()).to_vec();
+            if let Err(err) = apply(&iframe_id, &bytes) {
+                log(format!("couldn't apply live payload: {err}"));
+            }
+        }) as Box<dyn FnMut(_)>)
+    };
+    let on_close = {
+        let spectator = spectator.clone();
+        Closure::once(Box::new(move |_: Event| {
+            spawn_local(async move {
+                TimeoutFuture::new(RECONNECT_MILLIS).await;
+                if spectator.state.borrow().is_stopped {
+                    return;
+                }
+                if let Err(err) = connect(spectator, iframe_id, url) {
+                    log(format!("couldn't reconnect live socket: {err:?}"));
+                }
+            });
+        }) as Box<dyn FnOnce(_)>)
+    };
+    socket.set_onmessage(Some(on_message.into_js_value().unchecked_ref()));
+    socket.set_onclose(Some(on_close.into_js_value().unchecked_ref()));
+    spectator.state.borrow_mut().socket = Some(socket);
+    Ok(())
+}
+
+fn apply(iframe_id: &str, bytes: &[u8]) -> Result<(), WireError> {
+    match Bincode.payload_kind(bytes)? {
+        PayloadKind::Snapshot => {
+            rebuild(iframe_id, Bincode.decode_snapshot(bytes)?).map_err(WireError::Malformed)?;
+        }
+        PayloadKind::Mutations => {
+            for mutation in Bincode.decode_mutations(bytes)? {
+                mutation.replay();
+            }
+        }
+        PayloadKind::Events => {
+            for (_, event) in Bincode.decode_events(bytes)? {
+                event.replay();
+            }
+        }
+    }
+    Ok(())
+}
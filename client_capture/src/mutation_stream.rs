@@ -1,14 +1,37 @@
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::{cell::RefCell, rc::Rc};
+
+use gloo_timers::future::TimeoutFuture;
+use tokio::sync::mpsc::{channel, Receiver};
 use wasm_bindgen_futures::spawn_local;
 
-use crate::{timestamp, MutationVariant};
+use crate::{
+    queue::{
+        is_keyframe_pending, take_coalesced, MutationOverflow, MutationSender,
+        DEFAULT_MAX_CALLBACK_NODES, DEFAULT_MUTATION_CAPACITY,
+    },
+    snapshot::keyframe,
+    upload::{codec, on_page_hide, upload, upload_on_page_hide},
+    MutationVariant,
+};
+
+/// sendBeacon rejects bodies over 64KiB in most browsers, keeping chunks under it lets the final flush use a beacon.
+/// Beacons can't set Content-Encoding so they go uncompressed, chunks are measured as the codec encodes them.
+pub const DEFAULT_MAX_CHUNK_BYTES: u64 = 64 * 1024;
 
 pub struct MutationStream<S: AsRef<str>> {
-    pub sender: UnboundedSender<MutationVariant>,
-    receiver: UnboundedReceiver<MutationVariant>,
+    pub sender: MutationSender,
+    receiver: Receiver<MutationVariant>,
     mutation_endpoint: S,
     interval_millis: f64,
-    chunk: Vec<MutationVariant>,
+    max_chunk_bytes: u64,
+    chunk: Rc<RefCell<Chunk>>,
+}
+
+#[derive(Default)]
+struct Chunk {
+    mutations: Vec<MutationVariant>,
+    /// The size of mutations in the upload codec, see Codec::mutation_len.
+    bytes: u64,
 }
 
 impl<S> MutationStream<S>
@@ -16,36 +39,108 @@ where
     S: AsRef<str>,
 {
     pub fn new(mutation_endpoint: S, interval_millis: f64) -> Self {
-        let (sender, receiver) = unbounded_channel();
+        let (sender, receiver) = channel(DEFAULT_MUTATION_CAPACITY);
         Self {
-            sender,
+            sender: MutationSender {
+                sender,
+                overflow: MutationOverflow::default(),
+                max_callback_nodes: DEFAULT_MAX_CALLBACK_NODES,
+            },
             receiver,
             mutation_endpoint,
             interval_millis,
-            chunk: Vec::new(),
+            max_chunk_bytes: DEFAULT_MAX_CHUNK_BYTES,
+            chunk: Rc::new(RefCell::new(Chunk::default())),
         }
     }
-    /// Will aggregate Mutations and then post them to the digest endpoint at the given interval.
+    /// Posts the chunk as soon as it grows past this many bytes, without waiting for the interval.
+    pub fn max_chunk_bytes(mut self, max_chunk_bytes: u64) -> Self {
+        self.max_chunk_bytes = max_chunk_bytes;
+        self
+    }
+    /// How many mutations can wait to be chunked, call this before handing out the sender.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        let (sender, receiver) = channel(capacity);
+        self.sender.sender = sender;
+        self.receiver = receiver;
+        self
+    }
+    /// What to do with mutations that don't fit the queue.
+    pub fn overflow(mut self, overflow: MutationOverflow) -> Self {
+        self.sender.overflow = overflow;
+        self
+    }
+    /// How many added nodes one observer callback may serialize, past it the recorder takes a keyframe instead,
+    /// call this before handing out the sender.
+    pub fn max_callback_nodes(mut self, max_callback_nodes: usize) -> Self {
+        self.sender.max_callback_nodes = max_callback_nodes;
+        self
+    }
+    /// Will aggregate Mutations and then post them to the digest endpoint at the given interval,
+    /// or sooner if the chunk exceeds the max chunk size. Whatever is left is sent with a beacon when the page is hidden or unloaded.
     pub async fn receive_and_post(&mut self) {
-        let mut ts = timestamp();
+        let endpoint: Rc<str> = self.mutation_endpoint.as_ref().into();
+        // flush on a timer so the last batch doesn't wait for a mutation that might never come.
+        {
+            let endpoint = endpoint.clone();
+            let chunk = self.chunk.clone();
+            let sender = self.sender.sender.clone();
+            let interval_millis = self.interval_millis as u32;
+            spawn_local(async move {
+                loop {
+                    TimeoutFuture::new(interval_millis).await;
+                    post_chunk(&endpoint, &chunk);
+                    // a keyframe requested while no mutations are coming, e.g. after uploads were evicted.
+                    if is_keyframe_pending() && sender.capacity() == sender.max_capacity() {
+                        keyframe();
+                    }
+                }
+            });
+        }
+        flush_on_page_hide(endpoint.clone(), self.chunk.clone());
         while let Some(mutation) = self.receiver.recv().await {
-            self.chunk.push(mutation);
-            let now = timestamp();
-            // if current timestamp is 500 millis greater than last timestamp
-            if now > ts + self.interval_millis {
-                let chunk = bincode::serialize(&self.chunk).unwrap();
-                let endpoint = self.mutation_endpoint.as_ref().to_string();
-                spawn_local(async move {
-                    gloo_net::http::Request::post(&endpoint)
-                        .body(chunk)
-                        .expect("body ody ody")
-                        .send()
-                        .await
-                        .expect("Server to receive request.");
-                });
-                self.chunk = Vec::new();
-                ts = now;
+            let mut mutations = vec![mutation];
+            if self.receiver.is_empty() {
+                // coalesced changes are newer than anything that was queued.
+                mutations.extend(take_coalesced());
+            }
+            for mutation in mutations {
+                let is_full = {
+                    let mut chunk = self.chunk.borrow_mut();
+                    chunk.bytes += codec().mutation_len(&mutation);
+                    chunk.mutations.push(mutation);
+                    chunk.bytes >= self.max_chunk_bytes
+                };
+                if is_full {
+                    post_chunk(&endpoint, &self.chunk);
+                }
+            }
+            if self.receiver.is_empty() && is_keyframe_pending() {
+                // what's chunked belongs to the previous keyframe.
+                post_chunk(&endpoint, &self.chunk);
+                keyframe();
             }
         }
     }
 }
+
+/// Takes the current chunk, if it isn't empty.
+fn take_chunk(chunk: &RefCell<Chunk>) -> Option<Vec<u8>> {
+    let Chunk { mutations, .. } = std::mem::take(&mut *chunk.borrow_mut());
+    (!mutations.is_empty()).then(|| codec().encode_mutations(&mutations))
+}
+
+fn post_chunk(endpoint: &str, chunk: &RefCell<Chunk>) {
+    if let Some(body) = take_chunk(chunk) {
+        upload(endpoint, codec().content_type(), body);
+    }
+}
+
+/// Sends the remaining chunk when the page is hidden or unloaded.
+fn flush_on_page_hide(endpoint: Rc<str>, chunk: Rc<RefCell<Chunk>>) {
+    on_page_hide(move || {
+        if let Some(body) = take_chunk(&chunk) {
+            upload_on_page_hide(&endpoint, codec().content_type(), body, Vec::new());
+        }
+    });
+}
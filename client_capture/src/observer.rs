@@ -1,27 +1,35 @@
 use std::collections::HashMap;
 
 use js_sys::{Array, Function};
-use tokio::sync::mpsc::UnboundedSender;
 use wasm_bindgen::prelude::*;
-use web_sys::{MutationObserver, MutationObserverInit, MutationRecord, Node};
+use web_sys::{
+    Event, HtmlIFrameElement, MutationObserver, MutationObserverInit, MutationRecord, Node,
+};
 
 use crate::{
-    MutationVariant, SerializedNode, NODE_ID, NODE_MAP, REVERSE_NODE_MAP, SERIALIZED_NODE_MAP,
+    queue::MutationSender,
+    snapshot::{child_nodes_of, map_node_to_id},
+    types::millis,
+    user_events::capture_frame_mouse,
+    MutationChildList, MutationVariant, SerializedNode, NODE_ID, NODE_MAP, OBSERVED_FRAMES,
+    OBSERVERS, PENDING_FRAMES, REVERSE_NODE_MAP, ROOTS, SERIALIZED_NODE_MAP,
 };
 
-pub fn observe(sender: UnboundedSender<MutationVariant>, target: &Node) {
+/// Observes target and the documents of any same-origin iframes serialized so far.
+pub fn observe(sender: MutationSender, target: &Node) {
+    let frame_sender = sender.clone();
     let closure = Closure::wrap(
         Box::new(move |mutation_records: Array, _: MutationObserver| {
             let records = mutation_records
                 .iter()
                 .map(|item| item.unchecked_into::<MutationRecord>())
                 .collect::<Vec<_>>();
+            sender.start_callback();
             for record in records {
-                web_sys::console::log_1(record.as_ref());
-                sender
-                    .send(MutationVariant::new(record))
-                    .expect("mutation send to always succeed");
+                sender.send_record(record);
             }
+            // added subtrees may have contained iframes.
+            observe_pending_frames(&sender);
         }) as Box<dyn FnMut(_, _)>,
     );
     let f = closure.into_js_value().unchecked_into::<Function>();
@@ -39,6 +47,150 @@ pub fn observe(sender: UnboundedSender<MutationVariant>, target: &Node) {
             init
         })
         .expect("observe");
+    OBSERVERS.with(|observers| {
+        observers
+            .borrow_mut()
+            .push((target.clone(), mutation_observer))
+    });
+    observe_pending_frames(&frame_sender);
+}
+
+fn observe_pending_frames(sender: &MutationSender) {
+    let frames = PENDING_FRAMES.with(|frames| std::mem::take(&mut *frames.borrow_mut()));
+    for iframe in frames {
+        if let Some(document) = iframe.content_document() {
+            let is_observed = OBSERVED_FRAMES.with(|observed| observed.has(document.as_ref()));
+            if !is_observed {
+                OBSERVED_FRAMES.with(|observed| observed.add(document.as_ref()));
+                observe(sender.clone(), document.as_ref());
+                // only fails if the frame navigated cross-origin in the meantime.
+                _ = capture_frame_mouse(&iframe);
+            }
+        }
+        let is_listening = OBSERVED_FRAMES.with(|observed| observed.has(iframe.as_ref()));
+        if !is_listening {
+            OBSERVED_FRAMES.with(|observed| observed.add(iframe.as_ref()));
+            let sender = sender.clone();
+            let listener = iframe.clone();
+            let closure = Closure::wrap(Box::new(move |_: Event| {
+                frame_loaded(&sender, &listener);
+            }) as Box<dyn FnMut(_)>);
+            iframe
+                .add_event_listener_with_callback(
+                    "load",
+                    closure.into_js_value().unchecked_ref::<Function>(),
+                )
+                .expect("load listener");
+        }
+    }
+}
+
+/// Replaces the serialized document of an iframe after it navigates.
+fn frame_loaded(sender: &MutationSender, iframe: &HtmlIFrameElement) {
+    // The frame was removed from the page before it finished loading.
+    let Some(iframe_id) = map_node_to_id(iframe.as_ref()) else {
+        return;
+    };
+    let document = iframe
+        .content_document()
+        .map(|document| document.unchecked_into::<Node>());
+    if document
+        .as_ref()
+        .is_some_and(|document| map_node_to_id(document).is_some())
+    {
+        return;
+    }
+    let old_documents = forget_frame_documents(iframe_id);
+    if !old_documents.is_empty() {
+        sender.send(MutationVariant::ChildListRemoved(MutationChildList {
+            target_id: iframe_id,
+            millis: millis(),
+            prev_sibling: None,
+            next_sibling: None,
+            nodes: old_documents,
+        }));
+    }
+    // None when the frame navigated cross-origin, it stays blank in the replay.
+    let Some(document) = document else {
+        return;
+    };
+    let added_map = mutation_parse_added_nodes(vec![document.clone()], iframe.clone().into());
+    let document_id = map_node_to_id(&document).expect("document to be in map by now");
+    sender.send(MutationVariant::ChildListAdded((
+        MutationChildList {
+            target_id: iframe_id,
+            millis: millis(),
+            prev_sibling: None,
+            next_sibling: None,
+            nodes: vec![document_id],
+        },
+        added_map,
+    )));
+    PENDING_FRAMES.with(|frames| frames.borrow_mut().push(iframe.clone()));
+    observe_pending_frames(sender);
+}
+
+/// Forgets the serialized documents of an iframe and stops observing them, after the frame navigated or was
+/// removed from the page. Returns their ids.
+pub(crate) fn forget_frame_documents(iframe_id: u32) -> Vec<u32> {
+    let documents = SERIALIZED_NODE_MAP.with(|node_map| {
+        let mut node_map = node_map.borrow_mut();
+        let documents = match node_map.get(&iframe_id) {
+            Some(SerializedNode::ElementNode(iframe)) => iframe
+                .child_nodes
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|id| matches!(node_map.get(id), Some(SerializedNode::DocumentNode(_))))
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        if let Some(SerializedNode::ElementNode(iframe)) = node_map.get_mut(&iframe_id) {
+            iframe.child_nodes = iframe.child_nodes.take().map(|children| {
+                children
+                    .into_iter()
+                    .filter(|id| !documents.contains(id))
+                    .collect()
+            });
+        }
+        documents
+    });
+    for id in documents.iter() {
+        if let Some(document) = NODE_MAP.with(|node_map| node_map.borrow().get(id).cloned()) {
+            OBSERVERS.with(|observers| {
+                observers.borrow_mut().retain(|(target, observer)| {
+                    let is_frame = target.is_same_node(Some(&document));
+                    if is_frame {
+                        observer.disconnect();
+                    }
+                    !is_frame
+                })
+            });
+            OBSERVED_FRAMES.with(|observed| observed.delete(document.as_ref()));
+        }
+        forget(*id);
+        ROOTS.with(|roots| roots.borrow_mut().retain(|(_, root_id)| root_id != id));
+    }
+    documents
+}
+
+/// Removes a serialized node and all of its descendants from the recorder's maps.
+fn forget(id: u32) {
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if let Some(node) = NODE_MAP.with(|node_map| node_map.borrow_mut().remove(&id)) {
+            REVERSE_NODE_MAP.with(|reverse_node_map| reverse_node_map.delete(node.as_ref()));
+        }
+        match SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow_mut().remove(&id)) {
+            Some(SerializedNode::DocumentNode(node)) => {
+                stack.extend(node.child_nodes.unwrap_or_default())
+            }
+            Some(SerializedNode::ElementNode(node)) => {
+                stack.extend(node.child_nodes.unwrap_or_default())
+            }
+            _ => {}
+        }
+    }
 }
 
 pub(crate) fn mutation_parse_added_nodes(
@@ -82,13 +234,8 @@ pub(crate) fn mutation_parse_added_nodes(
         SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow_mut().insert(id(), serialized_node));
         // Push the child nodes onto the stack in reverse order so that
         // the first child is processed first.
-        let child_nodes = current_node.child_nodes();
-        let length = child_nodes.length();
-
-        for i in (0..length).rev() {
-            if let Some(child) = child_nodes.item(i) {
-                stack.push((child, id()));
-            }
+        for child in child_nodes_of(&current_node).into_iter().rev() {
+            stack.push((child, id()));
         }
         NODE_ID.with(|id| *id.borrow_mut() += 1);
     }
@@ -0,0 +1,337 @@
+//! Plays a recorded session from replay-server's session API into an iframe.
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use gloo_net::http::Request;
+use gloo_timers::future::TimeoutFuture;
+use serde::Deserialize;
+use wasm_bindgen_futures::spawn_local;
+
+use crate::{
+    rebuild::rebuild,
+    timestamp,
+    validate::{self, validate, KeyframeRef},
+    wire::{Bincode, Codec, WireError},
+    CaptureEvent, MutationVariant, SerializedNode,
+};
+
+/// The longest the player sleeps before it checks whether it was paused or moved, and reports progress.
+const TICK_MILLIS: f64 = 100.;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlayerError {
+    #[error("request failed: {0}")]
+    Fetch(String),
+    #[error("server answered {0}")]
+    Status(u16),
+    #[error(transparent)]
+    Wire(#[from] WireError),
+    #[error("the session has no keyframe to start from")]
+    NoKeyframes,
+}
+
+impl From<gloo_net::Error> for PlayerError {
+    fn from(err: gloo_net::Error) -> Self {
+        Self::Fetch(err.to_string())
+    }
+}
+
+/// The parts of the session detail the player uses.
+#[derive(Deserialize)]
+struct SessionDetail {
+    keyframes: Vec<KeyframeDetail>,
+}
+
+#[derive(Deserialize)]
+struct KeyframeDetail {
+    time_origin: f64,
+    mutation_index: usize,
+    event_index: usize,
+}
+
+enum Step {
+    Keyframe(usize),
+    Mutation(usize),
+    Event(usize),
+}
+
+struct Recording {
+    snapshots: Vec<HashMap<u32, SerializedNode>>,
+    mutations: Vec<MutationVariant>,
+    events: Vec<(f64, CaptureEvent)>,
+    /// Every step with its offset from the start of the recording, in the order they're applied.
+    steps: Vec<(f64, Step)>,
+}
+
+struct Playback {
+    iframe_id: String,
+    recording: Recording,
+    /// How many steps are applied.
+    position: usize,
+    /// Where playback is, in millis from the start of the recording.
+    offset: f64,
+    playing: bool,
+    /// Bumped whenever the player is paused or moved, so a running loop knows to stop.
+    generation: u32,
+    on_progress: Option<Box<dyn Fn(f64, bool)>>,
+}
+
+/// Controls playback of one session, clones control the same playback.
+#[derive(Clone)]
+pub struct Player {
+    playback: Rc<RefCell<Playback>>,
+}
+
+impl Player {
+    /// Loads the session at session_url, like "/api/projects/{project}/sessions/{id}", and shows its first keyframe in the iframe.
+    /// A query string on session_url, like the project's read key, is sent along to every endpoint of the session.
+    pub async fn load<S: AsRef<str>>(iframe_id: S, session_url: S) -> Result<Self, PlayerError> {
+        let session_url = session_url.as_ref();
+        let detail = fetch(session_url, "application/json")
+            .await?
+            .json::<SessionDetail>()
+            .await?;
+        if detail.keyframes.is_empty() {
+            return Err(PlayerError::NoKeyframes);
+        }
+        let mut snapshots = Vec::new();
+        for index in 0..detail.keyframes.len() {
+            let bytes =
+                fetch_binary(&endpoint(session_url, &format!("/keyframes/{index}"))).await?;
+            snapshots.push(Bincode.decode_snapshot(&bytes)?);
+        }
+        let mutations =
+            Bincode.decode_mutations(&fetch_binary(&endpoint(session_url, "/mutations")).await?)?;
+        let events =
+            Bincode.decode_events(&fetch_binary(&endpoint(session_url, "/events")).await?)?;
+        // replaying a mutation of a node that isn't there panics, validate finds those first.
+        let unappliable = validate(&validate::Recording {
+            keyframes: detail
+                .keyframes
+                .iter()
+                .zip(&snapshots)
+                .map(|(keyframe, snapshot)| KeyframeRef {
+                    snapshot,
+                    time_origin: keyframe.time_origin,
+                    mutation_index: keyframe.mutation_index,
+                    event_index: keyframe.event_index,
+                })
+                .collect(),
+            mutations: &mutations,
+            events: &events,
+            seqs: &[],
+        })
+        .unappliable_mutations();
+        let steps = steps(&detail.keyframes, &mutations, &events, &unappliable);
+        let player = Self {
+            playback: Rc::new(RefCell::new(Playback {
+                iframe_id: iframe_id.as_ref().to_string(),
+                recording: Recording {
+                    snapshots,
+                    mutations,
+                    events,
+                    steps,
+                },
+                position: 0,
+                offset: 0.,
+                playing: false,
+                generation: 0,
+                on_progress: None,
+            })),
+        };
+        player.seek(0.);
+        Ok(player)
+    }
+    /// Called with the offset and whether it's playing whenever playback moves, starts or stops.
+    pub fn on_progress(&self, on_progress: impl Fn(f64, bool) + 'static) {
+        self.playback.borrow_mut().on_progress = Some(Box::new(on_progress));
+    }
+    /// The offset of the last step, in millis.
+    pub fn duration(&self) -> f64 {
+        let playback = self.playback.borrow();
+        playback
+            .recording
+            .steps
+            .last()
+            .map(|(offset, _)| *offset)
+            .unwrap_or_default()
+    }
+    pub fn offset(&self) -> f64 {
+        self.playback.borrow().offset
+    }
+    pub fn is_playing(&self) -> bool {
+        self.playback.borrow().playing
+    }
+    /// Plays from the current offset, from the start if playback had reached the end.
+    pub fn play(&self) {
+        if self.offset() >= self.duration() {
+            self.seek(0.);
+        }
+        let generation = {
+            let mut playback = self.playback.borrow_mut();
+            playback.playing = true;
+            playback.generation = playback.generation.wrapping_add(1);
+            playback.generation
+        };
+        self.progress();
+        let player = self.clone();
+        spawn_local(async move { player.run(generation).await });
+    }
+    pub fn pause(&self) {
+        {
+            let mut playback = self.playback.borrow_mut();
+            playback.playing = false;
+            playback.generation = playback.generation.wrapping_add(1);
+        }
+        self.progress();
+    }
+    /// Shows the page as it was offset millis into the recording. Going back rebuilds the keyframe before offset
+    /// and applies everything up to offset again, going forward applies the steps in between.
+    pub fn seek(&self, offset: f64) {
+        let was_playing = self.is_playing();
+        self.pause();
+        {
+            let mut playback = self.playback.borrow_mut();
+            let playback = &mut *playback;
+            let steps = &playback.recording.steps;
+            if offset < playback.offset || playback.position == 0 {
+                playback.position = steps[..steps.partition_point(|(at, _)| *at <= offset)]
+                    .iter()
+                    .rposition(|(_, step)| matches!(step, Step::Keyframe(_)))
+                    .unwrap_or_default();
+            }
+            playback.apply_until(offset);
+            playback.offset = offset;
+        }
+        self.progress();
+        if was_playing && offset < self.duration() {
+            self.play();
+        }
+    }
+    async fn run(&self, generation: u32) {
+        let mut last_tick = timestamp();
+        loop {
+            let next = {
+                let mut playback = self.playback.borrow_mut();
+                if playback.generation != generation {
+                    return;
+                }
+                let now = timestamp();
+                let offset = playback.offset + now - last_tick;
+                last_tick = now;
+                playback.apply_until(offset);
+                playback.offset = offset;
+                playback
+                    .recording
+                    .steps
+                    .get(playback.position)
+                    .map(|(at, _)| at - offset)
+            };
+            self.progress();
+            let Some(next) = next else {
+                let mut playback = self.playback.borrow_mut();
+                playback.offset = playback.offset.min(
+                    playback
+                        .recording
+                        .steps
+                        .last()
+                        .map(|(at, _)| *at)
+                        .unwrap_or_default(),
+                );
+                playback.playing = false;
+                drop(playback);
+                self.progress();
+                return;
+            };
+            TimeoutFuture::new(next.clamp(0., TICK_MILLIS) as u32).await;
+        }
+    }
+    fn progress(&self) {
+        let playback = self.playback.borrow();
+        if let Some(on_progress) = &playback.on_progress {
+            on_progress(playback.offset, playback.playing);
+        }
+    }
+}
+
+impl Playback {
+    /// Applies every step up to and including offset.
+    fn apply_until(&mut self, offset: f64) {
+        while let Some((at, step)) = self.recording.steps.get(self.position) {
+            if *at > offset {
+                break;
+            }
+            match step {
+                Step::Keyframe(index) => {
+                    let snapshot = self.recording.snapshots[*index].clone();
+                    if let Err(err) = rebuild(&self.iframe_id, snapshot) {
+                        crate::utils::log(format!("couldn't rebuild keyframe {index}: {err}"));
+                    }
+                }
+                Step::Mutation(index) => self.recording.mutations[*index].replay(),
+                Step::Event(index) => self.recording.events[*index].1.replay(),
+            }
+            self.position += 1;
+        }
+    }
+}
+
+/// Each keyframe starts a page whose mutations and events count from its time origin. Pages are played one
+/// after another, anything recorded before the first keyframe has no page to be applied to and is left out,
+/// like the unappliable mutations.
+fn steps(
+    keyframes: &[KeyframeDetail],
+    mutations: &[MutationVariant],
+    events: &[(f64, CaptureEvent)],
+    unappliable: &HashSet<usize>,
+) -> Vec<(f64, Step)> {
+    let start = keyframes[0].time_origin;
+    let mut steps = Vec::new();
+    let mut end = 0.;
+    for (index, keyframe) in keyframes.iter().enumerate() {
+        let next = keyframes.get(index + 1);
+        let mutation_end = next.map_or(mutations.len(), |next| next.mutation_index);
+        let event_end = next.map_or(events.len(), |next| next.event_index);
+        let keyframe_offset = (keyframe.time_origin - start).max(end);
+        let mut page = (keyframe.mutation_index..mutation_end)
+            .filter(|index| !unappliable.contains(index))
+            .map(|index| (mutations[index].millis(), Step::Mutation(index)))
+            .chain(
+                (keyframe.event_index..event_end)
+                    .map(|index| (events[index].0, Step::Event(index))),
+            )
+            .map(|(millis, step)| {
+                let offset = (keyframe.time_origin + millis - start).max(keyframe_offset);
+                (offset, step)
+            })
+            .collect::<Vec<_>>();
+        page.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        end = page.last().map_or(keyframe_offset, |(offset, _)| *offset);
+        steps.push((keyframe_offset, Step::Keyframe(index)));
+        steps.extend(page);
+    }
+    steps
+}
+
+/// The url of the session's endpoint at path, with session_url's query.
+fn endpoint(session_url: &str, path: &str) -> String {
+    match session_url.split_once('?') {
+        Some((url, query)) => format!("{url}{path}?{query}"),
+        None => format!("{session_url}{path}"),
+    }
+}
+
+async fn fetch(url: &str, accept: &str) -> Result<gloo_net::http::Response, PlayerError> {
+    let response = Request::get(url).header("Accept", accept).send().await?;
+    if !response.ok() {
+        return Err(PlayerError::Status(response.status()));
+    }
+    Ok(response)
+}
+
+async fn fetch_binary(url: &str) -> Result<Vec<u8>, PlayerError> {
+    Ok(fetch(url, Bincode.content_type()).await?.binary().await?)
+}
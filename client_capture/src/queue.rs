@@ -0,0 +1,285 @@
+//! Bounded queues between the capture callbacks and the streams that upload what they capture.
+//! When a queue is full something has to give, events drop mouse moves first and mutations follow MutationOverflow.
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use web_sys::{MutationRecord, Node};
+
+use crate::{timestamp, CaptureEvent, MutationVariant, BACKPRESSURE};
+
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+pub const DEFAULT_MUTATION_CAPACITY: usize = 1024;
+/// How many added nodes one observer callback serializes before the recorder gives up on them and takes a keyframe.
+pub const DEFAULT_MAX_CALLBACK_NODES: usize = 10_000;
+/// Keyframes serialize the whole page, a page that keeps overflowing gets at most one this often.
+/// Mutations are dropped while the next one waits.
+pub const MIN_KEYFRAME_INTERVAL_MILLIS: f64 = 5_000.;
+
+/// What the recorder had to drop since the page loaded. Event uploads report it to the server as it grows,
+/// see DROPPED_HEADER.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DropCounts {
+    pub mouse_moves: u64,
+    /// Events other than mouse and touch moves.
+    pub events: u64,
+    /// Mutations skipped while waiting for a keyframe, or replaced by a newer change to the same node.
+    pub mutations: u64,
+    /// Keyframes taken because mutations were dropped.
+    pub keyframes: u64,
+    /// Uploads evicted from or refused by the full upload buffer, see UploadConfig::max_buffered_bytes.
+    pub uploads: u64,
+    pub upload_bytes: u64,
+}
+
+impl DropCounts {
+    pub fn add(&mut self, other: &DropCounts) {
+        self.mouse_moves += other.mouse_moves;
+        self.events += other.events;
+        self.mutations += other.mutations;
+        self.keyframes += other.keyframes;
+        self.uploads += other.uploads;
+        self.upload_bytes += other.upload_bytes;
+    }
+    fn since(&self, earlier: &DropCounts) -> DropCounts {
+        DropCounts {
+            mouse_moves: self.mouse_moves - earlier.mouse_moves,
+            events: self.events - earlier.events,
+            mutations: self.mutations - earlier.mutations,
+            keyframes: self.keyframes - earlier.keyframes,
+            uploads: self.uploads - earlier.uploads,
+            upload_bytes: self.upload_bytes - earlier.upload_bytes,
+        }
+    }
+}
+
+pub fn dropped() -> DropCounts {
+    BACKPRESSURE.with(|backpressure| backpressure.borrow().dropped)
+}
+
+/// What was dropped since the last call, None if nothing was.
+pub(crate) fn take_unreported() -> Option<DropCounts> {
+    BACKPRESSURE.with(|backpressure| {
+        let mut backpressure = backpressure.borrow_mut();
+        let unreported = backpressure.dropped.since(&backpressure.reported);
+        backpressure.reported = backpressure.dropped;
+        (unreported != DropCounts::default()).then_some(unreported)
+    })
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MutationOverflow {
+    /// Stop serializing mutations until the queue drains, then start over from a new snapshot.
+    #[default]
+    Keyframe,
+    /// Hold only the latest attribute and text change of each node until there's room.
+    /// Child list changes can't be merged, so those still force a keyframe.
+    Coalesce,
+}
+
+#[derive(Default)]
+pub struct Backpressure {
+    dropped: DropCounts,
+    /// What of dropped was sent to the server.
+    reported: DropCounts,
+    keyframe_pending: bool,
+    /// The performance.now() millis the last keyframe was taken at.
+    last_keyframe: Option<f64>,
+    /// Added nodes serialized in the current observer callback.
+    callback_nodes: usize,
+    /// Attribute and text changes waiting for room in the queue, oldest first.
+    coalesced: VecDeque<MutationVariant>,
+}
+
+/// The capture callbacks' end of an EventStream, events are queued with the performance.now() millis they were
+/// captured at.
+pub type EventSender = Sender<(f64, CaptureEvent)>;
+
+/// Queues a capture event stamped with the current time. Mouse and touch moves are dropped once the queue is three
+/// quarters full, which leaves room for clicks and resizes.
+pub fn send_event(sender: &EventSender, event: CaptureEvent) {
+    let is_move = matches!(
+        event,
+        CaptureEvent::MouseMove { .. } | CaptureEvent::TouchMove { .. }
+    );
+    if is_move && sender.capacity() <= sender.max_capacity() / 4 {
+        BACKPRESSURE.with(|backpressure| backpressure.borrow_mut().dropped.mouse_moves += 1);
+        return;
+    }
+    if sender.try_send((timestamp(), event)).is_err() {
+        BACKPRESSURE.with(|backpressure| backpressure.borrow_mut().dropped.events += 1);
+    }
+}
+
+/// The observer's end of a MutationStream.
+#[derive(Clone, Debug)]
+pub struct MutationSender {
+    pub(crate) sender: Sender<MutationVariant>,
+    pub(crate) overflow: MutationOverflow,
+    pub(crate) max_callback_nodes: usize,
+}
+
+impl MutationSender {
+    /// Starts the budget of nodes an observer callback may serialize over.
+    pub fn start_callback(&self) {
+        BACKPRESSURE.with(|backpressure| backpressure.borrow_mut().callback_nodes = 0);
+    }
+    /// Serializes and queues a record, unless the recorder is waiting for a keyframe anyway.
+    /// Added subtrees are counted before they're serialized, one that would take the callback over
+    /// max_callback_nodes is dropped and the recorder starts over from a keyframe.
+    pub fn send_record(&self, record: MutationRecord) {
+        if is_keyframe_pending() {
+            count_dropped_mutations(1);
+            return;
+        }
+        let used = BACKPRESSURE.with(|backpressure| backpressure.borrow().callback_nodes);
+        let budget = self.max_callback_nodes.saturating_sub(used);
+        match added_nodes(&record, budget) {
+            Some(added) => {
+                BACKPRESSURE.with(|backpressure| backpressure.borrow_mut().callback_nodes += added)
+            }
+            None => {
+                count_dropped_mutations(1);
+                start_keyframe();
+                return;
+            }
+        }
+        if self.has_room() {
+            if let Some(mutation) = MutationVariant::new(record) {
+                self.send(mutation);
+            }
+        } else if self.overflow == MutationOverflow::Coalesce && record.type_() != "childList" {
+            if let Some(mutation) = MutationVariant::new(record) {
+                coalesce(mutation);
+            }
+        } else {
+            count_dropped_mutations(1);
+            start_keyframe();
+        }
+    }
+    /// Queues a serialized mutation, if it doesn't fit the recorder starts over from a keyframe.
+    pub fn send(&self, mutation: MutationVariant) {
+        if is_keyframe_pending() {
+            count_dropped_mutations(1);
+            return;
+        }
+        if !self.has_room() || self.sender.try_send(mutation).is_err() {
+            count_dropped_mutations(1);
+            start_keyframe();
+        }
+    }
+    /// Moves coalesced changes into the queue first, nothing may overtake them.
+    fn has_room(&self) -> bool {
+        BACKPRESSURE.with(|backpressure| {
+            let mut backpressure = backpressure.borrow_mut();
+            while let Some(mutation) = backpressure.coalesced.pop_front() {
+                match self.sender.try_send(mutation) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(mutation) | TrySendError::Closed(mutation)) => {
+                        backpressure.coalesced.push_front(mutation);
+                        return false;
+                    }
+                }
+            }
+            self.sender.capacity() > 0
+        })
+    }
+}
+
+/// How many nodes record adds, counting their descendants, or None if that's more than max.
+/// Documents of iframes among them aren't counted.
+fn added_nodes(record: &MutationRecord, max: usize) -> Option<usize> {
+    let added = record.added_nodes();
+    let mut stack = (0..added.length())
+        .filter_map(|index| added.item(index))
+        .collect::<Vec<Node>>();
+    let mut count = 0;
+    while let Some(node) = stack.pop() {
+        count += 1;
+        if count > max {
+            return None;
+        }
+        let mut child = node.first_child();
+        while let Some(node) = child {
+            child = node.next_sibling();
+            stack.push(node);
+        }
+    }
+    Some(count)
+}
+
+/// Replaces an older change to the same attribute or text of the same node.
+fn coalesce(mutation: MutationVariant) {
+    let key = |mutation: &MutationVariant| match mutation {
+        MutationVariant::Attributes(this) => (
+            this.target_id,
+            this.attribute.as_ref().map(|(name, _)| name.clone()),
+        ),
+        _ => (mutation.target_id(), None),
+    };
+    BACKPRESSURE.with(|backpressure| {
+        let mut backpressure = backpressure.borrow_mut();
+        let before = backpressure.coalesced.len();
+        backpressure
+            .coalesced
+            .retain(|queued| key(queued) != key(&mutation));
+        backpressure.dropped.mutations += (before - backpressure.coalesced.len()) as u64;
+        backpressure.coalesced.push_back(mutation);
+    });
+}
+
+pub(crate) fn count_dropped_upload(bytes: usize) {
+    BACKPRESSURE.with(|backpressure| {
+        let mut backpressure = backpressure.borrow_mut();
+        backpressure.dropped.uploads += 1;
+        backpressure.dropped.upload_bytes += bytes as u64;
+    });
+}
+
+/// Has the mutation stream start over from a keyframe, because mutations of the current page were lost.
+pub(crate) fn request_keyframe() {
+    start_keyframe();
+}
+
+fn count_dropped_mutations(count: u64) {
+    BACKPRESSURE.with(|backpressure| backpressure.borrow_mut().dropped.mutations += count);
+}
+
+/// Drops whatever was coalesced, the keyframe includes it.
+fn start_keyframe() {
+    BACKPRESSURE.with(|backpressure| {
+        let mut backpressure = backpressure.borrow_mut();
+        backpressure.keyframe_pending = true;
+        let coalesced = std::mem::take(&mut backpressure.coalesced);
+        backpressure.dropped.mutations += coalesced.len() as u64;
+    });
+}
+
+pub(crate) fn is_keyframe_pending() -> bool {
+    BACKPRESSURE.with(|backpressure| backpressure.borrow().keyframe_pending)
+}
+
+/// Whether MIN_KEYFRAME_INTERVAL_MILLIS has passed since the last keyframe.
+pub(crate) fn is_keyframe_due() -> bool {
+    BACKPRESSURE.with(|backpressure| {
+        backpressure
+            .borrow()
+            .last_keyframe
+            .is_none_or(|last| timestamp() - last >= MIN_KEYFRAME_INTERVAL_MILLIS)
+    })
+}
+
+pub(crate) fn keyframe_taken() {
+    BACKPRESSURE.with(|backpressure| {
+        let mut backpressure = backpressure.borrow_mut();
+        backpressure.keyframe_pending = false;
+        backpressure.last_keyframe = Some(timestamp());
+        backpressure.dropped.keyframes += 1;
+    });
+}
+
+/// Coalesced changes, for the stream to take once it has drained the queue.
+pub(crate) fn take_coalesced() -> VecDeque<MutationVariant> {
+    BACKPRESSURE.with(|backpressure| std::mem::take(&mut backpressure.borrow_mut().coalesced))
+}
@@ -1,8 +1,9 @@
-use crate::{types::*, SERIALIZED_NODE_MAP_REPLAY};
+use crate::{types::*, REPLAY_VIEWPORT, SERIALIZED_NODE_MAP_REPLAY};
 use crate::{window, NODE_MAP_REPLAY};
+use js_sys::Function;
 use std::collections::HashMap;
-use wasm_bindgen::JsCast;
-use web_sys::{DomException, HtmlIFrameElement, Node};
+use wasm_bindgen::{prelude::Closure, JsCast};
+use web_sys::{DomException, Element, Event, HtmlIFrameElement, Node};
 
 pub fn rebuild<S: AsRef<str>>(
     iframe_id: S,
@@ -25,20 +26,124 @@ pub fn rebuild<S: AsRef<str>>(
         .get(&0)
         .expect("Node to be at 0 idx")
         .clone();
+    // nodes of an earlier rebuild into the same player, like a live viewer's previous keyframe.
+    NODE_MAP_REPLAY.with(|map| map.borrow_mut().clear());
     SERIALIZED_NODE_MAP_REPLAY.with(|map| {
-        map.borrow_mut().extend(serialized_node_map);
+        let mut map = map.borrow_mut();
+        map.clear();
+        map.extend(serialized_node_map);
     });
 
     let (root, root_children) = serialized_root
         .build(&iframe_document, None, None)
         .map_err(|err| err.message())?;
     add_dom_tree(root, root_children, 0)?;
+    if let SerializedNode::DocumentNode(DocumentNode {
+        viewport: Some(viewport),
+        ..
+    }) = serialized_root
+    {
+        fit_viewport(&iframe, viewport);
+    }
     Ok(())
 }
 
+/// Sizes the iframe to the recorded viewport and scales it with a CSS transform to fit its parent element.
+/// The iframe is refitted whenever the player's window resizes.
+pub fn fit_viewport(iframe: &HtmlIFrameElement, viewport: Viewport) {
+    let previous = REPLAY_VIEWPORT.with(|replay_viewport| {
+        replay_viewport
+            .borrow_mut()
+            .replace((iframe.clone(), viewport))
+    });
+    if previous.is_none() {
+        let closure = Closure::wrap(Box::new(move |_: Event| {
+            if let Some((iframe, viewport)) =
+                REPLAY_VIEWPORT.with(|replay_viewport| replay_viewport.borrow().clone())
+            {
+                scale_to_container(&iframe, viewport);
+            }
+        }) as Box<dyn FnMut(_)>);
+        window()
+            .add_event_listener_with_callback(
+                "resize",
+                closure.into_js_value().unchecked_ref::<Function>(),
+            )
+            .expect("resize listener");
+    }
+    scale_to_container(iframe, viewport);
+}
+
+/// Applies a recorded window resize to the replay iframe.
+pub fn resize_viewport(width: u32, height: u32) {
+    if let Some((iframe, viewport)) =
+        REPLAY_VIEWPORT.with(|replay_viewport| replay_viewport.borrow().clone())
+    {
+        fit_viewport(
+            &iframe,
+            Viewport {
+                width,
+                height,
+                ..viewport
+            },
+        );
+    }
+}
+
+fn scale_to_container(iframe: &HtmlIFrameElement, viewport: Viewport) {
+    let (width, height) = (viewport.width as f64, viewport.height as f64);
+    // if the container isn't laid out yet show the recording at its own size.
+    let scale = iframe
+        .parent_element()
+        .map(|container| {
+            (
+                container.client_width() as f64,
+                container.client_height() as f64,
+            )
+        })
+        .filter(|(container_width, container_height)| {
+            *container_width > 0. && *container_height > 0. && width > 0. && height > 0.
+        })
+        .map(|(container_width, container_height)| {
+            (container_width / width).min(container_height / height)
+        })
+        .unwrap_or(1.);
+    let style = iframe.style();
+    for (property, value) in [
+        ("width", format!("{width}px")),
+        ("height", format!("{height}px")),
+        ("transform-origin", "top left".to_string()),
+        ("transform", format!("scale({scale})")),
+    ] {
+        style
+            .set_property(property, &value)
+            .expect("valid style property");
+    }
+}
+
 pub fn add_dom_tree(root: Node, root_children: Vec<u32>, root_id: u32) -> Result<(), String> {
     // insert root
     NODE_MAP_REPLAY.with(|node_map| node_map.borrow_mut().insert(root_id, root.clone()));
+    // properties that can only be restored once the whole tree exists.
+    let mut form_states = Vec::new();
+    let mut scrolls = Vec::new();
+    let mut defer = |node: &Node, serialized: &SerializedNode| {
+        if let SerializedNode::ElementNode(ElementNode {
+            form_state: Some(form_state),
+            ..
+        }) = serialized
+        {
+            form_states.push((node.clone(), form_state.clone()));
+        }
+        if let Some(scroll) = serialized.scroll() {
+            scrolls.push((node.clone(), scroll));
+        }
+    };
+    if let Some(serialized_root) =
+        SERIALIZED_NODE_MAP_REPLAY.with(|node_map| node_map.borrow().get(&root_id).cloned())
+    {
+        defer(&root, &serialized_root);
+    }
     let mut stack: Vec<(Node, Vec<u32>)> = vec![(root, root_children)];
     // insert all children iteratively
     while let Some((node, mut children)) = stack.pop() {
@@ -47,7 +152,7 @@ pub fn add_dom_tree(root: Node, root_children: Vec<u32>, root_id: u32) -> Result
                 node_map
                     .borrow()
                     .get(&child)
-                    .expect(&format!("not fouond {child} in {:#?}", node_map.borrow()))
+                    .unwrap_or_else(|| panic!("not fouond {child} in {:#?}", node_map.borrow()))
                     .clone()
             });
 
@@ -55,8 +160,24 @@ pub fn add_dom_tree(root: Node, root_children: Vec<u32>, root_id: u32) -> Result
                 .build(&node, None, None)
                 .map_err(|err| err.message())?;
             NODE_MAP_REPLAY.with(|node_map| node_map.borrow_mut().insert(child, node.clone()));
+            defer(&node, &serialized_child);
             stack.push((node, node_children));
         }
     }
+    // selects need their options before the selected index means anything.
+    for (node, form_state) in form_states {
+        form_state.restore(node.unchecked_ref::<Element>());
+    }
+    if !scrolls.is_empty() {
+        // wait a frame so the rebuilt tree has been laid out and has something to scroll.
+        let closure = Closure::once_into_js(move || {
+            for (node, scroll) in scrolls {
+                scroll.restore(&node);
+            }
+        });
+        window()
+            .request_animation_frame(closure.unchecked_ref::<Function>())
+            .expect("set animation frame");
+    }
     Ok(())
 }
@@ -3,7 +3,10 @@ use js_sys::Function;
 use wasm_bindgen::{prelude::Closure, JsCast};
 use web_sys::Element;
 
-use crate::{window, MutationVariant, NODE_MAP_REPLAY, SERIALIZED_NODE_MAP_REPLAY};
+use crate::{
+    rebuild::resize_viewport, window, CaptureEvent, MutationVariant, NODE_MAP_REPLAY,
+    SERIALIZED_NODE_MAP_REPLAY,
+};
 pub async fn replay(mutations: Vec<MutationVariant>) {
     let mut mutations = mutations;
     // make sure mutations are sorted in chronological order, we're are assuring that millis is unique in our mutation new code by adding a fractional increment
@@ -44,11 +47,13 @@ impl MutationVariant {
 
                     let parent = NODE_MAP_REPLAY
                         .with(|node_map| node_map.borrow().get(&target_id).cloned())
-                        .expect(&format!(
-                            "Didnt find {} in \n {:#?}",
-                            target_id,
-                            NODE_MAP_REPLAY.with(|s| s.borrow().clone())
-                        ));
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "Didnt find {} in \n {:#?}",
+                                target_id,
+                                NODE_MAP_REPLAY.with(|s| s.borrow().clone())
+                            )
+                        });
                     let prev_sibling = mutation.prev_sibling.map(|id| {
                         NODE_MAP_REPLAY
                             .with(|node_map| node_map.borrow().get(&id).cloned())
@@ -72,10 +77,17 @@ impl MutationVariant {
                         .expect("Parent to resolve to a node");
                     let this = NODE_MAP_REPLAY
                         .with(|node_map| node_map.borrow_mut().remove(id))
-                        .expect(&format!("removed node id:{id} to exist in node map"));
-                    parent
-                        .remove_child(&this)
-                        .expect("remove child to be valid");
+                        .unwrap_or_else(|| panic!("removed node id:{id} to exist in node map"));
+                    if this.node_type() == 9 {
+                        // the document of a replayed iframe, which can't be removed, only emptied.
+                        while let Some(child) = this.last_child() {
+                            this.remove_child(&child).expect("remove child to be valid");
+                        }
+                    } else {
+                        parent
+                            .remove_child(&this)
+                            .expect("remove child to be valid");
+                    }
                     SERIALIZED_NODE_MAP_REPLAY
                         .with(|node_map| node_map.borrow_mut().remove(id))
                         .expect("node to exist in serialized node map too.");
@@ -88,7 +100,7 @@ impl MutationVariant {
                         .borrow()
                         .get(&id)
                         .expect("valid node")
-                        .set_text_content(mutation.text_content.as_ref().map(|s| s.as_str()))
+                        .set_text_content(mutation.text_content.as_deref())
                 });
                 SERIALIZED_NODE_MAP_REPLAY.with(|node_map| {
                     node_map
@@ -99,10 +111,10 @@ impl MutationVariant {
                 })
             }
             MutationVariant::Attributes(mutation) => {
-                let (name, value) = mutation
-                    .attribute
-                    .clone()
-                    .expect("Attribute variant to have attribute value");
+                // the recorder leaves out changes it doesn't record, older sessions may still have them.
+                let Some((name, value)) = mutation.attribute.clone() else {
+                    return;
+                };
                 let id = target_id;
                 NODE_MAP_REPLAY.with(|node_map| {
                     node_map
@@ -124,3 +136,11 @@ impl MutationVariant {
         }
     }
 }
+impl CaptureEvent {
+    /// Applies the events that change the player rather than the page.
+    pub fn replay(&self) {
+        if let CaptureEvent::WindowResize { height, width } = *self {
+            resize_viewport(width, height);
+        }
+    }
+}
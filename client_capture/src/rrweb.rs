@@ -0,0 +1,1146 @@
+//! Converts recordings to and from rrweb's event format, so they can be played with rrweb-player
+//! and rrweb recordings can be replayed with rebuild and replay.
+use std::collections::HashMap;
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    CDataNode, CaptureEvent, CommentNode, DocumentNode, DocumentTypeNode, ElementNode, FormState,
+    MutationAttributes, MutationCharacterData, MutationChildList, MutationVariant, ScrollPosition,
+    SerializedNode, TextNode, Viewport,
+};
+
+// rrweb's EventType.
+const FULL_SNAPSHOT: u64 = 2;
+const INCREMENTAL_SNAPSHOT: u64 = 3;
+const META: u64 = 4;
+
+// rrweb's IncrementalSource.
+const MUTATION: u64 = 0;
+const MOUSE_MOVE: u64 = 1;
+const MOUSE_INTERACTION: u64 = 2;
+const VIEWPORT_RESIZE: u64 = 4;
+const TOUCH_MOVE: u64 = 6;
+
+/// rrweb's MouseInteractions.Click
+const CLICK: u64 = 2;
+
+// rrweb-snapshot's NodeType.
+const DOCUMENT: u64 = 0;
+const DOCUMENT_TYPE: u64 = 1;
+const ELEMENT: u64 = 2;
+const TEXT: u64 = 3;
+const CDATA: u64 = 4;
+const COMMENT: u64 = 5;
+
+/// A snapshot with everything recorded after it, times are performance.now() millis like MutationVariant::millis.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    pub snapshot: HashMap<u32, SerializedNode>,
+    pub snapshot_millis: f64,
+    pub mutations: Vec<MutationVariant>,
+    pub events: Vec<(f64, CaptureEvent)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RrwebError {
+    #[error("the recording has no full snapshot")]
+    MissingFullSnapshot,
+    #[error("malformed rrweb event: {0}")]
+    Malformed(String),
+}
+
+/// rrweb treats 0 as a missing id, so ids are shifted by one on the way out.
+fn rrweb_id(id: u32) -> u32 {
+    id + 1
+}
+
+/// Returns rrweb events, time_origin is added to every time to make the epoch millis rrweb expects (performance.timeOrigin).
+pub fn export(recording: &Recording, time_origin: f64) -> Vec<Value> {
+    let timestamp = |millis: f64| time_origin + millis;
+    let mut events = Vec::new();
+    let (viewport, scroll) = match recording.snapshot.get(&0) {
+        Some(SerializedNode::DocumentNode(document)) => (document.viewport, document.scroll),
+        _ => (None, None),
+    };
+    if let Some(viewport) = viewport {
+        events.push(json!({
+            "type": META,
+            "timestamp": timestamp(recording.snapshot_millis),
+            "data": { "href": "", "width": viewport.width, "height": viewport.height },
+        }));
+    }
+    let scroll = scroll.unwrap_or(ScrollPosition { top: 0, left: 0 });
+    events.push(json!({
+        "type": FULL_SNAPSHOT,
+        "timestamp": timestamp(recording.snapshot_millis),
+        "data": {
+            "node": export_node(&recording.snapshot, 0, true),
+            "initialOffset": { "top": scroll.top, "left": scroll.left },
+        },
+    }));
+    let mut incremental = recording
+        .mutations
+        .iter()
+        .map(|mutation| (mutation.millis(), export_mutation(mutation)))
+        .chain(
+            recording
+                .events
+                .iter()
+                .filter_map(|(millis, event)| Some((*millis, export_event(event)?))),
+        )
+        .collect::<Vec<_>>();
+    incremental.sort_by(|a, b| a.0.total_cmp(&b.0));
+    events.extend(incremental.into_iter().map(|(millis, data)| {
+        json!({ "type": INCREMENTAL_SNAPSHOT, "timestamp": timestamp(millis), "data": data })
+    }));
+    events
+}
+
+/// Our child_nodes are stored last child first.
+fn children_in_order(node: &SerializedNode) -> Vec<u32> {
+    let mut children = node.child_nodes().unwrap_or_default();
+    children.reverse();
+    children
+}
+
+/// rrweb's serializedNodeWithId, mutations add children separately so they leave them out.
+fn export_node(map: &HashMap<u32, SerializedNode>, id: u32, with_children: bool) -> Value {
+    let Some(node) = map.get(&id) else {
+        return Value::Null;
+    };
+    let child_nodes = || {
+        let children = if with_children {
+            children_in_order(node)
+        } else {
+            Vec::new()
+        };
+        children
+            .into_iter()
+            .map(|child| export_node(map, child, true))
+            .filter(|child| !child.is_null())
+            .collect::<Vec<_>>()
+    };
+    let mut value = match node {
+        SerializedNode::DocumentNode(document) => json!({
+            "type": DOCUMENT,
+            "compatMode": document.compat_mode,
+            "childNodes": child_nodes(),
+        }),
+        SerializedNode::DocumentTypeNode(doc_type) => json!({
+            "type": DOCUMENT_TYPE,
+            "name": doc_type.name,
+            "publicId": doc_type.public_id,
+            "systemId": doc_type.system_id,
+        }),
+        SerializedNode::ElementNode(el) => {
+            let mut attributes = el
+                .attributes
+                .iter()
+                .flatten()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect::<Map<_, _>>();
+            // rrweb keeps the live properties of form controls in their attributes.
+            if let Some(form_state) = &el.form_state {
+                if let Some(value) = &form_state.value {
+                    attributes.insert("value".to_string(), value.as_str().into());
+                }
+                if let Some(checked) = form_state.checked {
+                    attributes.insert("checked".to_string(), checked.into());
+                }
+                if let Some(selected) = form_state.selected {
+                    attributes.insert("selected".to_string(), selected.into());
+                }
+            }
+            if let Some(scroll) = el.scroll {
+                attributes.insert("rr_scrollTop".to_string(), scroll.top.into());
+                attributes.insert("rr_scrollLeft".to_string(), scroll.left.into());
+            }
+            json!({
+                "type": ELEMENT,
+                // rrweb lower cases html tags, svg tags are case sensitive.
+                "tagName": if el.is_svg { el.tag_name.clone() } else { el.tag_name.to_lowercase() },
+                "attributes": attributes,
+                "childNodes": child_nodes(),
+                "isSVG": el.is_svg,
+                "needBlock": el.need_block,
+            })
+        }
+        SerializedNode::TextNode(TextNode { text_content, .. }) => {
+            json!({ "type": TEXT, "textContent": text_content.clone().unwrap_or_default() })
+        }
+        SerializedNode::CDataNode(_) => json!({ "type": CDATA, "textContent": "" }),
+        SerializedNode::CommentNode(CommentNode { text_content, .. }) => {
+            json!({ "type": COMMENT, "textContent": text_content.clone().unwrap_or_default() })
+        }
+    };
+    let (is_shadow_host, is_shadow) = node.shadow();
+    value["id"] = rrweb_id(id).into();
+    if is_shadow_host {
+        value["isShadowHost"] = true.into();
+    }
+    if is_shadow {
+        value["isShadow"] = true.into();
+    }
+    value
+}
+
+fn export_mutation(mutation: &MutationVariant) -> Value {
+    let mut adds = Vec::new();
+    let mut removes = Vec::new();
+    let mut texts = Vec::new();
+    let mut attributes = Vec::new();
+    match mutation {
+        MutationVariant::ChildListAdded((list, added)) => {
+            for (i, id) in list.nodes.iter().enumerate() {
+                // nodes of one record are siblings, each goes after the one before it.
+                let previous = if i == 0 {
+                    list.prev_sibling
+                } else {
+                    Some(list.nodes[i - 1])
+                };
+                adds.push(json!({
+                    "parentId": rrweb_id(list.target_id),
+                    "previousId": previous.map(rrweb_id),
+                    "nextId": list.next_sibling.map(rrweb_id),
+                    "node": export_node(added, *id, false),
+                }));
+                // parents are added before their children, which are appended in order.
+                let mut stack = vec![*id];
+                while let Some(parent) = stack.pop() {
+                    let Some(node) = added.get(&parent) else {
+                        continue;
+                    };
+                    let children = children_in_order(node);
+                    for (i, child) in children.iter().enumerate() {
+                        adds.push(json!({
+                            "parentId": rrweb_id(parent),
+                            "previousId": i.checked_sub(1).map(|i| rrweb_id(children[i])),
+                            "nextId": null,
+                            "node": export_node(added, *child, false),
+                        }));
+                    }
+                    stack.extend(children.into_iter().rev());
+                }
+            }
+        }
+        MutationVariant::ChildListRemoved(list) => {
+            for id in list.nodes.iter() {
+                removes.push(json!({ "parentId": rrweb_id(list.target_id), "id": rrweb_id(*id) }));
+            }
+        }
+        MutationVariant::CharacterData(text) => {
+            texts.push(json!({ "id": rrweb_id(text.target_id), "value": text.text_content }));
+        }
+        MutationVariant::Attributes(MutationAttributes {
+            target_id,
+            attribute: Some((name, value)),
+            ..
+        }) => {
+            attributes.push(json!({ "id": rrweb_id(*target_id), "attributes": { name: value } }));
+        }
+        MutationVariant::Attributes(_) => {}
+    }
+    json!({
+        "source": MUTATION,
+        "adds": adds,
+        "removes": removes,
+        "texts": texts,
+        "attributes": attributes,
+    })
+}
+
+/// Pointer events aren't tied to a target, so they point at the document. Clicks point at their target if they have one.
+fn export_event(event: &CaptureEvent) -> Option<Value> {
+    let document = rrweb_id(0);
+    Some(match *event {
+        CaptureEvent::MouseMove { x, y } => json!({
+            "source": MOUSE_MOVE,
+            "positions": [{ "x": x, "y": y, "id": document, "timeOffset": 0 }],
+        }),
+        CaptureEvent::TouchMove { x, y } => json!({
+            "source": TOUCH_MOVE,
+            "positions": [{ "x": x, "y": y, "id": document, "timeOffset": 0 }],
+        }),
+        CaptureEvent::MouseClick { x, y } => json!({
+            "source": MOUSE_INTERACTION,
+            "type": CLICK,
+            "id": document,
+            "x": x,
+            "y": y,
+        }),
+        CaptureEvent::Click { x, y, target, .. } => json!({
+            "source": MOUSE_INTERACTION,
+            "type": CLICK,
+            "id": target.map_or(document, rrweb_id),
+            "x": x,
+            "y": y,
+        }),
+        CaptureEvent::WindowResize { height, width } => json!({
+            "source": VIEWPORT_RESIZE,
+            "width": width,
+            "height": height,
+        }),
+        CaptureEvent::Scoll {} | CaptureEvent::Error { .. } => return None,
+    })
+}
+
+/// Reads rrweb events into a recording, times are made relative to the first event.
+/// Only the first full snapshot is used, rrweb takes another one when the page is reloaded
+/// and everything after it would need a new rebuild.
+pub fn import(events: &[Value]) -> Result<Recording, RrwebError> {
+    let origin = match events.first() {
+        Some(event) => number(event, "timestamp")?,
+        None => return Err(RrwebError::MissingFullSnapshot),
+    };
+    let mut recording = Recording::default();
+    let mut viewport = None;
+    let mut importer: Option<Importer> = None;
+    for event in events {
+        let millis = number(event, "timestamp")? - origin;
+        let data = field(event, "data")?;
+        match (number(event, "type")? as u64, importer.as_mut()) {
+            (META, _) => {
+                viewport = Some(Viewport {
+                    width: number(data, "width")? as u32,
+                    height: number(data, "height")? as u32,
+                    device_pixel_ratio: 1.,
+                    scale: 1.,
+                });
+            }
+            (FULL_SNAPSHOT, None) => {
+                let node = field(data, "node")?;
+                let mut new_importer = Importer {
+                    root: number(node, "id")? as u64,
+                    generated_id: u32::MAX,
+                };
+                new_importer.node(node, 0, &mut recording.snapshot)?;
+                let Some(SerializedNode::DocumentNode(document)) = recording.snapshot.get_mut(&0)
+                else {
+                    return Err(RrwebError::Malformed(
+                        "the full snapshot isn't a document".to_string(),
+                    ));
+                };
+                document.viewport = viewport;
+                if let Some(offset) = data.get("initialOffset") {
+                    let top = number(offset, "top")? as i32;
+                    let left = number(offset, "left")? as i32;
+                    document.scroll =
+                        (top != 0 || left != 0).then_some(ScrollPosition { top, left });
+                }
+                recording.snapshot_millis = millis;
+                importer = Some(new_importer);
+            }
+            (FULL_SNAPSHOT, Some(_)) => break,
+            (INCREMENTAL_SNAPSHOT, Some(importer)) => {
+                importer.incremental(data, millis, &mut recording)?
+            }
+            _ => {}
+        }
+    }
+    if importer.is_none() {
+        return Err(RrwebError::MissingFullSnapshot);
+    }
+    recording.events.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(recording)
+}
+
+struct Importer {
+    /// The id of the full snapshot's document, which becomes 0 because rebuild starts there.
+    root: u64,
+    /// Ids for nodes rrweb doesn't have, counting down from the top so they never meet rrweb's.
+    generated_id: u32,
+}
+
+/// Where a node added by a mutation goes, ids are ours.
+struct Placement {
+    id: u32,
+    parent: u32,
+    previous: Option<u32>,
+    next: Option<u32>,
+}
+
+impl Importer {
+    fn id(&self, value: &Value) -> Result<u32, RrwebError> {
+        value
+            .as_u64()
+            .and_then(|id| id.checked_sub(self.root))
+            .map(|id| id as u32)
+            .ok_or_else(|| RrwebError::Malformed(format!("{value} isn't a node id")))
+    }
+    fn optional_id(&self, value: &Value, name: &str) -> Result<Option<u32>, RrwebError> {
+        match value.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(id) => self.id(id).map(Some),
+        }
+    }
+    fn generate_id(&mut self) -> u32 {
+        self.generated_id -= 1;
+        self.generated_id
+    }
+    /// Adds a serializedNodeWithId and its children to map, returns its id.
+    fn node(
+        &mut self,
+        value: &Value,
+        root_id: u32,
+        map: &mut HashMap<u32, SerializedNode>,
+    ) -> Result<u32, RrwebError> {
+        let id = self.id(field(value, "id")?)?;
+        let node_type = number(value, "type")? as u64;
+        let root_id = if node_type == DOCUMENT { id } else { root_id };
+        let mut children = Vec::new();
+        for child in array(value, "childNodes") {
+            children.push(self.node(child, root_id, map)?);
+        }
+        let is_shadow_host = flag(value, "isShadowHost");
+        let is_shadow = flag(value, "isShadow");
+        let text_content = value
+            .get("textContent")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let node = match node_type {
+            DOCUMENT => SerializedNode::DocumentNode(DocumentNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                child_nodes: stored_children(children),
+                compat_mode: value
+                    .get("compatMode")
+                    .and_then(Value::as_str)
+                    .unwrap_or("CSS1Compat")
+                    .to_string(),
+                viewport: None,
+                scroll: None,
+            }),
+            DOCUMENT_TYPE => SerializedNode::DocumentTypeNode(DocumentTypeNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                name: string(value, "name")?.to_string(),
+                public_id: string(value, "publicId")?.to_string(),
+                system_id: string(value, "systemId")?.to_string(),
+            }),
+            ELEMENT => self.element(value, id, root_id, children, map)?,
+            TEXT => SerializedNode::TextNode(TextNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }),
+            CDATA => SerializedNode::CDataNode(CDataNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }),
+            COMMENT => SerializedNode::CommentNode(CommentNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }),
+            other => return Err(RrwebError::Malformed(format!("unknown node type {other}"))),
+        };
+        map.insert(id, node);
+        Ok(id)
+    }
+    fn element(
+        &mut self,
+        value: &Value,
+        id: u32,
+        root_id: u32,
+        mut children: Vec<u32>,
+        map: &mut HashMap<u32, SerializedNode>,
+    ) -> Result<SerializedNode, RrwebError> {
+        let is_svg = flag(value, "isSVG");
+        let tag_name = string(value, "tagName")?;
+        let mut tag_name = if is_svg {
+            tag_name.to_string()
+        } else {
+            tag_name.to_uppercase()
+        };
+        let mut attributes = Vec::new();
+        let mut form_state = FormState {
+            value: None,
+            checked: None,
+            selected: None,
+            selected_index: None,
+        };
+        let mut scroll = ScrollPosition { top: 0, left: 0 };
+        let mut css_text = None;
+        for (name, value) in value
+            .get("attributes")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Number(value) => value.to_string(),
+                // boolean attributes are present or missing.
+                Value::Bool(true) => String::new(),
+                _ => continue,
+            };
+            match (tag_name.as_str(), name.as_str()) {
+                ("INPUT" | "TEXTAREA" | "SELECT", "value") => form_state.value = Some(value),
+                ("INPUT", "checked") => form_state.checked = Some(true),
+                ("OPTION", "selected") => form_state.selected = Some(true),
+                (_, "rr_scrollTop") => scroll.top = value.parse().unwrap_or_default(),
+                (_, "rr_scrollLeft") => scroll.left = value.parse().unwrap_or_default(),
+                (_, "_cssText") => css_text = Some(value),
+                // rrweb's own bookkeeping, like rr_dataURL and rr_width.
+                (_, name) if name.starts_with("rr_") => {}
+                _ => attributes.push((name.clone(), value)),
+            }
+        }
+        // rrweb inlines stylesheets as _cssText, turn them back into style elements.
+        if let Some(css_text) = css_text.filter(|_| children.is_empty()) {
+            if tag_name == "LINK" {
+                tag_name = "STYLE".to_string();
+            }
+            let text_id = self.generate_id();
+            map.insert(
+                text_id,
+                SerializedNode::TextNode(TextNode {
+                    id: text_id,
+                    root_id,
+                    is_shadow_host: false,
+                    is_shadow: false,
+                    text_content: Some(css_text),
+                }),
+            );
+            children.push(text_id);
+        }
+        let has_form_state = form_state.value.is_some()
+            || form_state.checked.is_some()
+            || form_state.selected.is_some();
+        Ok(SerializedNode::ElementNode(ElementNode {
+            id,
+            root_id,
+            is_shadow_host: flag(value, "isShadowHost"),
+            is_shadow: flag(value, "isShadow"),
+            tag_name,
+            attributes: Some(attributes),
+            child_nodes: stored_children(children),
+            is_svg,
+            need_block: flag(value, "needBlock"),
+            is_custom: flag(value, "isCustom"),
+            form_state: has_form_state.then_some(form_state),
+            scroll: (scroll.top != 0 || scroll.left != 0).then_some(scroll),
+        }))
+    }
+    fn incremental(
+        &mut self,
+        data: &Value,
+        millis: f64,
+        recording: &mut Recording,
+    ) -> Result<(), RrwebError> {
+        let source = number(data, "source")? as u64;
+        match source {
+            MUTATION => self.mutation(data, millis, &mut recording.mutations)?,
+            MOUSE_MOVE | TOUCH_MOVE => {
+                for position in array(data, "positions") {
+                    let x = number(position, "x")? as i32;
+                    let y = number(position, "y")? as i32;
+                    let time_offset = position
+                        .get("timeOffset")
+                        .and_then(Value::as_f64)
+                        .unwrap_or_default();
+                    let event = if source == MOUSE_MOVE {
+                        CaptureEvent::MouseMove { x, y }
+                    } else {
+                        CaptureEvent::TouchMove { x, y }
+                    };
+                    recording.events.push((millis + time_offset, event));
+                }
+            }
+            MOUSE_INTERACTION if number(data, "type")? as u64 == CLICK => {
+                recording.events.push((
+                    millis,
+                    CaptureEvent::MouseClick {
+                        x: number(data, "x")? as i32,
+                        y: number(data, "y")? as i32,
+                    },
+                ));
+            }
+            VIEWPORT_RESIZE => recording.events.push((
+                millis,
+                CaptureEvent::WindowResize {
+                    height: number(data, "height")? as u32,
+                    width: number(data, "width")? as u32,
+                },
+            )),
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Pushes the mutations in the order rrweb applies them, removes, adds, texts then attributes.
+    /// They share a time so replay's stable sort keeps that order.
+    fn mutation(
+        &mut self,
+        data: &Value,
+        millis: f64,
+        mutations: &mut Vec<MutationVariant>,
+    ) -> Result<(), RrwebError> {
+        for remove in array(data, "removes") {
+            mutations.push(MutationVariant::ChildListRemoved(MutationChildList {
+                target_id: self.id(field(remove, "parentId")?)?,
+                millis,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![self.id(field(remove, "id")?)?],
+            }));
+        }
+
+        // rrweb adds every node of a subtree separately, nest them again so they're built together.
+        let mut added = HashMap::new();
+        let mut placements = Vec::new();
+        for add in array(data, "adds") {
+            placements.push(Placement {
+                id: self.node(field(add, "node")?, 0, &mut added)?,
+                parent: self.id(field(add, "parentId")?)?,
+                previous: self.optional_id(add, "previousId")?,
+                next: self.optional_id(add, "nextId")?,
+            });
+        }
+        let mut by_parent: Vec<(u32, Vec<Placement>)> = Vec::new();
+        for placement in placements {
+            match by_parent
+                .iter_mut()
+                .find(|(parent, _)| *parent == placement.parent)
+            {
+                Some((_, siblings)) => siblings.push(placement),
+                None => by_parent.push((placement.parent, vec![placement])),
+            }
+        }
+        let mut top_level = Vec::new();
+        for (parent, siblings) in by_parent {
+            let siblings = order_siblings(siblings);
+            match added.get_mut(&parent) {
+                Some(parent) => {
+                    let mut children = children_in_order(parent);
+                    children.extend(siblings.iter().map(|placement| placement.id));
+                    set_child_nodes(parent, stored_children(children));
+                }
+                None => top_level.push(siblings),
+            }
+        }
+        for siblings in top_level {
+            for (i, placement) in siblings.iter().enumerate() {
+                let previous = if i == 0 {
+                    placement.previous
+                } else {
+                    Some(siblings[i - 1].id)
+                };
+                mutations.push(MutationVariant::ChildListAdded((
+                    MutationChildList {
+                        target_id: placement.parent,
+                        millis,
+                        prev_sibling: previous,
+                        next_sibling: placement.next,
+                        nodes: vec![placement.id],
+                    },
+                    subtree(&added, placement.id),
+                )));
+            }
+        }
+
+        for text in array(data, "texts") {
+            mutations.push(MutationVariant::CharacterData(MutationCharacterData {
+                target_id: self.id(field(text, "id")?)?,
+                millis,
+                text_content: text
+                    .get("value")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+            }));
+        }
+        for attribute in array(data, "attributes") {
+            let target_id = self.id(field(attribute, "id")?)?;
+            for (name, value) in field(attribute, "attributes")?
+                .as_object()
+                .into_iter()
+                .flatten()
+            {
+                // removed attributes are null and style diffs are objects, neither has a MutationAttributes.
+                if let Some(value) = value.as_str() {
+                    mutations.push(MutationVariant::Attributes(MutationAttributes {
+                        target_id,
+                        millis,
+                        attribute: Some((name.clone(), value.to_string())),
+                    }));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puts nodes added under the same parent in document order. A node goes before its next sibling
+/// or after its previous one when that sibling was added too, otherwise at the end.
+fn order_siblings(mut pending: Vec<Placement>) -> Vec<Placement> {
+    let mut ordered: Vec<Placement> = Vec::new();
+    while !pending.is_empty() {
+        let pending_ids = pending.iter().map(|p| p.id).collect::<Vec<_>>();
+        let position = |ordered: &[Placement], placement: &Placement| {
+            let index_of = |id: u32| ordered.iter().position(|p| p.id == id);
+            if let Some(i) = placement.next.and_then(index_of) {
+                return Some(i);
+            }
+            if let Some(i) = placement.previous.and_then(index_of) {
+                return Some(i + 1);
+            }
+            // wait for the sibling it's placed against.
+            let waits = [placement.previous, placement.next]
+                .into_iter()
+                .flatten()
+                .any(|id| pending_ids.contains(&id));
+            (!waits).then_some(ordered.len())
+        };
+        let next = pending
+            .iter()
+            .enumerate()
+            .find_map(|(i, placement)| position(&ordered, placement).map(|at| (i, at)));
+        match next {
+            Some((i, at)) => {
+                let placement = pending.remove(i);
+                ordered.insert(at, placement);
+            }
+            // siblings that only point at each other, keep the recorded order.
+            None => ordered.append(&mut pending),
+        }
+    }
+    ordered
+}
+
+/// The node and all of its descendants.
+fn subtree(map: &HashMap<u32, SerializedNode>, id: u32) -> HashMap<u32, SerializedNode> {
+    let mut nodes = HashMap::new();
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if let Some(node) = map.get(&id) {
+            stack.extend(node.child_nodes().unwrap_or_default());
+            nodes.insert(id, node.clone());
+        }
+    }
+    nodes
+}
+
+/// Children in document order as they're stored, last child first and None when there are none.
+fn stored_children(mut children: Vec<u32>) -> Option<Vec<u32>> {
+    children.reverse();
+    (!children.is_empty()).then_some(children)
+}
+
+fn set_child_nodes(node: &mut SerializedNode, child_nodes: Option<Vec<u32>>) {
+    match node {
+        SerializedNode::DocumentNode(this) => this.child_nodes = child_nodes,
+        SerializedNode::ElementNode(this) => this.child_nodes = child_nodes,
+        _ => {}
+    }
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, RrwebError> {
+    value
+        .get(name)
+        .ok_or_else(|| RrwebError::Malformed(format!("missing {name}")))
+}
+
+fn number(value: &Value, name: &str) -> Result<f64, RrwebError> {
+    field(value, name)?
+        .as_f64()
+        .ok_or_else(|| RrwebError::Malformed(format!("{name} isn't a number")))
+}
+
+fn string<'a>(value: &'a Value, name: &str) -> Result<&'a str, RrwebError> {
+    field(value, name)?
+        .as_str()
+        .ok_or_else(|| RrwebError::Malformed(format!("{name} isn't a string")))
+}
+
+fn flag(value: &Value, name: &str) -> bool {
+    value.get(name).and_then(Value::as_bool).unwrap_or_default()
+}
+
+fn array<'a>(value: &'a Value, name: &str) -> impl Iterator<Item = &'a Value> {
+    value
+        .get(name)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(
+        id: u32,
+        tag_name: &str,
+        attributes: &[(&str, &str)],
+        children: &[u32],
+    ) -> ElementNode {
+        ElementNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: tag_name.to_string(),
+            attributes: Some(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            child_nodes: stored_children(children.to_vec()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        }
+    }
+
+    fn text(id: u32, text_content: &str) -> SerializedNode {
+        SerializedNode::TextNode(TextNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            text_content: Some(text_content.to_string()),
+        })
+    }
+
+    /// A page with a doctype, a scrolled body and a filled in input, then a div added, edited and removed again.
+    /// Only holds what rrweb events can carry, so it survives a round trip.
+    fn recording() -> Recording {
+        let document = SerializedNode::DocumentNode(DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: stored_children(vec![1, 2]),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: Some(Viewport {
+                width: 1280,
+                height: 720,
+                device_pixel_ratio: 1.,
+                scale: 1.,
+            }),
+            scroll: Some(ScrollPosition { top: 200, left: 0 }),
+        });
+        let doctype = SerializedNode::DocumentTypeNode(DocumentTypeNode {
+            id: 1,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            name: "html".to_string(),
+            public_id: String::new(),
+            system_id: String::new(),
+        });
+        let body = ElementNode {
+            scroll: Some(ScrollPosition { top: 10, left: 0 }),
+            ..element(3, "BODY", &[], &[4, 5])
+        };
+        let input = ElementNode {
+            form_state: Some(FormState {
+                value: Some("hi".to_string()),
+                checked: None,
+                selected: None,
+                selected_index: None,
+            }),
+            ..element(5, "INPUT", &[("type", "text")], &[])
+        };
+        let snapshot = HashMap::from([
+            (0, document),
+            (1, doctype),
+            (
+                2,
+                SerializedNode::ElementNode(element(2, "HTML", &[], &[3])),
+            ),
+            (3, SerializedNode::ElementNode(body)),
+            (4, text(4, "hello")),
+            (5, SerializedNode::ElementNode(input)),
+        ]);
+        let added = HashMap::from([
+            (6, SerializedNode::ElementNode(element(6, "DIV", &[], &[7]))),
+            (7, text(7, "new")),
+        ]);
+        Recording {
+            snapshot,
+            snapshot_millis: 0.,
+            mutations: vec![
+                MutationVariant::ChildListAdded((
+                    MutationChildList {
+                        target_id: 3,
+                        millis: 10.,
+                        prev_sibling: Some(5),
+                        next_sibling: None,
+                        nodes: vec![6],
+                    },
+                    added,
+                )),
+                MutationVariant::CharacterData(MutationCharacterData {
+                    target_id: 4,
+                    millis: 20.,
+                    text_content: Some("bye".to_string()),
+                }),
+                MutationVariant::Attributes(MutationAttributes {
+                    target_id: 6,
+                    millis: 30.,
+                    attribute: Some(("class".to_string(), "open".to_string())),
+                }),
+                MutationVariant::ChildListRemoved(MutationChildList {
+                    target_id: 3,
+                    millis: 40.,
+                    prev_sibling: None,
+                    next_sibling: None,
+                    nodes: vec![6],
+                }),
+            ],
+            events: vec![
+                (5., CaptureEvent::MouseMove { x: 1, y: 2 }),
+                (
+                    15.,
+                    CaptureEvent::WindowResize {
+                        height: 600,
+                        width: 800,
+                    },
+                ),
+                (25., CaptureEvent::MouseClick { x: 3, y: 4 }),
+                (35., CaptureEvent::TouchMove { x: 5, y: 6 }),
+            ],
+        }
+    }
+
+    #[test]
+    fn exports_a_meta_event_a_full_snapshot_and_incremental_events_in_time_order() {
+        let events = export(&recording(), 1_000.);
+        let types = events
+            .iter()
+            .map(|event| event["type"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(types[..2], [META, FULL_SNAPSHOT]);
+        assert!(types[2..].iter().all(|kind| *kind == INCREMENTAL_SNAPSHOT));
+        let timestamps = events
+            .iter()
+            .map(|event| event["timestamp"].as_f64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            timestamps,
+            [1_000., 1_000., 1_005., 1_010., 1_015., 1_020., 1_025., 1_030., 1_035., 1_040.]
+        );
+        let sources = events[2..]
+            .iter()
+            .map(|event| event["data"]["source"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                MOUSE_MOVE,
+                MUTATION,
+                VIEWPORT_RESIZE,
+                MUTATION,
+                MOUSE_INTERACTION,
+                MUTATION,
+                TOUCH_MOVE,
+                MUTATION
+            ]
+        );
+        assert_eq!(
+            events[0]["data"],
+            json!({ "href": "", "width": 1280, "height": 720 })
+        );
+    }
+
+    #[test]
+    fn exports_nodes_in_document_order_with_shifted_ids() {
+        let events = export(&recording(), 0.);
+        let data = &events[1]["data"];
+        assert_eq!(data["initialOffset"], json!({ "top": 200, "left": 0 }));
+        let document = &data["node"];
+        assert_eq!(document["type"], DOCUMENT);
+        assert_eq!(document["id"], 1);
+        let [doctype, html] = document["childNodes"].as_array().unwrap().as_slice() else {
+            panic!("expected a doctype and html in {document}");
+        };
+        assert_eq!(
+            *doctype,
+            json!({ "type": DOCUMENT_TYPE, "id": 2, "name": "html", "publicId": "", "systemId": "" })
+        );
+        assert_eq!(html["tagName"], "html");
+        let body = &html["childNodes"][0];
+        assert_eq!(
+            body["attributes"],
+            json!({ "rr_scrollTop": 10, "rr_scrollLeft": 0 })
+        );
+        assert_eq!(
+            body["childNodes"],
+            json!([
+                { "type": TEXT, "id": 5, "textContent": "hello" },
+                {
+                    "type": ELEMENT,
+                    "id": 6,
+                    "tagName": "input",
+                    "attributes": { "type": "text", "value": "hi" },
+                    "childNodes": [],
+                    "isSVG": false,
+                    "needBlock": false,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn exports_mutations_as_rrweb_mutation_data() {
+        let events = export(&recording(), 0.);
+        let data = |millis: f64| {
+            &events
+                .iter()
+                .find(|event| event["timestamp"] == millis)
+                .unwrap()["data"]
+        };
+        // the added subtree is flattened, parents first.
+        assert_eq!(
+            data(10.)["adds"],
+            json!([
+                {
+                    "parentId": 4,
+                    "previousId": 6,
+                    "nextId": null,
+                    "node": {
+                        "type": ELEMENT,
+                        "id": 7,
+                        "tagName": "div",
+                        "attributes": {},
+                        "childNodes": [],
+                        "isSVG": false,
+                        "needBlock": false,
+                    },
+                },
+                {
+                    "parentId": 7,
+                    "previousId": null,
+                    "nextId": null,
+                    "node": { "type": TEXT, "id": 8, "textContent": "new" },
+                },
+            ])
+        );
+        assert_eq!(data(20.)["texts"], json!([{ "id": 5, "value": "bye" }]));
+        assert_eq!(
+            data(30.)["attributes"],
+            json!([{ "id": 7, "attributes": { "class": "open" } }])
+        );
+        assert_eq!(data(40.)["removes"], json!([{ "parentId": 4, "id": 7 }]));
+        assert_eq!(data(40.)["adds"], json!([]));
+        assert_eq!(
+            data(25.),
+            &json!({ "source": MOUSE_INTERACTION, "type": CLICK, "id": 1, "x": 3, "y": 4 })
+        );
+    }
+
+    #[test]
+    fn exports_clicks_at_their_target_and_leaves_out_errors() {
+        let recording = Recording {
+            events: vec![
+                (
+                    1.,
+                    CaptureEvent::Click {
+                        x: 1,
+                        y: 2,
+                        page_x: 1,
+                        page_y: 202,
+                        target: Some(5),
+                    },
+                ),
+                (2., CaptureEvent::Error { line: 1, column: 2 }),
+                (3., CaptureEvent::Scoll {}),
+            ],
+            ..recording()
+        };
+        let events = export(&recording, 0.);
+        let incremental = events
+            .iter()
+            .filter(|event| event["type"] == INCREMENTAL_SNAPSHOT)
+            .filter(|event| event["data"]["source"] != MUTATION)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            incremental,
+            [&json!({
+                "type": INCREMENTAL_SNAPSHOT,
+                "timestamp": 1.,
+                "data": { "source": MOUSE_INTERACTION, "type": CLICK, "id": 6, "x": 1, "y": 2 },
+            })]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let recording = recording();
+        assert_eq!(
+            import(&export(&recording, 1_700_000_000_000.)).unwrap(),
+            recording
+        );
+    }
+
+    #[test]
+    fn imports_what_only_rrweb_records() {
+        let events = [
+            json!({ "type": 4, "timestamp": 100, "data": { "href": "", "width": 800, "height": 600 } }),
+            json!({ "type": 2, "timestamp": 100, "data": {
+                "node": { "type": 0, "id": 1, "childNodes": [
+                    { "type": 2, "id": 2, "tagName": "link", "attributes": {
+                        "_cssText": "p { color: red }", "rr_dataURL": "data:", "disabled": true,
+                    }, "childNodes": [] },
+                ] },
+                "initialOffset": { "top": 0, "left": 0 },
+            } }),
+            json!({ "type": 3, "timestamp": 150, "data": {
+                "source": 0, "adds": [], "removes": [], "texts": [],
+                "attributes": [{ "id": 2, "attributes": { "media": null, "style": { "color": "red" } } }],
+            } }),
+            // a reload, everything after it belongs to another page.
+            json!({ "type": 2, "timestamp": 200, "data": { "node": { "type": 0, "id": 10, "childNodes": [] } } }),
+            json!({ "type": 3, "timestamp": 250, "data": {
+                "source": 1, "positions": [{ "x": 1, "y": 1, "id": 10, "timeOffset": 0 }],
+            } }),
+        ];
+        let recording = import(&events).unwrap();
+        assert!(recording.mutations.is_empty());
+        assert!(recording.events.is_empty());
+        let Some(SerializedNode::DocumentNode(document)) = recording.snapshot.get(&0) else {
+            panic!("expected the document at 0");
+        };
+        assert_eq!(document.scroll, None);
+        assert_eq!(document.viewport.map(|viewport| viewport.width), Some(800));
+        let Some(SerializedNode::ElementNode(style)) = recording.snapshot.get(&1) else {
+            panic!("expected the link at 1");
+        };
+        assert_eq!(style.tag_name, "STYLE");
+        assert_eq!(
+            style.attributes,
+            Some(vec![("disabled".to_string(), String::new())])
+        );
+        let [css] = style.child_nodes.as_deref().unwrap() else {
+            panic!("expected the inlined stylesheet");
+        };
+        assert_eq!(recording.snapshot[css], text(*css, "p { color: red }"));
+    }
+
+    #[test]
+    fn refuses_recordings_without_a_full_snapshot() {
+        assert!(matches!(import(&[]), Err(RrwebError::MissingFullSnapshot)));
+        let meta = json!({ "type": 4, "timestamp": 0, "data": { "width": 1, "height": 1 } });
+        assert!(matches!(
+            import(&[meta]),
+            Err(RrwebError::MissingFullSnapshot)
+        ));
+        let no_data = json!({ "type": 2, "timestamp": 0 });
+        assert!(matches!(import(&[no_data]), Err(RrwebError::Malformed(_))));
+    }
+}
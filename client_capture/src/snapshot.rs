@@ -1,7 +1,13 @@
+use crate::{
+    queue::{is_keyframe_due, keyframe_taken},
+    upload::{codec, upload_with_headers},
+    wire::{PAGE_URL_HEADER, TIME_ORIGIN_HEADER},
+    KEYFRAME_SOURCE, OBSERVERS, PENDING_FRAMES,
+};
 use crate::{types::*, NODE_ID, NODE_MAP, REVERSE_NODE_MAP, ROOTS, SERIALIZED_NODE_MAP};
 use crate::{window, SNAPSHOT_TIME};
 use wasm_bindgen::{JsCast, JsValue};
-use web_sys::Node;
+use web_sys::{HtmlIFrameElement, Node};
 
 //. if no node is given will snapshot the document
 pub async fn snapshot<S: AsRef<str>>(
@@ -17,18 +23,55 @@ pub async fn snapshot<S: AsRef<str>>(
     snapshot_parse_dom(&node, 0);
     //initialize snapshot time...
     _ = SNAPSHOT_TIME.with(|time| *time);
-    let body = bincode::serialize(&SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow().clone()))
-        .unwrap();
-
-    gloo_net::http::Request::post(digest_endpoint.as_ref())
-        .body(body)
-        .expect("body ody ody")
-        .send()
-        .await
-        .expect("Server to receive request.");
+    upload_snapshot(digest_endpoint.as_ref());
+    KEYFRAME_SOURCE
+        .with(|source| *source.borrow_mut() = Some((node, digest_endpoint.as_ref().to_string())));
     Ok(())
 }
 
+/// Serializes the snapshotted node again from scratch and uploads it as a new keyframe,
+/// after mutations were dropped the recorded ids can't be trusted anymore. Until MIN_KEYFRAME_INTERVAL_MILLIS
+/// has passed since the last one this does nothing, the keyframe stays pending and the stream tries again.
+pub(crate) fn keyframe() {
+    if !is_keyframe_due() {
+        return;
+    }
+    let Some((node, endpoint)) = KEYFRAME_SOURCE.with(|source| source.borrow().clone()) else {
+        return;
+    };
+    // undelivered records describe changes the new snapshot already contains.
+    OBSERVERS.with(|observers| {
+        for (_, observer) in observers.borrow().iter() {
+            observer.take_records();
+        }
+    });
+    NODE_ID.with(|id| *id.borrow_mut() = 0);
+    NODE_MAP.with(|node_map| node_map.borrow_mut().clear());
+    SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow_mut().clear());
+    REVERSE_NODE_MAP.with(|reverse_node_map| reverse_node_map.clear());
+    ROOTS.with(|roots| roots.borrow_mut().clear());
+    snapshot_parse_dom(&node, 0);
+    upload_snapshot(&endpoint);
+    keyframe_taken();
+}
+
+fn upload_snapshot(endpoint: &str) {
+    let codec = codec();
+    let body = SERIALIZED_NODE_MAP.with(|node_map| codec.encode_snapshot(&node_map.borrow()));
+    let mut headers = vec![(
+        TIME_ORIGIN_HEADER.to_string(),
+        window()
+            .performance()
+            .expect("performance")
+            .time_origin()
+            .to_string(),
+    )];
+    if let Ok(url) = window().location().href() {
+        headers.push((PAGE_URL_HEADER.to_string(), url));
+    }
+    upload_with_headers(endpoint, codec.content_type(), body, headers);
+}
+
 // Returns a list of serialized nodes that were created after parsing DOM tree beginning at node.
 fn snapshot_parse_dom(initial_node: &Node, parent_id: u32) {
     let id = || NODE_ID.with(|id| *id.borrow());
@@ -53,17 +96,29 @@ fn snapshot_parse_dom(initial_node: &Node, parent_id: u32) {
         SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow_mut().insert(id(), serialized_node));
         // Push the child nodes onto the stack in reverse order so that
         // the first child is processed first.
-        let child_nodes = current_node.child_nodes();
-        let length = child_nodes.length();
-
-        for i in (0..length).rev() {
-            if let Some(child) = child_nodes.item(i) {
-                stack.push((child, id()));
-            }
+        for child in child_nodes_of(&current_node).into_iter().rev() {
+            stack.push((child, id()));
         }
         NODE_ID.with(|id| *id.borrow_mut() += 1);
     }
 }
+
+/// The child nodes of node, followed by the document of a same-origin iframe.
+/// Iframes with a document are queued in PENDING_FRAMES so the observer can watch them.
+pub(crate) fn child_nodes_of(node: &Node) -> Vec<Node> {
+    let child_nodes = node.child_nodes();
+    let mut children = (0..child_nodes.length())
+        .filter_map(|i| child_nodes.item(i))
+        .collect::<Vec<_>>();
+    if let Some(iframe) = node.dyn_ref::<HtmlIFrameElement>() {
+        // content_document is None for cross-origin frames.
+        if let Some(document) = iframe.content_document() {
+            children.push(document.unchecked_into::<Node>());
+            PENDING_FRAMES.with(|frames| frames.borrow_mut().push(iframe.clone()));
+        }
+    }
+    children
+}
 /// Don't call this from replay code.
 pub fn map_node_to_id(node: &Node) -> Option<u32> {
     REVERSE_NODE_MAP
@@ -0,0 +1,129 @@
+//! Sends uploads over one WebSocket to replay-server instead of a request each, see Transport::WebSocket.
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use gloo_timers::future::TimeoutFuture;
+use js_sys::Uint8Array;
+use tokio::sync::oneshot;
+use wasm_bindgen::{prelude::Closure, JsCast};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{BinaryType, Event, MessageEvent, WebSocket};
+
+use crate::{
+    upload::{session_url, Upload},
+    wire::{SocketAck, SocketFrame, SESSION_HEADER},
+    SOCKET,
+};
+
+/// An upload that isn't acknowledged in this long counts as failed and is retried.
+const ACK_TIMEOUT_MILLIS: u32 = 10_000;
+
+#[derive(Default)]
+pub struct Socket {
+    state: State,
+    seq: u32,
+    /// Uploads waiting for their ack, by seq.
+    pending: HashMap<u32, oneshot::Sender<SocketAck>>,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Closed,
+    Open(WebSocket),
+    /// The socket couldn't be established, uploads are posted for the rest of the page.
+    Unavailable,
+}
+
+pub(crate) enum SocketError {
+    Unavailable,
+    /// Sent but not acknowledged, because the socket closed or the ack timed out.
+    Lost,
+}
+
+/// Sends upload over the socket to url, opening it if needed, and returns the server's ack.
+pub(crate) async fn send(url: &str, upload: &Upload) -> Result<SocketAck, SocketError> {
+    let socket = connect(url).await.ok_or(SocketError::Unavailable)?;
+    let (acked, ack) = oneshot::channel();
+    let seq = SOCKET.with(|socket| {
+        let mut socket = socket.borrow_mut();
+        socket.seq = socket.seq.wrapping_add(1);
+        let seq = socket.seq;
+        socket.pending.insert(seq, acked);
+        seq
+    });
+    let mut headers = upload.headers.clone();
+    headers.push((SESSION_HEADER.to_string(), upload.session.clone()));
+    let frame = SocketFrame {
+        seq,
+        headers,
+        body: upload.body.clone(),
+    };
+    if socket.send_with_u8_array(&frame.encode()).is_err() {
+        SOCKET.with(|socket| socket.borrow_mut().pending.remove(&seq));
+        return Err(SocketError::Lost);
+    }
+    spawn_local(async move {
+        TimeoutFuture::new(ACK_TIMEOUT_MILLIS).await;
+        // dropping the sender resolves the ack as lost.
+        SOCKET.with(|socket| socket.borrow_mut().pending.remove(&seq));
+    });
+    ack.await.map_err(|_| SocketError::Lost)
+}
+
+/// Returns the open socket, or opens one. A socket that closes after opening is reopened by the next upload,
+/// one that closes before it opens marks the socket unavailable.
+async fn connect(url: &str) -> Option<WebSocket> {
+    let current = SOCKET.with(|socket| match &socket.borrow().state {
+        State::Open(socket) => Some(Some(socket.clone())),
+        State::Unavailable => Some(None),
+        State::Closed => None,
+    });
+    if let Some(socket) = current {
+        return socket;
+    }
+    let Ok(socket) = WebSocket::new(&session_url(url)) else {
+        SOCKET.with(|socket| socket.borrow_mut().state = State::Unavailable);
+        return None;
+    };
+    socket.set_binary_type(BinaryType::Arraybuffer);
+    let (opened, is_open) = oneshot::channel::<bool>();
+    let opened = Rc::new(RefCell::new(Some(opened)));
+
+    let on_open = {
+        let opened = opened.clone();
+        let socket = socket.clone();
+        Closure::wrap(Box::new(move |_: Event| {
+            SOCKET.with(|s| s.borrow_mut().state = State::Open(socket.clone()));
+            if let Some(opened) = opened.borrow_mut().take() {
+                _ = opened.send(true);
+            }
+        }) as Box<dyn FnMut(_)>)
+    };
+    // errors are always followed by close.
+    let on_close = Closure::wrap(Box::new(move |_: Event| {
+        let never_opened = opened.borrow_mut().take().map(|opened| opened.send(false));
+        SOCKET.with(|socket| {
+            let mut socket = socket.borrow_mut();
+            socket.state = if never_opened.is_some() {
+                State::Unavailable
+            } else {
+                State::Closed
+            };
+            socket.pending.clear();
+        });
+    }) as Box<dyn FnMut(_)>);
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let bytes = Uint8Array::new(&event.data()).to_vec();
+        if let Ok(ack) = SocketAck::decode(&bytes) {
+            if let Some(acked) = SOCKET.with(|socket| socket.borrow_mut().pending.remove(&ack.seq))
+            {
+                _ = acked.send(ack);
+            }
+        }
+    }) as Box<dyn FnMut(_)>);
+    socket.set_onopen(Some(on_open.into_js_value().unchecked_ref()));
+    socket.set_onclose(Some(on_close.into_js_value().unchecked_ref()));
+    socket.set_onmessage(Some(on_message.into_js_value().unchecked_ref()));
+
+    is_open.await.unwrap_or_default().then_some(socket)
+}
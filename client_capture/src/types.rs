@@ -4,8 +4,9 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use wasm_bindgen::JsCast;
 use web_sys::{
-    Document, DocumentType, DomException, Element, GetRootNodeOptions, MutationRecord, Node,
-    SvgElement,
+    Document, DocumentType, DomException, Element, GetRootNodeOptions, HtmlIFrameElement,
+    HtmlInputElement, HtmlOptionElement, HtmlSelectElement, HtmlTextAreaElement, MutationRecord,
+    Node, SvgElement,
 };
 
 use crate::{
@@ -13,7 +14,6 @@ use crate::{
     NODE_MAP, ROOTS, SERIALIZED_NODE_MAP,
 };
 
-#[cfg_attr(feature = "influxdb", derive(influxdb::InfluxDbWriteable))]
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum SerializedNode {
     DocumentNode(DocumentNode),
@@ -25,17 +25,40 @@ pub enum SerializedNode {
 }
 
 impl SerializedNode {
+    pub fn scroll(&self) -> Option<ScrollPosition> {
+        match self {
+            SerializedNode::DocumentNode(this) => this.scroll,
+            SerializedNode::ElementNode(this) => this.scroll,
+            _ => None,
+        }
+    }
+    /// Child ids in the order they're stored, last child first.
+    pub fn child_nodes(&self) -> Option<Vec<u32>> {
+        match self {
+            SerializedNode::DocumentNode(this) => this.child_nodes.clone(),
+            SerializedNode::ElementNode(this) => this.child_nodes.clone(),
+            _ => None,
+        }
+    }
+    /// (is_shadow_host, is_shadow)
+    pub fn shadow(&self) -> (bool, bool) {
+        match self {
+            SerializedNode::DocumentNode(this) => (this.is_shadow_host, this.is_shadow),
+            SerializedNode::ElementNode(this) => (this.is_shadow_host, this.is_shadow),
+            SerializedNode::TextNode(this) => (this.is_shadow_host, this.is_shadow),
+            SerializedNode::CommentNode(this) => (this.is_shadow_host, this.is_shadow),
+            SerializedNode::CDataNode(this) => (this.is_shadow_host, this.is_shadow),
+            SerializedNode::DocumentTypeNode(this) => (this.is_shadow_host, this.is_shadow),
+        }
+    }
     pub fn set_attribute(&mut self, name: String, value: String) {
         match self {
             SerializedNode::ElementNode(this) => {
-                let mut attributes = this
-                    .attributes
-                    .clone()
-                    .unwrap_or_default()
-                    .into_iter()
-                    .filter(|(n, _)| n != &name)
-                    .collect::<Vec<(String, String)>>();
-                attributes.push((name, value));
+                let attributes = this.attributes.get_or_insert_with(Vec::new);
+                match attributes.iter_mut().find(|(n, _)| n == &name) {
+                    Some((_, current)) => *current = value,
+                    None => attributes.push((name, value)),
+                }
             }
             _ => panic!("this node doesn't support attributes"),
         }
@@ -94,6 +117,7 @@ impl SerializedNode {
                 attributes,
                 child_nodes,
                 is_custom,
+                form_state,
                 ..
             }) => {
                 let tag_name = if *is_custom {
@@ -126,10 +150,18 @@ impl SerializedNode {
                         .map_err(|err| err.unchecked_into::<DomException>())?;
                 }
                 let el = node.unchecked_ref::<Element>();
+                // An iframe with a captured document must not load its source over the rebuilt document.
+                let has_document = tag_name == "IFRAME" && child_nodes.is_some();
                 for (name, value) in attributes.as_ref().cloned().unwrap_or_default() {
+                    if has_document && (name == "src" || name == "srcdoc") {
+                        continue;
+                    }
                     el.set_attribute(name.as_str(), value.as_str())
                         .map_err(|err| err.unchecked_into::<DomException>())?;
                 }
+                if let Some(form_state) = form_state {
+                    form_state.restore(el);
+                }
                 Ok((node, child_nodes.as_ref().cloned().unwrap_or_default()))
             }
             SerializedNode::CommentNode(CommentNode { text_content, .. }) => {
@@ -154,12 +186,28 @@ impl SerializedNode {
                     .map_err(|err| err.unchecked_into::<DomException>())?;
                 Ok((node, Vec::new()))
             }
+            // A nested document's parent is the replayed iframe element, we build into the iframe's own document.
+            SerializedNode::DocumentNode(DocumentNode { child_nodes, .. })
+                if parent.dyn_ref::<HtmlIFrameElement>().is_some() =>
+            {
+                let document = parent
+                    .unchecked_ref::<HtmlIFrameElement>()
+                    .content_document()
+                    .expect("replayed iframe to be attached to a document")
+                    .unchecked_into::<Node>();
+                while let Some(child) = document.last_child() {
+                    document
+                        .remove_child(&child)
+                        .map_err(|err| err.unchecked_into::<DomException>())?;
+                }
+                Ok((document, child_nodes.as_ref().cloned().unwrap_or_default()))
+            }
             // expect the document node of the iframe to be the parent, we won't append a document node just add its compat node
             SerializedNode::DocumentNode(DocumentNode { child_nodes, .. }) => {
                 // not sure what to do here, compat_mode was to fix a bug that I don't know yet
                 Ok((
                     parent.clone(),
-                    child_nodes.as_ref().cloned().unwrap_or_else(|| Vec::new()),
+                    child_nodes.as_ref().cloned().unwrap_or_default(),
                 ))
             }
             SerializedNode::CDataNode(_) => todo!(),
@@ -193,7 +241,7 @@ impl SerializedNode {
         // JS node equality is based on memory location.
         if root.loose_eq(node.as_ref()) {
             // If the root doesn't already exist in roots add it.
-            if find_root_id(&node).is_none() {
+            if find_root_id(node).is_none() {
                 ROOTS.with(|roots| roots.borrow_mut().push((node.clone(), id)));
                 // If the current node type doesn't equal the document node and it is a root, then it is a shadow root
                 if node.node_type() != 9 {
@@ -210,7 +258,7 @@ impl SerializedNode {
         // This panics if a root of a node is not in roots. This could happen if the child is evaluated by this function before the parent.
         // So if the node in the functions argument is not the dom root, and is called before the dom root, or any other child before parent relation.
         // if the root id doesn't exist in our map, assume the id is 0
-        let root_id = find_root_id(&node).unwrap_or_default();
+        let root_id = find_root_id(node).unwrap_or_default();
 
         match node.node_type() {
             1 => Self::ElementNode({
@@ -242,7 +290,11 @@ impl SerializedNode {
                             } else {
                                 attr.value()
                             };
-                            list.push((attr.name(), value));
+                            if let Some(value) =
+                                recorded_attribute(&attr.name(), value, || is_masked(el))
+                            {
+                                list.push((attr.name(), value));
+                            }
                         }
                         Some(list)
                     },
@@ -253,6 +305,8 @@ impl SerializedNode {
                         false
                     },
                     is_custom: !HTML_TAGS.contains(&el.tag_name().as_str()),
+                    form_state: FormState::new(el),
+                    scroll: ScrollPosition::of_element(el),
                 }
             }),
             3 => Self::TextNode(TextNode {
@@ -260,7 +314,7 @@ impl SerializedNode {
                 root_id,
                 is_shadow_host,
                 is_shadow,
-                text_content: node.text_content(),
+                text_content: recorded_text(node),
             }),
             9 => Self::DocumentNode(DocumentNode {
                 id,
@@ -269,6 +323,12 @@ impl SerializedNode {
                 is_shadow,
                 child_nodes: None,
                 compat_mode: node.unchecked_ref::<Document>().compat_mode(),
+                // iframe documents are laid out by the iframe element, only the top document has a viewport.
+                viewport: window()
+                    .document()
+                    .is_some_and(|document| document.loose_eq(node.as_ref()))
+                    .then(Viewport::current),
+                scroll: ScrollPosition::of_document(node.unchecked_ref::<Document>()),
             }),
             8 => Self::CommentNode(CommentNode {
                 id,
@@ -328,6 +388,39 @@ pub struct DocumentNode {
     pub is_shadow: bool,
     pub child_nodes: Option<Vec<u32>>,
     pub compat_mode: String,
+    pub viewport: Option<Viewport>,
+    /// The scroll position of the document's window.
+    pub scroll: Option<ScrollPosition>,
+}
+
+/// The size of the recording window when the snapshot was taken.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Viewport {
+    /// window.innerWidth
+    pub width: u32,
+    /// window.innerHeight
+    pub height: u32,
+    pub device_pixel_ratio: f64,
+    /// Pinch zoom of the visual viewport, 1 when the page isn't zoomed.
+    pub scale: f64,
+}
+
+impl Viewport {
+    pub fn current() -> Self {
+        let window = window();
+        let dimension = |value: Result<wasm_bindgen::JsValue, wasm_bindgen::JsValue>| {
+            value.ok().and_then(|v| v.as_f64()).unwrap_or_default() as u32
+        };
+        Self {
+            width: dimension(window.inner_width()),
+            height: dimension(window.inner_height()),
+            device_pixel_ratio: window.device_pixel_ratio(),
+            scale: window
+                .visual_viewport()
+                .map(|visual_viewport| visual_viewport.scale())
+                .unwrap_or(1.),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -342,6 +435,163 @@ pub struct ElementNode {
     pub is_svg: bool,
     pub need_block: bool,
     pub is_custom: bool,
+    /// Live properties of form controls, these aren't reflected in attributes once the user interacts with the control.
+    pub form_state: Option<FormState>,
+    pub scroll: Option<ScrollPosition>,
+}
+
+/// Only recorded when the document or element is scrolled away from the origin.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ScrollPosition {
+    pub top: i32,
+    pub left: i32,
+}
+
+impl ScrollPosition {
+    pub fn of_element(el: &Element) -> Option<Self> {
+        Self::non_zero(el.scroll_top(), el.scroll_left())
+    }
+    pub fn of_document(document: &Document) -> Option<Self> {
+        let view = document.default_view()?;
+        Self::non_zero(
+            view.scroll_y().unwrap_or_default() as i32,
+            view.scroll_x().unwrap_or_default() as i32,
+        )
+    }
+    fn non_zero(top: i32, left: i32) -> Option<Self> {
+        (top != 0 || left != 0).then_some(Self { top, left })
+    }
+    /// Scrolls a built element, or the window of a built document.
+    pub fn restore(&self, node: &Node) {
+        if node.node_type() == 9 {
+            if let Some(view) = node.unchecked_ref::<Document>().default_view() {
+                view.scroll_to_with_x_and_y(self.left as f64, self.top as f64);
+            }
+        } else if let Some(el) = node.dyn_ref::<Element>() {
+            el.set_scroll_top(self.top);
+            el.set_scroll_left(self.left);
+        }
+    }
+}
+
+/// Elements with this attribute have their form values masked before they leave the page.
+pub const MASK_ATTRIBUTE: &str = "data-capture-mask";
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct FormState {
+    pub value: Option<String>,
+    pub checked: Option<bool>,
+    pub selected: Option<bool>,
+    pub selected_index: Option<i32>,
+}
+
+impl FormState {
+    /// Returns None for elements that aren't inputs, textareas, selects or options.
+    pub fn new(el: &Element) -> Option<Self> {
+        let masked = is_masked(el);
+        if let Some(input) = el.dyn_ref::<HtmlInputElement>() {
+            let is_checkable = matches!(input.type_().as_str(), "checkbox" | "radio");
+            let value = if masked {
+                mask(&input.value())
+            } else {
+                input.value()
+            };
+            Some(Self {
+                value: Some(value),
+                checked: (is_checkable && !masked).then(|| input.checked()),
+                selected: None,
+                selected_index: None,
+            })
+        } else if let Some(text_area) = el.dyn_ref::<HtmlTextAreaElement>() {
+            let value = if masked {
+                mask(&text_area.value())
+            } else {
+                text_area.value()
+            };
+            Some(Self {
+                value: Some(value),
+                checked: None,
+                selected: None,
+                selected_index: None,
+            })
+        } else if let Some(select) = el.dyn_ref::<HtmlSelectElement>() {
+            Some(Self {
+                value: (!masked).then(|| select.value()),
+                checked: None,
+                selected: None,
+                selected_index: (!masked).then(|| select.selected_index()),
+            })
+        } else {
+            el.dyn_ref::<HtmlOptionElement>().map(|option| Self {
+                value: None,
+                checked: None,
+                selected: (!masked).then(|| option.selected()),
+                selected_index: None,
+            })
+        }
+    }
+    /// Sets the properties on a built element. A select's selected index can only be restored once its options exist,
+    /// so rebuild calls this again after the children are added.
+    pub fn restore(&self, el: &Element) {
+        if let Some(input) = el.dyn_ref::<HtmlInputElement>() {
+            if let Some(value) = &self.value {
+                input.set_value(value);
+            }
+            if let Some(checked) = self.checked {
+                input.set_checked(checked);
+            }
+        } else if let Some(text_area) = el.dyn_ref::<HtmlTextAreaElement>() {
+            if let Some(value) = &self.value {
+                text_area.set_value(value);
+            }
+        } else if let Some(select) = el.dyn_ref::<HtmlSelectElement>() {
+            if let Some(selected_index) = self.selected_index {
+                select.set_selected_index(selected_index);
+            }
+        } else if let Some(option) = el.dyn_ref::<HtmlOptionElement>() {
+            if let Some(selected) = self.selected {
+                option.set_selected(selected);
+            }
+        }
+    }
+}
+
+/// Keeps the length of the value so the replay looks right but none of the content.
+fn mask(value: &str) -> String {
+    "*".repeat(value.chars().count())
+}
+
+/// Password inputs, and every form control in or under an element with MASK_ATTRIBUTE, e.g. the options of a
+/// masked select.
+pub fn is_masked(el: &Element) -> bool {
+    el.dyn_ref::<HtmlInputElement>()
+        .is_some_and(|input| input.type_() == "password")
+        || el
+            .closest(&format!("[{MASK_ATTRIBUTE}]"))
+            .ok()
+            .flatten()
+            .is_some()
+}
+
+/// An attribute's value as it's recorded, None to leave it out. The attributes of a masked control that hold what
+/// the user typed or picked are masked like its form state. is_masked is only asked for those attributes.
+fn recorded_attribute(name: &str, value: String, is_masked: impl Fn() -> bool) -> Option<String> {
+    match name {
+        "value" if is_masked() => Some(mask(&value)),
+        "checked" | "selected" if is_masked() => None,
+        _ => Some(value),
+    }
+}
+
+/// A text node's text as it's recorded, the text of a masked textarea is its value.
+fn recorded_text(node: &Node) -> Option<String> {
+    let text = node.text_content();
+    match node.parent_element() {
+        Some(parent) if parent.dyn_ref::<HtmlTextAreaElement>().is_some() && is_masked(&parent) => {
+            text.map(|text| mask(&text))
+        }
+        _ => text,
+    }
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -392,15 +642,16 @@ impl MutationVariant {
             MutationVariant::Attributes(this) => this.target_id,
         }
     }
-    pub fn new(record: MutationRecord) -> Self {
+    /// None for attribute changes that aren't recorded, see recorded_attribute.
+    pub fn new(record: MutationRecord) -> Option<Self> {
         match record.type_().as_str() {
-            "attributes" => Self::Attributes(MutationAttributes::new(record)),
-            "characterData" => Self::CharacterData(MutationCharacterData::new(record)),
+            "attributes" => MutationAttributes::new(record).map(Self::Attributes),
+            "characterData" => Some(Self::CharacterData(MutationCharacterData::new(record))),
             "childList" => {
                 if record.added_nodes().unchecked_into::<Array>().length() != 0 {
-                    Self::ChildListAdded(MutationChildList::added(record))
+                    Some(Self::ChildListAdded(MutationChildList::added(record)))
                 } else if record.removed_nodes().unchecked_into::<Array>().length() != 0 {
-                    Self::ChildListRemoved(MutationChildList::removed(record))
+                    Some(Self::ChildListRemoved(MutationChildList::removed(record)))
                 } else {
                     panic!("expecting child list to always have either added or removed nodes")
                 }
@@ -417,7 +668,7 @@ fn target(record: &MutationRecord) -> (Node, u32) {
     let id = map_node_to_id(&target).expect("target to already exist in the node map");
     (target, id)
 }
-fn millis() -> f64 {
+pub(crate) fn millis() -> f64 {
     TIME_OF_LAST_MUTATION.with(|last_time| {
         let mut ts = timestamp();
         if ts <= *last_time.borrow() {
@@ -434,27 +685,32 @@ pub struct MutationAttributes {
     pub attribute: Option<(String, String)>,
 }
 impl MutationAttributes {
-    pub fn new(record: MutationRecord) -> Self {
+    /// None when the attribute is left out of the recording, replaying a change without its value would only
+    /// fail.
+    pub fn new(record: MutationRecord) -> Option<Self> {
         let (target, target_id) = target(&record);
-        let attribute = record.attribute_name().map(|name| {
-            let t = (
-                target
-                    .dyn_ref::<Element>()
-                    .expect(
-                        "Attribute mutation record to only apply to nodes that are valid Elements",
-                    )
-                    .get_attribute(&name)
-                    .expect("attribute name to have value"),
-                name,
-            );
-            // we ordered it as above (value,name) to use a reference and then consume name. now reorder it so it's (name,value)
-            (t.1, t.0)
-        });
-        Self {
+        let name = record.attribute_name()?;
+        let el = target
+            .dyn_ref::<Element>()
+            .expect("Attribute mutation record to only apply to nodes that are valid Elements");
+        let value = el
+            .get_attribute(&name)
+            .expect("attribute name to have value");
+        Self::recorded(target_id, millis(), name, value, || is_masked(el))
+    }
+    fn recorded(
+        target_id: u32,
+        millis: f64,
+        name: String,
+        value: String,
+        is_masked: impl Fn() -> bool,
+    ) -> Option<Self> {
+        let value = recorded_attribute(&name, value, is_masked)?;
+        Some(Self {
             target_id,
-            millis: millis(),
-            attribute,
-        }
+            millis,
+            attribute: Some((name, value)),
+        })
     }
 }
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -469,7 +725,7 @@ impl MutationCharacterData {
         Self {
             target_id,
             millis: millis(),
-            text_content: target.text_content(),
+            text_content: recorded_text(&target),
         }
     }
 }
@@ -531,9 +787,7 @@ impl MutationChildList {
                 .expect("nodelist to return node");
             let id = map_node_to_id(&node).expect("removed node to be in node map");
             nodes.push(id);
-            NODE_MAP.with(|node_map| node_map.borrow_mut().remove(&id));
-            SERIALIZED_NODE_MAP.with(|node_map| node_map.borrow_mut().remove(&id));
-            clean_up(&node);
+            clean_up(&node, id);
         }
         Self {
             target_id,
@@ -545,23 +799,28 @@ impl MutationChildList {
     }
 }
 
-fn clean_up(node: &Node) {
+/// Removes a removed node and its descendants from the node maps. The documents of iframes among them are
+/// forgotten too, they aren't children in the DOM.
+fn clean_up(node: &Node, id: u32) {
+    if node.dyn_ref::<HtmlIFrameElement>().is_some() {
+        crate::observer::forget_frame_documents(id);
+    }
+
+    NODE_MAP.with(|node_map| {
+        node_map.borrow_mut().remove(&id);
+    });
+
+    SERIALIZED_NODE_MAP.with(|node_map| {
+        node_map.borrow_mut().remove(&id);
+    });
+
     for child in node.child_nodes().values() {
         let node = child
             .unwrap()
             .dyn_into::<Node>()
             .expect("nodelist to return node");
         let id = map_node_to_id(&node).expect("removed node to be in node map");
-
-        NODE_MAP.with(|node_map| {
-            node_map.borrow_mut().remove(&id);
-        });
-
-        SERIALIZED_NODE_MAP.with(|node_map| {
-            node_map.borrow_mut().remove(&id);
-        });
-
-        clean_up(&node);
+        clean_up(&node, id);
     }
 }
 
@@ -692,3 +951,38 @@ const HTML_TAGS: [&str; 125] = [
     "VIDEO",
     "WBR",
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masked_checkbox_toggle_is_left_out_and_replays() {
+        let checked = |masked: bool| {
+            MutationAttributes::recorded(4, 10., "checked".to_string(), String::new(), || masked)
+        };
+        assert_eq!(checked(true), None);
+        assert_eq!(
+            checked(false).and_then(|mutation| mutation.attribute),
+            Some(("checked".to_string(), String::new()))
+        );
+        let value = MutationAttributes::recorded(
+            4,
+            10.,
+            "value".to_string(),
+            "hunter2".to_string(),
+            || true,
+        );
+        assert_eq!(
+            value.and_then(|mutation| mutation.attribute),
+            Some(("value".to_string(), "*******".to_string()))
+        );
+        // sessions recorded before masked changes were left out still hold them without a value.
+        MutationVariant::Attributes(MutationAttributes {
+            target_id: 4,
+            millis: 10.,
+            attribute: None,
+        })
+        .replay();
+    }
+}
@@ -0,0 +1,940 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::Write,
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+use gloo_timers::future::TimeoutFuture;
+use js_sys::{
+    decode_uri_component, encode_uri_component, Array, Date, Function, Reflect, Uint8Array,
+};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{Blob, BlobPropertyBag, Event, RequestInit, VisibilityState};
+
+use crate::{
+    queue::{count_dropped_upload, request_keyframe},
+    socket::{self, SocketError},
+    utils::log,
+    window,
+    wire::{payload_kind, Bincode, Codec, IngestError, PayloadKind, SEQ_HEADER},
+    SESSION_ID, UPLOADS,
+};
+
+/// Uploads that haven't reached the server yet are kept in localStorage one per key, this followed by
+/// the session id and the upload's seq. One key per upload keeps a write the size of that upload, and lets
+/// every tab of the origin keep its own.
+const UPLOAD_KEY_PREFIX: &str = "capture_rs_upload:";
+/// A tab owns the stored uploads of a session while that session's lease, this followed by the session id,
+/// holds the unix millis of the last renewal. Pages adopt stored uploads whose lease has expired.
+const LEASE_KEY_PREFIX: &str = "capture_rs_lease:";
+/// Long enough for the minute-long timer throttling of background tabs.
+const LEASE_MILLIS: f64 = 5. * 60_000.;
+const LEASE_RENEW_MILLIS: u32 = 30_000;
+/// The sessionStorage key that holds the tab's session id.
+const SESSION_KEY: &str = "capture_rs_session";
+/// The sessionStorage key that holds the sequence number of the tab's last upload.
+const SEQ_KEY: &str = "capture_rs_seq";
+
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    /// How many times an upload is sent before it's left in the buffer until the browser is back online.
+    pub max_attempts: u32,
+    pub initial_backoff_millis: u32,
+    pub max_backoff_millis: u32,
+    /// Past this the oldest mutation chunks are evicted to make room, then the oldest event chunks, then
+    /// snapshots but the latest. The latest snapshot is kept even if it doesn't fit on its own.
+    /// Evictions are counted in queue::dropped.
+    pub max_buffered_bytes: usize,
+    /// Gzip upload bodies and send them with a Content-Encoding header.
+    pub compress: bool,
+    /// How snapshots and mutation chunks are encoded, Json makes them readable in the network tab.
+    pub codec: &'static dyn Codec,
+    pub transport: Transport,
+    /// The key of the project on replay-server that uploads are stored under. Servers without projects don't need one.
+    pub api_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum Transport {
+    /// Posts every upload to its endpoint.
+    #[default]
+    Http,
+    /// Streams every upload over one WebSocket to this url, e.g. "ws://localhost:3000/api/socket".
+    /// Uploads are posted instead when the socket can't be established.
+    WebSocket(String),
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff_millis: 500,
+            max_backoff_millis: 30_000,
+            max_buffered_bytes: 4 * 1024 * 1024,
+            compress: true,
+            codec: &Bincode,
+            transport: Transport::default(),
+            api_key: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Upload {
+    pub endpoint: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// None for bodies the codecs can't read, those are evicted like mutation chunks.
+    pub kind: Option<PayloadKind>,
+    /// The session and seq the upload was numbered with, uploads adopted from another tab keep theirs.
+    pub session: String,
+    pub seq: u32,
+}
+
+impl Upload {
+    fn storage_key(&self) -> String {
+        format!("{UPLOAD_KEY_PREFIX}{}:{}", self.session, self.seq)
+    }
+}
+
+/// Uploads waiting to be sent, oldest first. Mirrored into localStorage so they survive a reload while offline.
+#[derive(Default)]
+pub struct Uploads {
+    config: UploadConfig,
+    queue: VecDeque<Upload>,
+    bytes: usize,
+    is_draining: bool,
+    is_initialized: bool,
+    /// The sequence number of the last upload, read from sessionStorage when the first one is numbered.
+    seq: Option<u32>,
+}
+
+pub fn configure_uploads(config: UploadConfig) {
+    UPLOADS.with(|uploads| uploads.borrow_mut().config = config);
+}
+
+/// The codec uploads should be encoded with.
+pub fn codec() -> &'static dyn Codec {
+    UPLOADS.with(|uploads| uploads.borrow().config.codec)
+}
+
+/// Identifies this tab's recording to the server. Kept in sessionStorage, so reloading the tab continues the same session.
+pub fn session_id() -> String {
+    SESSION_ID.with(Clone::clone)
+}
+
+pub(crate) fn new_session_id() -> String {
+    let storage = window().session_storage().ok().flatten();
+    if let Some(id) = storage
+        .as_ref()
+        .and_then(|storage| storage.get_item(SESSION_KEY).ok().flatten())
+    {
+        return id;
+    }
+    let random = || (js_sys::Math::random() * u32::MAX as f64) as u32;
+    let id = format!("{:08x}{:08x}", random(), random());
+    if let Some(storage) = storage {
+        _ = storage.set_item(SESSION_KEY, &id);
+    }
+    log(format!("capture session {id}"));
+    id
+}
+
+/// Numbers the session's uploads, so the server can tell when one went missing or arrived out of order.
+/// Counted in sessionStorage next to the session id, so numbering carries on across reloads of the tab.
+pub(crate) fn next_seq() -> u32 {
+    let storage = window().session_storage().ok().flatten();
+    let seq = UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        let last = uploads.seq.or_else(|| {
+            storage
+                .as_ref()?
+                .get_item(SEQ_KEY)
+                .ok()
+                .flatten()?
+                .parse()
+                .ok()
+        });
+        let seq = last.map_or(0, |last: u32| last.wrapping_add(1));
+        uploads.seq = Some(seq);
+        seq
+    });
+    if let Some(storage) = storage {
+        _ = storage.set_item(SEQ_KEY, &seq.to_string());
+    }
+    seq
+}
+
+/// Adds the session id and api key to endpoint's query string, where beacons and sockets can carry them too.
+pub fn session_url(endpoint: &str) -> String {
+    let separator = if endpoint.contains('?') { '&' } else { '?' };
+    let mut url = format!("{endpoint}{separator}session={}", session_id());
+    if let Some(api_key) = UPLOADS.with(|uploads| uploads.borrow().config.api_key.clone()) {
+        url += &format!("&key={}", String::from(encode_uri_component(&api_key)));
+    }
+    url
+}
+
+/// Queues body to be posted to endpoint. Uploads are sent one at a time in the order they were queued,
+/// failed uploads are retried with exponential backoff and kept in the buffer while the browser is offline.
+pub fn upload(endpoint: &str, content_type: &str, body: Vec<u8>) {
+    upload_with_headers(endpoint, content_type, body, Vec::new())
+}
+
+/// Like upload, with headers sent next to Content-Type and Content-Encoding.
+pub fn upload_with_headers(
+    endpoint: &str,
+    content_type: &str,
+    body: Vec<u8>,
+    mut headers: Vec<(String, String)>,
+) {
+    initialize();
+    let kind = payload_kind(&body, Some(content_type)).ok();
+    let seq = next_seq();
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
+    headers.push((SEQ_HEADER.to_string(), seq.to_string()));
+    let compress = UPLOADS.with(|uploads| uploads.borrow().config.compress);
+    let body = match compress.then(|| gzip(&body)).flatten() {
+        Some(compressed) => {
+            headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+            compressed
+        }
+        None => body,
+    };
+    let upload = Upload {
+        endpoint: session_url(endpoint),
+        headers,
+        body,
+        kind,
+        session: session_id(),
+        seq,
+    };
+    if make_room(&upload) {
+        store(&upload);
+        UPLOADS.with(|uploads| {
+            let mut uploads = uploads.borrow_mut();
+            uploads.bytes += upload.body.len();
+            uploads.queue.push_back(upload);
+        });
+    } else {
+        drop_upload(&upload);
+        if upload.kind != Some(PayloadKind::Events) {
+            request_keyframe();
+        }
+    }
+    drain();
+}
+
+/// Calls flush when the page is hidden or unloaded.
+/// Mobile browsers often kill hidden tabs without firing pagehide, so visibilitychange is the last reliable chance.
+pub(crate) fn on_page_hide(mut flush: impl FnMut() + 'static) {
+    let closure = Closure::wrap(Box::new(move |event: Event| {
+        let is_hidden = event.type_() == "pagehide"
+            || window()
+                .document()
+                .is_some_and(|document| document.visibility_state() == VisibilityState::Hidden);
+        if is_hidden {
+            flush();
+        }
+    }) as Box<dyn FnMut(_)>)
+    .into_js_value()
+    .unchecked_into::<Function>();
+    for event in ["pagehide", "visibilitychange"] {
+        window()
+            .add_event_listener_with_callback(event, &closure)
+            .expect("page hide listener");
+    }
+}
+
+/// Sends the last upload of a page that's going away. A beacon would overtake earlier uploads that are still
+/// waiting, and is lost while offline, so it's only used when nothing is queued. Queued uploads are persisted
+/// and sent by the next page instead. Beacons can't set headers, so headers only go with queued uploads.
+pub(crate) fn upload_on_page_hide(
+    endpoint: &str,
+    content_type: &str,
+    body: Vec<u8>,
+    headers: Vec<(String, String)>,
+) {
+    if is_idle() {
+        let url = format!("{}&seq={}", session_url(endpoint), next_seq());
+        send_beacon(&url, content_type, body);
+    } else {
+        upload_with_headers(endpoint, content_type, body, headers);
+    }
+}
+
+/// Queues body with sendBeacon, which outlives the page, as a Blob so it carries content_type. If the browser
+/// refuses the beacon fall back to a keepalive fetch, which is also allowed to finish after the page is gone.
+/// Chrome refuses beacons whose type isn't CORS-safelisted, the fetch sends those after a preflight.
+fn send_beacon(endpoint: &str, content_type: &str, body: Vec<u8>) {
+    let options = BlobPropertyBag::new();
+    options.set_type(content_type);
+    let Ok(blob) = Blob::new_with_u8_array_sequence_and_options(
+        &Array::of1(&Uint8Array::from(body.as_slice())),
+        &options,
+    ) else {
+        return;
+    };
+    let queued = window()
+        .navigator()
+        .send_beacon_with_opt_blob(endpoint, Some(&blob))
+        .unwrap_or_default();
+    if !queued {
+        let init = RequestInit::new();
+        init.set_method("POST");
+        init.set_body(&blob);
+        // web-sys doesn't expose keepalive yet.
+        _ = Reflect::set(&init, &JsValue::from_str("keepalive"), &JsValue::TRUE);
+        _ = window().fetch_with_str_and_init(endpoint, &init);
+    }
+}
+
+/// Evicts buffered uploads until upload fits under max_buffered_bytes, returns false if upload should be
+/// dropped instead. Evicting mutations of the current page leaves the recorded page behind the real one,
+/// so the recorder starts over from a keyframe.
+fn make_room(upload: &Upload) -> bool {
+    let is_snapshot = upload.kind == Some(PayloadKind::Snapshot);
+    let evicted = UPLOADS.with(|uploads| uploads.borrow_mut().make_room(upload));
+    let Some(evicted) = evicted else {
+        return false;
+    };
+    for queued in &evicted {
+        unstore(queued);
+        drop_upload(queued);
+    }
+    let loses_current_page =
+        UPLOADS.with(|uploads| uploads.borrow().loses_current_page(&evicted, upload));
+    if loses_current_page && !is_snapshot {
+        request_keyframe();
+    }
+    true
+}
+
+impl Uploads {
+    /// Evicts queued uploads until upload fits under max_buffered_bytes and returns them, see
+    /// UploadConfig::max_buffered_bytes for the order. None if upload should be dropped instead, nothing is
+    /// evicted then.
+    fn make_room(&mut self, upload: &Upload) -> Option<Vec<Upload>> {
+        let is_snapshot = upload.kind == Some(PayloadKind::Snapshot);
+        let max = self.config.max_buffered_bytes;
+        if self.bytes + upload.body.len() <= max {
+            return Some(Vec::new());
+        }
+        let latest_snapshot = self
+            .queue
+            .iter()
+            .rev()
+            .find(|queued| queued.kind == Some(PayloadKind::Snapshot))
+            .map(|queued| (queued.session.clone(), queued.seq, queued.body.len()));
+        // what's left once everything that may go is gone.
+        let kept = match &latest_snapshot {
+            Some((_, _, bytes)) if !is_snapshot => *bytes,
+            _ => 0,
+        };
+        if kept + upload.body.len() > max && !is_snapshot {
+            return None;
+        }
+        let mut evicted = Vec::new();
+        for kind in [
+            Some(PayloadKind::Mutations),
+            Some(PayloadKind::Events),
+            Some(PayloadKind::Snapshot),
+        ] {
+            let mut index = 0;
+            while index < self.queue.len() && self.bytes + upload.body.len() > max {
+                let queued = &self.queue[index];
+                let is_kept = !is_snapshot
+                    && latest_snapshot.as_ref().is_some_and(|(session, seq, _)| {
+                        queued.session == *session && queued.seq == *seq
+                    });
+                let matches = queued.kind == kind
+                    || (kind == Some(PayloadKind::Mutations) && queued.kind.is_none());
+                if matches && !is_kept {
+                    let queued = self.queue.remove(index).expect("index in queue");
+                    self.bytes -= queued.body.len();
+                    evicted.push(queued);
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        Some(evicted)
+    }
+
+    /// Whether evicted held mutations of upload's page that are still needed, those queued after the latest
+    /// snapshot of its session that's still queued describe the page as it is now.
+    fn loses_current_page(&self, evicted: &[Upload], upload: &Upload) -> bool {
+        let latest_snapshot = self
+            .queue
+            .iter()
+            .filter(|queued| {
+                queued.kind == Some(PayloadKind::Snapshot) && queued.session == upload.session
+            })
+            .map(|queued| queued.seq)
+            .max();
+        evicted.iter().any(|queued| {
+            !matches!(
+                queued.kind,
+                Some(PayloadKind::Snapshot | PayloadKind::Events)
+            ) && queued.session == upload.session
+                && latest_snapshot.is_none_or(|latest| queued.seq > latest)
+        })
+    }
+}
+
+fn drop_upload(upload: &Upload) {
+    count_dropped_upload(upload.body.len());
+    log(format!(
+        "capture upload buffer is full, dropped upload {} of session {} ({} bytes)",
+        upload.seq,
+        upload.session,
+        upload.body.len()
+    ));
+}
+
+/// True if nothing is waiting to be sent and the browser is online, so a new upload can skip the buffer.
+pub fn is_idle() -> bool {
+    initialize();
+    window().navigator().on_line() && UPLOADS.with(|uploads| uploads.borrow().queue.is_empty())
+}
+
+/// Restores uploads left by a previous page of this tab, or by a tab that's gone, and starts draining
+/// whenever the browser comes back online.
+fn initialize() {
+    let is_initialized =
+        UPLOADS.with(|uploads| std::mem::replace(&mut uploads.borrow_mut().is_initialized, true));
+    if is_initialized {
+        return;
+    }
+    let restored = restore();
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        uploads.bytes += restored
+            .iter()
+            .map(|upload| upload.body.len())
+            .sum::<usize>();
+        uploads.queue.extend(restored);
+    });
+    let closure = Closure::wrap(Box::new(move |_: Event| drain()) as Box<dyn FnMut(_)>);
+    window()
+        .add_event_listener_with_callback(
+            "online",
+            closure.into_js_value().unchecked_ref::<Function>(),
+        )
+        .expect("online listener");
+    renew_leases();
+    spawn_local(async {
+        loop {
+            TimeoutFuture::new(LEASE_RENEW_MILLIS).await;
+            renew_leases();
+        }
+    });
+    drain();
+}
+
+/// Sends queued uploads in order until the queue is empty or the front upload runs out of attempts.
+fn drain() {
+    let is_draining =
+        UPLOADS.with(|uploads| std::mem::replace(&mut uploads.borrow_mut().is_draining, true));
+    if is_draining {
+        return;
+    }
+    spawn_local(async {
+        let config = UPLOADS.with(|uploads| uploads.borrow().config.clone());
+        'queue: while let Some(upload) =
+            UPLOADS.with(|uploads| uploads.borrow().queue.front().cloned())
+        {
+            let mut backoff = Backoff::new(&config);
+            for attempt in 1..=config.max_attempts {
+                if !window().navigator().on_line() {
+                    // the online listener picks up from here.
+                    break 'queue;
+                }
+                let Some(wait) = backoff.wait(send(&upload, &config.transport).await) else {
+                    // it may have been evicted while it was sent.
+                    UPLOADS.with(|uploads| {
+                        let mut uploads = uploads.borrow_mut();
+                        let index = uploads.queue.iter().position(|queued| {
+                            queued.session == upload.session && queued.seq == upload.seq
+                        });
+                        if let Some(index) = index {
+                            uploads.queue.remove(index);
+                            uploads.bytes -= upload.body.len();
+                        }
+                    });
+                    unstore(&upload);
+                    continue 'queue;
+                };
+                if attempt < config.max_attempts {
+                    TimeoutFuture::new(wait).await;
+                }
+            }
+            // stays at the front of the queue for the next upload or online event.
+            break;
+        }
+        UPLOADS.with(|uploads| uploads.borrow_mut().is_draining = false);
+    });
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Sent,
+    /// The server refused the upload, sending it again won't help.
+    Rejected,
+    /// A network error or a server error worth retrying.
+    Failed,
+    /// The server is rate limiting, it's retried after at least the millis the server asked for.
+    Throttled(Option<u32>),
+}
+
+/// The retry schedule of one upload, doubling from initial_backoff_millis up to max_backoff_millis.
+struct Backoff {
+    millis: u32,
+    max_millis: u32,
+}
+
+impl Backoff {
+    fn new(config: &UploadConfig) -> Self {
+        Self {
+            millis: config.initial_backoff_millis,
+            max_millis: config.max_backoff_millis,
+        }
+    }
+    /// How long to wait before sending the upload again after outcome, None once it's done with.
+    fn wait(&mut self, outcome: Outcome) -> Option<u32> {
+        let wait = match outcome {
+            Outcome::Sent | Outcome::Rejected => return None,
+            Outcome::Failed => self.millis,
+            Outcome::Throttled(retry_after) => retry_after.unwrap_or_default().max(self.millis),
+        };
+        self.millis = self.millis.saturating_mul(2).min(self.max_millis);
+        Some(wait)
+    }
+}
+
+fn gzip(body: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()
+}
+
+async fn send(upload: &Upload, transport: &Transport) -> Outcome {
+    if let Transport::WebSocket(url) = transport {
+        match socket::send(url, upload).await {
+            Ok(ack) => {
+                let retry_after = (ack.retry_after_millis > 0).then_some(ack.retry_after_millis);
+                return outcome(ack.status, retry_after);
+            }
+            Err(SocketError::Lost) => return Outcome::Failed,
+            Err(SocketError::Unavailable) => {}
+        }
+    }
+    let mut request = gloo_net::http::Request::post(&upload.endpoint);
+    for (name, value) in upload.headers.iter() {
+        request = request.header(name, value);
+    }
+    let request = match request.body(upload.body.clone()) {
+        Ok(request) => request,
+        Err(_) => return Outcome::Rejected,
+    };
+    let response = match request.send().await {
+        Ok(response) if response.ok() => return Outcome::Sent,
+        Ok(response) => response,
+        Err(_) => return Outcome::Failed,
+    };
+    // Retry-After is in seconds, the body can be more precise.
+    let retry_after = response
+        .headers()
+        .get("Retry-After")
+        .and_then(|seconds| seconds.parse::<u32>().ok())
+        .map(|seconds| seconds * 1000);
+    let error = response
+        .binary()
+        .await
+        .ok()
+        .and_then(|body| IngestError::decode(&body).ok());
+    let retry_after = error
+        .as_ref()
+        .and_then(|error| error.retry_after_millis)
+        .or(retry_after);
+    let outcome = outcome(response.status(), retry_after);
+    if let (Outcome::Rejected, Some(error)) = (&outcome, error) {
+        log(format!("capture upload refused: {}", error.message));
+    }
+    outcome
+}
+
+fn outcome(status: u16, retry_after_millis: Option<u32>) -> Outcome {
+    match status {
+        200..=299 => Outcome::Sent,
+        429 => Outcome::Throttled(retry_after_millis),
+        500.. => Outcome::Failed,
+        _ => Outcome::Rejected,
+    }
+}
+
+fn storage() -> Option<web_sys::Storage> {
+    window().local_storage().ok().flatten()
+}
+
+/// Best effort, if the quota is exceeded the upload only lives in memory.
+fn store(upload: &Upload) {
+    if let (Some(storage), Some(encoded)) = (storage(), encode(upload)) {
+        _ = storage.set_item(&upload.storage_key(), &encoded);
+        _ = storage.set_item(
+            &format!("{LEASE_KEY_PREFIX}{}", upload.session),
+            &Date::now().to_string(),
+        );
+    }
+}
+
+fn unstore(upload: &Upload) {
+    if let Some(storage) = storage() {
+        _ = storage.remove_item(&upload.storage_key());
+    }
+}
+
+/// Marks the sessions of the queued uploads as owned by this tab.
+fn renew_leases() {
+    let Some(storage) = storage() else {
+        return;
+    };
+    let sessions = UPLOADS.with(|uploads| {
+        uploads
+            .borrow()
+            .queue
+            .iter()
+            .map(|upload| upload.session.clone())
+            .collect::<HashSet<_>>()
+    });
+    let now = Date::now().to_string();
+    for session in sessions {
+        _ = storage.set_item(&format!("{LEASE_KEY_PREFIX}{session}"), &now);
+    }
+}
+
+/// The stored uploads of this tab's session, and those of sessions whose lease expired, in the order
+/// they were queued. Expired leases are removed, the adopted sessions' are renewed by initialize.
+fn restore() -> Vec<Upload> {
+    let Some(storage) = storage() else {
+        return Vec::new();
+    };
+    let keys = (0..storage.length().unwrap_or_default())
+        .filter_map(|index| storage.key(index).ok().flatten())
+        .collect::<Vec<_>>();
+    let own = session_id();
+    let now = Date::now();
+    let is_expired = |session: &str| {
+        let renewed = storage
+            .get_item(&format!("{LEASE_KEY_PREFIX}{session}"))
+            .ok()
+            .flatten();
+        is_lease_expired(renewed.as_deref(), now)
+    };
+    let mut restored = keys
+        .iter()
+        .filter_map(|key| {
+            let (session, seq) = restorable(key, &own, is_expired)?;
+            let stored = storage.get_item(key).ok().flatten()?;
+            let upload = decode(&stored, session.to_string(), seq);
+            if upload.is_none() {
+                _ = storage.remove_item(key);
+            }
+            upload
+        })
+        .collect::<Vec<_>>();
+    restored.sort_by(|a, b| (&a.session, a.seq).cmp(&(&b.session, b.seq)));
+    for key in keys {
+        if let Some(session) = key.strip_prefix(LEASE_KEY_PREFIX) {
+            if session != own && is_expired(session) {
+                _ = storage.remove_item(&key);
+            }
+        }
+    }
+    restored
+}
+
+/// Whether a lease renewed at the unix millis renewed has run out by now. Leases that are missing or can't be
+/// read have.
+fn is_lease_expired(renewed: Option<&str>, now: f64) -> bool {
+    renewed
+        .and_then(|renewed| renewed.parse::<f64>().ok())
+        .is_none_or(|renewed| now - renewed > LEASE_MILLIS)
+}
+
+/// The session and seq of a stored upload's key, if a page of the session own should restore it: its own uploads,
+/// and those of sessions whose lease expired.
+fn restorable<'a>(
+    key: &'a str,
+    own: &str,
+    is_expired: impl Fn(&str) -> bool,
+) -> Option<(&'a str, u32)> {
+    let (session, seq) = key.strip_prefix(UPLOAD_KEY_PREFIX)?.rsplit_once(':')?;
+    if session != own && !is_expired(session) {
+        return None;
+    }
+    Some((session, seq.parse().ok()?))
+}
+
+/// "kind endpoint headers base64-body", kind is the PayloadKind's number or - if it isn't known. Endpoints are
+/// URLs so they can't contain spaces, headers are "name:value" pairs separated by commas with both parts URI encoded.
+fn encode(upload: &Upload) -> Option<String> {
+    // btoa takes a string with one char per byte.
+    let binary = upload
+        .body
+        .iter()
+        .map(|byte| *byte as char)
+        .collect::<String>();
+    let body = window().btoa(&binary).ok()?;
+    let headers = upload
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}:{}",
+                String::from(encode_uri_component(name)),
+                String::from(encode_uri_component(value))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let kind = upload
+        .kind
+        .map_or("-".to_string(), |kind| (kind as u8).to_string());
+    Some(format!("{kind} {} {headers} {body}", upload.endpoint))
+}
+
+fn decode(stored: &str, session: String, seq: u32) -> Option<Upload> {
+    let decode_component = |component: &str| decode_uri_component(component).ok().map(String::from);
+    let [kind, endpoint, headers, body] = stored.split(' ').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let headers = headers
+        .split(',')
+        .filter(|header| !header.is_empty())
+        .map(|header| {
+            let (name, value) = header.split_once(':')?;
+            Some((decode_component(name)?, decode_component(value)?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let body = window()
+        .atob(body)
+        .ok()?
+        .chars()
+        .map(|c| c as u8)
+        .collect::<Vec<_>>();
+    let kind = kind
+        .parse::<u8>()
+        .ok()
+        .and_then(|kind| PayloadKind::try_from(kind).ok());
+    Some(Upload {
+        endpoint: endpoint.to_string(),
+        headers,
+        body,
+        kind,
+        session,
+        seq,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(session: &str, seq: u32, kind: Option<PayloadKind>, bytes: usize) -> Upload {
+        Upload {
+            endpoint: String::new(),
+            headers: Vec::new(),
+            body: vec![0; bytes],
+            kind,
+            session: session.to_string(),
+            seq,
+        }
+    }
+
+    fn uploads(max_buffered_bytes: usize, queue: Vec<Upload>) -> Uploads {
+        Uploads {
+            config: UploadConfig {
+                max_buffered_bytes,
+                ..UploadConfig::default()
+            },
+            bytes: queue.iter().map(|upload| upload.body.len()).sum(),
+            queue: queue.into(),
+            ..Uploads::default()
+        }
+    }
+
+    fn seqs(uploads: &[Upload]) -> Vec<u32> {
+        uploads.iter().map(|upload| upload.seq).collect()
+    }
+
+    const SNAPSHOT: Option<PayloadKind> = Some(PayloadKind::Snapshot);
+    const MUTATIONS: Option<PayloadKind> = Some(PayloadKind::Mutations);
+    const EVENTS: Option<PayloadKind> = Some(PayloadKind::Events);
+
+    #[test]
+    fn evicts_mutations_then_events_then_older_snapshots() {
+        let mut uploads = uploads(
+            100,
+            vec![
+                queued("a", 0, SNAPSHOT, 20),
+                queued("a", 1, EVENTS, 10),
+                queued("a", 2, MUTATIONS, 10),
+                queued("a", 3, SNAPSHOT, 30),
+                queued("a", 4, None, 10),
+                queued("a", 5, MUTATIONS, 10),
+            ],
+        );
+        let upload = queued("a", 6, EVENTS, 15);
+        assert_eq!(seqs(&uploads.make_room(&upload).unwrap()), [2]);
+        let upload = queued("a", 6, EVENTS, 60);
+        assert_eq!(seqs(&uploads.make_room(&upload).unwrap()), [4, 5, 1, 0]);
+        assert_eq!(seqs(&Vec::from(uploads.queue.clone())), [3]);
+        assert_eq!(uploads.bytes, 30);
+    }
+
+    #[test]
+    fn fits_without_evicting() {
+        let mut uploads = uploads(100, vec![queued("a", 0, MUTATIONS, 50)]);
+        assert!(uploads
+            .make_room(&queued("a", 1, MUTATIONS, 50))
+            .unwrap()
+            .is_empty());
+        assert_eq!(uploads.queue.len(), 1);
+    }
+
+    #[test]
+    fn keeps_the_latest_snapshot_and_drops_what_cant_fit_next_to_it() {
+        let queue = vec![queued("a", 0, SNAPSHOT, 80), queued("a", 1, MUTATIONS, 10)];
+        let mut full = uploads(100, queue.clone());
+        assert!(full.make_room(&queued("a", 2, MUTATIONS, 30)).is_none());
+        // nothing is evicted for an upload that's dropped anyway.
+        assert_eq!(full.queue.len(), 2);
+        assert_eq!(full.bytes, 90);
+
+        // a newer snapshot replaces it, even if it doesn't fit on its own.
+        let mut full = uploads(100, queue);
+        let evicted = full.make_room(&queued("a", 2, SNAPSHOT, 200)).unwrap();
+        assert_eq!(seqs(&evicted), [1, 0]);
+        assert_eq!(full.bytes, 0);
+    }
+
+    #[test]
+    fn keeps_the_latest_snapshot_of_any_session() {
+        let mut uploads = uploads(
+            100,
+            vec![
+                queued("old", 0, SNAPSHOT, 40),
+                queued("new", 0, SNAPSHOT, 40),
+                queued("old", 1, MUTATIONS, 10),
+            ],
+        );
+        let evicted = uploads.make_room(&queued("new", 1, MUTATIONS, 50)).unwrap();
+        assert_eq!(seqs(&evicted), [1, 0]);
+        assert_eq!(uploads.queue[0].session, "new");
+    }
+
+    #[test]
+    fn losing_mutations_after_the_latest_snapshot_loses_the_page() {
+        let uploads = uploads(
+            100,
+            vec![queued("a", 3, SNAPSHOT, 10), queued("b", 0, SNAPSHOT, 10)],
+        );
+        let upload = queued("a", 6, MUTATIONS, 10);
+        let loses = |evicted: &[Upload]| uploads.loses_current_page(evicted, &upload);
+        assert!(loses(&[queued("a", 4, MUTATIONS, 10)]));
+        assert!(loses(&[queued("a", 5, None, 10)]));
+        assert!(!loses(&[queued("a", 2, MUTATIONS, 10)]));
+        assert!(!loses(&[queued("a", 4, EVENTS, 10)]));
+        assert!(!loses(&[queued("a", 1, SNAPSHOT, 10)]));
+        // adopted sessions aren't recorded by this page.
+        assert!(!loses(&[queued("c", 4, MUTATIONS, 10)]));
+
+        // without a queued snapshot every evicted chunk of the session was needed.
+        let upload = queued("c", 6, MUTATIONS, 10);
+        assert!(uploads.loses_current_page(&[queued("c", 0, MUTATIONS, 10)], &upload));
+    }
+
+    #[test]
+    fn leases_expire_after_lease_millis() {
+        let now = 10. * LEASE_MILLIS;
+        assert!(!is_lease_expired(Some(&now.to_string()), now));
+        assert!(!is_lease_expired(
+            Some(&(now - LEASE_MILLIS).to_string()),
+            now
+        ));
+        assert!(is_lease_expired(
+            Some(&(now - LEASE_MILLIS - 1.).to_string()),
+            now
+        ));
+        assert!(is_lease_expired(None, now));
+        assert!(is_lease_expired(Some("soon"), now));
+    }
+
+    #[test]
+    fn restores_own_and_expired_sessions() {
+        let is_expired = |session: &str| session == "gone";
+        let key = |session: &str, seq: &str| format!("{UPLOAD_KEY_PREFIX}{session}:{seq}");
+        assert_eq!(
+            restorable(&key("own", "4"), "own", is_expired),
+            Some(("own", 4))
+        );
+        assert_eq!(
+            restorable(&key("gone", "0"), "own", is_expired),
+            Some(("gone", 0))
+        );
+        assert_eq!(restorable(&key("other", "0"), "own", is_expired), None);
+        assert_eq!(restorable(&key("own", "x"), "own", is_expired), None);
+        assert_eq!(
+            restorable(&format!("{LEASE_KEY_PREFIX}own"), "own", is_expired),
+            None
+        );
+        assert_eq!(restorable("unrelated", "own", is_expired), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max() {
+        let mut backoff = Backoff::new(&UploadConfig {
+            initial_backoff_millis: 500,
+            max_backoff_millis: 3_000,
+            ..UploadConfig::default()
+        });
+        let waits = (0..5)
+            .map(|_| backoff.wait(Outcome::Failed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            waits,
+            [
+                Some(500),
+                Some(1_000),
+                Some(2_000),
+                Some(3_000),
+                Some(3_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn throttling_waits_at_least_what_the_server_asked() {
+        let mut backoff = Backoff::new(&UploadConfig::default());
+        assert_eq!(backoff.wait(Outcome::Throttled(Some(10_000))), Some(10_000));
+        assert_eq!(backoff.wait(Outcome::Throttled(Some(10))), Some(1_000));
+        assert_eq!(backoff.wait(Outcome::Throttled(None)), Some(2_000));
+        assert_eq!(backoff.wait(Outcome::Sent), None);
+        assert_eq!(backoff.wait(Outcome::Rejected), None);
+    }
+
+    #[test]
+    fn maps_statuses_to_outcomes() {
+        assert_eq!(outcome(200, None), Outcome::Sent);
+        assert_eq!(outcome(204, Some(5)), Outcome::Sent);
+        assert_eq!(outcome(429, Some(1_500)), Outcome::Throttled(Some(1_500)));
+        assert_eq!(outcome(500, None), Outcome::Failed);
+        assert_eq!(outcome(503, Some(1_000)), Outcome::Failed);
+        assert_eq!(outcome(400, None), Outcome::Rejected);
+        assert_eq!(outcome(413, None), Outcome::Rejected);
+        assert_eq!(outcome(0, None), Outcome::Rejected);
+    }
+}
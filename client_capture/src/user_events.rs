@@ -1,48 +1,111 @@
-use crate::window;
+use crate::{window, CAPTURE_EVENT_SENDER};
 use js_sys::Function;
-use tokio::sync::mpsc::UnboundedSender;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::Event;
-use web_sys::MouseEvent;
+use web_sys::{ErrorEvent, Event};
+use web_sys::{EventTarget, HtmlIFrameElement, Window};
+use web_sys::{MouseEvent, Node};
 
+use crate::queue::{send_event, EventSender};
+use crate::snapshot::map_node_to_id;
 use crate::utils::throttle;
 use crate::CaptureEvent;
 
-pub fn capture_mouse(sender: UnboundedSender<CaptureEvent>) -> Result<(), JsValue> {
+pub fn capture_mouse(sender: EventSender) -> Result<(), JsValue> {
+    CAPTURE_EVENT_SENDER.with(|event_sender| *event_sender.borrow_mut() = Some(sender.clone()));
+    add_mouse_listeners(window().as_ref(), sender, || (0, 0), || scroll(&window()))
+}
+
+/// Captures mouse events inside a same-origin iframe, with coordinates relative to the top window.
+/// Does nothing unless capture_mouse has been called.
+pub fn capture_frame_mouse(iframe: &HtmlIFrameElement) -> Result<(), JsValue> {
+    let Some(sender) = CAPTURE_EVENT_SENDER.with(|event_sender| event_sender.borrow().clone())
+    else {
+        return Ok(());
+    };
+    let frame_window = iframe
+        .content_window()
+        .ok_or_else(|| JsValue::from_str("iframe has no window"))?;
+    let iframe = iframe.clone();
+    let target = frame_window.clone();
+    add_mouse_listeners(
+        target.as_ref(),
+        sender,
+        move || {
+            let rect = iframe.get_bounding_client_rect();
+            (rect.left() as i32, rect.top() as i32)
+        },
+        // the snapshot shows the frame's document from its top, so what it was scrolled by is added too.
+        move || {
+            let (frame_left, frame_top) = scroll(&frame_window);
+            let (left, top) = scroll(&window());
+            (frame_left + left, frame_top + top)
+        },
+    )
+}
+
+/// How far win is scrolled.
+fn scroll(win: &Window) -> (i32, i32) {
+    (
+        win.scroll_x().unwrap_or_default() as i32,
+        win.scroll_y().unwrap_or_default() as i32,
+    )
+}
+
+/// offset returns the position of target's viewport in the top window, and scroll what's added to that
+/// for the position in the recorded page.
+fn add_mouse_listeners(
+    target: &EventTarget,
+    sender: EventSender,
+    offset: impl Fn() -> (i32, i32) + Clone + 'static,
+    scroll: impl Fn() -> (i32, i32) + 'static,
+) -> Result<(), JsValue> {
     let sender_c = sender.clone();
+    let offset_c = offset.clone();
     let closure = Closure::wrap(Box::new(throttle(
         move |event: MouseEvent| {
-            let x = event.client_x();
-            let y = event.client_y();
-            sender_c
-                .send(CaptureEvent::MouseMove { x, y })
-                .expect("send to always succeed");
+            let (left, top) = offset_c();
+            let x = event.client_x() + left;
+            let y = event.client_y() + top;
+            send_event(&sender_c, CaptureEvent::MouseMove { x, y });
         },
         50, // Throttle delay of 200 milliseconds
     )) as Box<dyn FnMut(_)>);
 
-    window().add_event_listener_with_callback(
+    target.add_event_listener_with_callback(
         "mousemove",
         &closure.into_js_value().dyn_into::<Function>()?,
     )?;
 
     let closure = Closure::wrap(Box::new(move |event: MouseEvent| {
-        let x = event.client_x();
-        let y = event.client_y();
-        sender
-            .send(CaptureEvent::MouseClick { x, y })
-            .expect("send to always succeed");
+        let (left, top) = offset();
+        let x = event.client_x() + left;
+        let y = event.client_y() + top;
+        let (scroll_x, scroll_y) = scroll();
+        let target = event
+            .target()
+            .and_then(|target| target.dyn_into::<Node>().ok())
+            .and_then(|node| map_node_to_id(&node));
+        send_event(
+            &sender,
+            CaptureEvent::Click {
+                x,
+                y,
+                page_x: x + scroll_x,
+                page_y: y + scroll_y,
+                target,
+            },
+        );
     }) as Box<dyn FnMut(_)>);
 
-    window().add_event_listener_with_callback(
-        "mouseclick",
+    target.add_event_listener_with_callback(
+        "click",
         &closure.into_js_value().dyn_into::<Function>()?,
     )?;
     Ok(())
 }
 
-pub fn capture_window(sender: UnboundedSender<CaptureEvent>) -> Result<(), JsValue> {
+pub fn capture_window(sender: EventSender) -> Result<(), JsValue> {
     // compress these to first and last in the event stream.
     let closure = Closure::wrap(Box::new(move |_: Event| {
         if let (Some(width), Some(height)) = (
@@ -57,9 +120,7 @@ pub fn capture_window(sender: UnboundedSender<CaptureEvent>) -> Result<(), JsVal
                 .and_then(|h| h.as_f64())
                 .map(|h| h as u32),
         ) {
-            sender
-                .send(CaptureEvent::WindowResize { height, width })
-                .expect("send to always succeed");
+            send_event(&sender, CaptureEvent::WindowResize { height, width });
         }
     }) as Box<dyn FnMut(_)>);
 
@@ -69,3 +130,20 @@ pub fn capture_window(sender: UnboundedSender<CaptureEvent>) -> Result<(), JsVal
     )?;
     Ok(())
 }
+
+/// Records uncaught errors and unhandled promise rejections, so sessions with errors can be found.
+pub fn capture_errors(sender: EventSender) -> Result<(), JsValue> {
+    let closure = Closure::wrap(Box::new(move |event: Event| {
+        let (line, column) = event
+            .dyn_ref::<ErrorEvent>()
+            .map(|event| (event.lineno(), event.colno()))
+            .unwrap_or_default();
+        send_event(&sender, CaptureEvent::Error { line, column });
+    }) as Box<dyn FnMut(_)>)
+    .into_js_value()
+    .dyn_into::<Function>()?;
+    for event in ["error", "unhandledrejection"] {
+        window().add_event_listener_with_callback(event, &closure)?;
+    }
+    Ok(())
+}
@@ -49,15 +49,15 @@ pub fn dom_changes() {
                     }
                 }
                 let attribute_name = record.attribute_name();
-                log(&attribute_name.unwrap_or_default());
+                log(attribute_name.unwrap_or_default());
                 let attribute_namespace = record.attribute_namespace();
-                log(&attribute_namespace.unwrap_or_default());
+                log(attribute_namespace.unwrap_or_default());
                 if let Some(next_sibling) = record.next_sibling() {
                     log_node_info(&next_sibling).unwrap();
                 }
 
                 let old_value = record.old_value();
-                log(&old_value.unwrap_or_default());
+                log(old_value.unwrap_or_default());
                 if let Some(previous_sibling) = record.previous_sibling() {
                     log_node_info(&previous_sibling).unwrap();
                 }
@@ -103,25 +103,25 @@ fn log_node_info(node: &Node) -> Result<(), JsValue> {
             .collect::<Vec<_>>();
         for attribute in attribute_names {
             let attribute_value = el.get_attribute(&attribute).unwrap();
-            log(&format!("{attribute}:{attribute_value}"));
+            log(format!("{attribute}:{attribute_value}"));
         }
     }
 
     // Node name (e.g., DIV, P, etc.)
-    log(&format!("Node Name: {:?}", node.node_name()));
+    log(format!("Node Name: {:?}", node.node_name()));
 
     // Text content (only available if the node has text content)
-    log(&format!("Text Content: {:?}", node.text_content()));
+    log(format!("Text Content: {:?}", node.text_content()));
 
     // Child nodes count
-    log(&format!(
+    log(format!(
         "Child Nodes Count: {:?}",
         node.child_nodes().length()
     ));
 
     // Parent node (if any)
     if let Some(parent) = node.parent_node() {
-        log(&format!("Parent Node Name: {:?}", parent.node_name()));
+        log(format!("Parent Node Name: {:?}", parent.node_name()));
     }
 
     Ok(())
@@ -0,0 +1,597 @@
+//! Checks a stored recording for what would break its replay: mutations of nodes that don't exist, ids used twice,
+//! nodes cut off from the document, uploads that went missing or arrived out of order, and time going backwards.
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    vdom::{VirtualDom, VirtualDomError},
+    CaptureEvent, MutationVariant, SerializedNode,
+};
+
+/// What validate needs of a stored session.
+pub struct Recording<'a> {
+    pub keyframes: Vec<KeyframeRef<'a>>,
+    pub mutations: &'a [MutationVariant],
+    pub events: &'a [(f64, CaptureEvent)],
+    /// The sequence number of every upload that had one, in the order they arrived.
+    pub seqs: &'a [u32],
+}
+
+pub struct KeyframeRef<'a> {
+    pub snapshot: &'a HashMap<u32, SerializedNode>,
+    /// The unix millis the page's recorded millis count from.
+    pub time_origin: f64,
+    /// How many of the session's mutations and events arrived before this keyframe.
+    pub mutation_index: usize,
+    pub event_index: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+    /// The mutations that can't be applied, validate leaves them out and goes on with the next, replays should too.
+    pub fn unappliable_mutations(&self) -> HashSet<usize> {
+        self.issues
+            .iter()
+            .filter_map(|issue| match issue {
+                Issue::UnknownTarget { mutation, .. } | Issue::InvalidMutation { mutation, .. } => {
+                    Some(*mutation)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Keyframes, mutations and events are referred to by their index in the session.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// A snapshot stores a node under a different id than its own.
+    MismatchedId {
+        keyframe: usize,
+        key: u32,
+        id: u32,
+    },
+    /// A node is the child of more than one parent, or is added while it's already in the page.
+    DuplicateId {
+        keyframe: usize,
+        mutation: Option<usize>,
+        id: u32,
+    },
+    /// A node lists a child that isn't in the page.
+    MissingNode {
+        keyframe: usize,
+        mutation: Option<usize>,
+        parent: u32,
+        id: u32,
+    },
+    /// A node that can't be reached from the document, at the keyframe or once its mutations are applied.
+    OrphanedNode {
+        keyframe: usize,
+        mutation: Option<usize>,
+        id: u32,
+    },
+    /// A mutation of a node that isn't in the page.
+    UnknownTarget {
+        mutation: usize,
+        target: u32,
+    },
+    /// A mutation that can't be applied for another reason.
+    InvalidMutation {
+        mutation: usize,
+        error: String,
+    },
+    /// Mutations and events that arrived before any keyframe, there's no page to apply them to.
+    BeforeFirstKeyframe {
+        mutations: usize,
+        events: usize,
+    },
+    /// Uploads that never arrived, from to to inclusive.
+    MissingChunks {
+        from: u32,
+        to: u32,
+    },
+    /// An upload that arrived after a later one.
+    OutOfOrderChunk {
+        seq: u32,
+        after: u32,
+    },
+    DuplicateChunk {
+        seq: u32,
+    },
+    /// Something recorded earlier than what was recorded before it on the same page.
+    NonMonotonicTime {
+        item: TimedItem,
+        index: usize,
+        millis: f64,
+        previous: f64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimedItem {
+    Keyframe,
+    Mutation,
+    Event,
+}
+
+pub fn validate(recording: &Recording) -> ValidationReport {
+    let mut issues = Vec::new();
+    if let Some(first) = recording.keyframes.first() {
+        if first.mutation_index > 0 || first.event_index > 0 {
+            issues.push(Issue::BeforeFirstKeyframe {
+                mutations: first.mutation_index,
+                events: first.event_index,
+            });
+        }
+    } else if !recording.mutations.is_empty() || !recording.events.is_empty() {
+        issues.push(Issue::BeforeFirstKeyframe {
+            mutations: recording.mutations.len(),
+            events: recording.events.len(),
+        });
+    }
+    for (index, keyframe) in recording.keyframes.iter().enumerate() {
+        let next = recording.keyframes.get(index + 1);
+        let mutations = keyframe.mutation_index
+            ..next.map_or(recording.mutations.len(), |next| next.mutation_index);
+        let events =
+            keyframe.event_index..next.map_or(recording.events.len(), |next| next.event_index);
+        validate_page(
+            index,
+            keyframe,
+            recording.mutations,
+            mutations.clone(),
+            &mut issues,
+        );
+        monotonic(
+            TimedItem::Mutation,
+            mutations.map(|index| (index, recording.mutations[index].millis())),
+            &mut issues,
+        );
+        monotonic(
+            TimedItem::Event,
+            events.map(|index| (index, recording.events[index].0)),
+            &mut issues,
+        );
+    }
+    monotonic(
+        TimedItem::Keyframe,
+        recording
+            .keyframes
+            .iter()
+            .map(|keyframe| keyframe.time_origin)
+            .enumerate(),
+        &mut issues,
+    );
+    validate_seqs(recording.seqs, &mut issues);
+    ValidationReport { issues }
+}
+
+/// Checks the keyframe's snapshot, then applies the mutations recorded on its page one at a time.
+fn validate_page(
+    keyframe_index: usize,
+    keyframe: &KeyframeRef,
+    mutations: &[MutationVariant],
+    range: std::ops::Range<usize>,
+    issues: &mut Vec<Issue>,
+) {
+    for (key, node) in keyframe.snapshot {
+        if *key != node.id() {
+            issues.push(Issue::MismatchedId {
+                keyframe: keyframe_index,
+                key: *key,
+                id: node.id(),
+            });
+        }
+    }
+    let mut dom = VirtualDom::new(keyframe.snapshot.clone());
+    validate_tree(keyframe_index, None, &dom, issues);
+    let Some(last) = range.end.checked_sub(1).filter(|last| *last >= range.start) else {
+        return;
+    };
+    for index in range {
+        let mutation = &mutations[index];
+        if let MutationVariant::ChildListAdded((list, added)) = mutation {
+            // nodes that are moved are already in the page, along with everything below them.
+            let moved = list
+                .nodes
+                .iter()
+                .filter(|id| dom.get(**id).is_some())
+                .flat_map(|id| subtree(&dom, *id))
+                .collect::<HashSet<_>>();
+            let mut duplicates = added
+                .keys()
+                .filter(|id| dom.get(**id).is_some() && !moved.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            duplicates.sort();
+            issues.extend(duplicates.into_iter().map(|id| Issue::DuplicateId {
+                keyframe: keyframe_index,
+                mutation: Some(index),
+                id,
+            }));
+        }
+        match dom.apply(mutation) {
+            Ok(()) => {}
+            Err(VirtualDomError::UnknownTarget(target)) => issues.push(Issue::UnknownTarget {
+                mutation: index,
+                target,
+            }),
+            Err(err) => issues.push(Issue::InvalidMutation {
+                mutation: index,
+                error: err.to_string(),
+            }),
+        }
+    }
+    validate_tree(keyframe_index, Some(last), &dom, issues);
+}
+
+/// Walks the page from the document, reporting children that aren't there, children with two parents,
+/// and nodes the walk never reaches.
+fn validate_tree(
+    keyframe: usize,
+    mutation: Option<usize>,
+    dom: &VirtualDom,
+    issues: &mut Vec<Issue>,
+) {
+    let mut reached = HashSet::new();
+    if dom.root().is_some() {
+        reached.insert(0);
+    }
+    let mut stack = reached.iter().copied().collect::<Vec<_>>();
+    while let Some(id) = stack.pop() {
+        for child in dom.children(id) {
+            if dom.get(child).is_none() {
+                issues.push(Issue::MissingNode {
+                    keyframe,
+                    mutation,
+                    parent: id,
+                    id: child,
+                });
+            } else if !reached.insert(child) {
+                issues.push(Issue::DuplicateId {
+                    keyframe,
+                    mutation,
+                    id: child,
+                });
+            } else {
+                stack.push(child);
+            }
+        }
+    }
+    let mut orphans = dom
+        .nodes()
+        .keys()
+        .filter(|id| !reached.contains(id))
+        .copied()
+        .collect::<Vec<_>>();
+    orphans.sort();
+    issues.extend(orphans.into_iter().map(|id| Issue::OrphanedNode {
+        keyframe,
+        mutation,
+        id,
+    }));
+}
+
+/// id and every node below it.
+fn subtree(dom: &VirtualDom, id: u32) -> Vec<u32> {
+    let mut nodes = Vec::new();
+    let mut stack = vec![id];
+    while let Some(id) = stack.pop() {
+        if nodes.contains(&id) {
+            continue;
+        }
+        nodes.push(id);
+        stack.extend(dom.children(id));
+    }
+    nodes
+}
+
+/// Items should be recorded no earlier than the item before them.
+fn monotonic(item: TimedItem, times: impl Iterator<Item = (usize, f64)>, issues: &mut Vec<Issue>) {
+    let mut previous = None;
+    for (index, millis) in times {
+        if let Some(previous) = previous.filter(|previous| millis < *previous) {
+            issues.push(Issue::NonMonotonicTime {
+                item,
+                index,
+                millis,
+                previous,
+            });
+        }
+        previous = Some(millis);
+    }
+}
+
+/// Uploads are numbered from 0 in the order they were queued, and sent in that order.
+fn validate_seqs(seqs: &[u32], issues: &mut Vec<Issue>) {
+    let mut seen = HashSet::new();
+    let mut latest = None;
+    for seq in seqs {
+        if !seen.insert(*seq) {
+            issues.push(Issue::DuplicateChunk { seq: *seq });
+            continue;
+        }
+        match latest {
+            Some(after) if *seq < after => issues.push(Issue::OutOfOrderChunk { seq: *seq, after }),
+            _ => latest = Some(*seq),
+        }
+    }
+    let mut sorted = seen.into_iter().collect::<Vec<_>>();
+    sorted.sort();
+    let mut expected = 0;
+    for seq in sorted {
+        if seq > expected {
+            issues.push(Issue::MissingChunks {
+                from: expected,
+                to: seq - 1,
+            });
+        }
+        expected = seq.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DocumentNode, ElementNode, MutationAttributes, MutationCharacterData, MutationChildList,
+    };
+
+    fn document(children: &[u32]) -> SerializedNode {
+        SerializedNode::DocumentNode(DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(children.iter().rev().copied().collect()),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: None,
+            scroll: None,
+        })
+    }
+
+    fn element(id: u32, children: &[u32]) -> SerializedNode {
+        SerializedNode::ElementNode(ElementNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: "div".to_string(),
+            attributes: Some(Vec::new()),
+            child_nodes: Some(children.iter().rev().copied().collect()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        })
+    }
+
+    fn snapshot(nodes: impl IntoIterator<Item = SerializedNode>) -> HashMap<u32, SerializedNode> {
+        nodes.into_iter().map(|node| (node.id(), node)).collect()
+    }
+
+    /// A document holding 1, which holds 2.
+    fn page() -> HashMap<u32, SerializedNode> {
+        snapshot([document(&[1]), element(1, &[2]), element(2, &[])])
+    }
+
+    fn attribute(target_id: u32, millis: f64) -> MutationVariant {
+        MutationVariant::Attributes(MutationAttributes {
+            target_id,
+            millis,
+            attribute: Some(("class".to_string(), "open".to_string())),
+        })
+    }
+
+    fn add(target_id: u32, millis: f64, node: SerializedNode) -> MutationVariant {
+        MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id,
+                millis,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![node.id()],
+            },
+            snapshot([node]),
+        ))
+    }
+
+    fn keyframe(snapshot: &HashMap<u32, SerializedNode>, mutation_index: usize) -> KeyframeRef<'_> {
+        KeyframeRef {
+            snapshot,
+            time_origin: 1_000.,
+            mutation_index,
+            event_index: 0,
+        }
+    }
+
+    fn issues(
+        keyframes: Vec<KeyframeRef>,
+        mutations: &[MutationVariant],
+        seqs: &[u32],
+    ) -> Vec<Issue> {
+        validate(&Recording {
+            keyframes,
+            mutations,
+            events: &[],
+            seqs,
+        })
+        .issues
+    }
+
+    #[test]
+    fn passes_a_recording_that_replays() {
+        let page = page();
+        let mutations = [
+            attribute(2, 1.),
+            add(1, 2., element(3, &[])),
+            attribute(3, 3.),
+        ];
+        let report = validate(&Recording {
+            keyframes: vec![keyframe(&page, 0)],
+            mutations: &mutations,
+            events: &[],
+            seqs: &[0, 1, 2],
+        });
+        assert!(report.is_valid(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn reports_broken_snapshots() {
+        let mut page = snapshot([document(&[1, 4]), element(1, &[2]), element(2, &[1])]);
+        page.insert(7, element(8, &[]));
+        assert_eq!(
+            issues(vec![keyframe(&page, 0)], &[], &[]),
+            [
+                Issue::MismatchedId {
+                    keyframe: 0,
+                    key: 7,
+                    id: 8
+                },
+                Issue::MissingNode {
+                    keyframe: 0,
+                    mutation: None,
+                    parent: 0,
+                    id: 4
+                },
+                Issue::DuplicateId {
+                    keyframe: 0,
+                    mutation: None,
+                    id: 1
+                },
+                Issue::OrphanedNode {
+                    keyframe: 0,
+                    mutation: None,
+                    id: 7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_mutations_that_cant_be_applied() {
+        let page = page();
+        let mutations = [
+            attribute(9, 1.),
+            MutationVariant::CharacterData(MutationCharacterData {
+                target_id: 1,
+                millis: 2.,
+                text_content: None,
+            }),
+            // 1 is already on the page and isn't moved, it's added again along with 3.
+            MutationVariant::ChildListAdded((
+                MutationChildList {
+                    target_id: 2,
+                    millis: 3.,
+                    prev_sibling: None,
+                    next_sibling: None,
+                    nodes: vec![3],
+                },
+                snapshot([element(3, &[]), element(1, &[2])]),
+            )),
+        ];
+        let report = validate(&Recording {
+            keyframes: vec![keyframe(&page, 0)],
+            mutations: &mutations,
+            events: &[],
+            seqs: &[],
+        });
+        assert_eq!(
+            report.issues,
+            [
+                Issue::UnknownTarget {
+                    mutation: 0,
+                    target: 9
+                },
+                Issue::InvalidMutation {
+                    mutation: 1,
+                    error: VirtualDomError::NotCharacterData(1).to_string()
+                },
+                Issue::DuplicateId {
+                    keyframe: 0,
+                    mutation: Some(2),
+                    id: 1
+                },
+            ]
+        );
+        assert_eq!(report.unappliable_mutations(), HashSet::from([0, 1]));
+    }
+
+    #[test]
+    fn checks_each_page_against_its_own_keyframe() {
+        let first = page();
+        let second = snapshot([document(&[5]), element(5, &[])]);
+        // 2 is only on the first page, 5 only on the second.
+        let mutations = [attribute(2, 1.), attribute(2, 1.), attribute(5, 2.)];
+        assert_eq!(
+            issues(
+                vec![keyframe(&first, 0), keyframe(&second, 1)],
+                &mutations,
+                &[]
+            ),
+            [Issue::UnknownTarget {
+                mutation: 1,
+                target: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_what_came_before_the_first_keyframe() {
+        let page = page();
+        let mutations = [attribute(1, 1.), attribute(1, 2.)];
+        assert_eq!(
+            issues(vec![keyframe(&page, 1)], &mutations, &[]),
+            [Issue::BeforeFirstKeyframe {
+                mutations: 1,
+                events: 0
+            }]
+        );
+        assert_eq!(
+            issues(Vec::new(), &mutations, &[]),
+            [Issue::BeforeFirstKeyframe {
+                mutations: 2,
+                events: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_time_going_backwards() {
+        let page = page();
+        let mutations = [attribute(1, 5.), attribute(1, 4.), attribute(1, 4.)];
+        assert_eq!(
+            issues(vec![keyframe(&page, 0)], &mutations, &[]),
+            [Issue::NonMonotonicTime {
+                item: TimedItem::Mutation,
+                index: 1,
+                millis: 4.,
+                previous: 5.
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_missing_reordered_and_repeated_uploads() {
+        assert_eq!(
+            issues(Vec::new(), &[], &[1, 0, 4, 4, 7]),
+            [
+                Issue::OutOfOrderChunk { seq: 0, after: 1 },
+                Issue::DuplicateChunk { seq: 4 },
+                Issue::MissingChunks { from: 2, to: 3 },
+                Issue::MissingChunks { from: 5, to: 6 },
+            ]
+        );
+    }
+}
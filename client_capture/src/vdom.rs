@@ -0,0 +1,485 @@
+//! Applies mutations to a snapshot without a browser, so recordings can be looked at on the server.
+//! MutationVariant::replay does the same to a live document in the player.
+use std::collections::HashMap;
+
+use crate::{MutationChildList, MutationVariant, SerializedNode};
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum VirtualDomError {
+    #[error("mutation targets node {0} which isn't in the dom")]
+    UnknownTarget(u32),
+    #[error("node {0} was removed from {1} but isn't one of its children")]
+    NotAChild(u32, u32),
+    #[error("added node {0} isn't among the mutation's serialized nodes")]
+    MissingAddedNode(u32),
+    #[error("sibling {0} of the added nodes isn't a child of {1}")]
+    UnknownSibling(u32, u32),
+    #[error("node {0} can't have children")]
+    NotAParent(u32),
+    #[error("node {0} isn't an element, it can't have attributes")]
+    NotAnElement(u32),
+    #[error("node {0} has no character data")]
+    NotCharacterData(u32),
+}
+
+/// The serialized nodes of a page, a snapshot with the mutations recorded since applied to it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VirtualDom {
+    nodes: HashMap<u32, SerializedNode>,
+    /// The parent of every node that has one.
+    parents: HashMap<u32, u32>,
+}
+
+impl VirtualDom {
+    pub fn new(snapshot: HashMap<u32, SerializedNode>) -> Self {
+        let mut parents = HashMap::new();
+        for (id, node) in &snapshot {
+            for child in node.child_nodes().unwrap_or_default() {
+                parents.insert(child, *id);
+            }
+        }
+        Self {
+            nodes: snapshot,
+            parents,
+        }
+    }
+    /// The page millis into the recording: the snapshot with every mutation recorded up to millis applied.
+    /// Mutations that can't be applied are skipped, and returned with why.
+    pub fn at(
+        snapshot: HashMap<u32, SerializedNode>,
+        mutations: &[MutationVariant],
+        millis: f64,
+    ) -> (Self, Vec<(usize, VirtualDomError)>) {
+        let mut dom = Self::new(snapshot);
+        let errors = dom.apply_until(mutations, millis);
+        (dom, errors)
+    }
+    /// Applies the mutations recorded up to and including millis in the order they were recorded,
+    /// returning the index of each one that couldn't be applied with why.
+    pub fn apply_until(
+        &mut self,
+        mutations: &[MutationVariant],
+        millis: f64,
+    ) -> Vec<(usize, VirtualDomError)> {
+        let mut order = (0..mutations.len())
+            .filter(|index| mutations[*index].millis() <= millis)
+            .collect::<Vec<_>>();
+        order.sort_by(|a, b| mutations[*a].millis().total_cmp(&mutations[*b].millis()));
+        order
+            .into_iter()
+            .filter_map(|index| self.apply(&mutations[index]).err().map(|err| (index, err)))
+            .collect()
+    }
+    /// Applies a mutation, leaving the dom as it was if it can't be.
+    pub fn apply(&mut self, mutation: &MutationVariant) -> Result<(), VirtualDomError> {
+        let target_id = mutation.target_id();
+        if !self.nodes.contains_key(&target_id) {
+            return Err(VirtualDomError::UnknownTarget(target_id));
+        }
+        match mutation {
+            MutationVariant::ChildListAdded((mutation, added)) => self.add(mutation, added),
+            MutationVariant::ChildListRemoved(mutation) => self.remove(mutation),
+            MutationVariant::CharacterData(mutation) => match self.nodes.get_mut(&target_id) {
+                Some(
+                    target @ (SerializedNode::TextNode(_)
+                    | SerializedNode::CommentNode(_)
+                    | SerializedNode::CDataNode(_)),
+                ) => {
+                    target.set_text_content(mutation.text_content.clone());
+                    Ok(())
+                }
+                _ => Err(VirtualDomError::NotCharacterData(target_id)),
+            },
+            MutationVariant::Attributes(mutation) => match self.nodes.get_mut(&target_id) {
+                Some(target @ SerializedNode::ElementNode(_)) => {
+                    if let Some((name, value)) = mutation.attribute.clone() {
+                        target.set_attribute(name, value);
+                    }
+                    Ok(())
+                }
+                _ => Err(VirtualDomError::NotAnElement(target_id)),
+            },
+        }
+    }
+    fn add(
+        &mut self,
+        mutation: &MutationChildList,
+        added: &HashMap<u32, SerializedNode>,
+    ) -> Result<(), VirtualDomError> {
+        let target_id = mutation.target_id;
+        if let Some(id) = mutation.nodes.iter().find(|id| !added.contains_key(id)) {
+            return Err(VirtualDomError::MissingAddedNode(*id));
+        }
+        // children in document order, they're stored last child first.
+        let mut children = self
+            .nodes
+            .get(&target_id)
+            .and_then(SerializedNode::child_nodes)
+            .ok_or(VirtualDomError::NotAParent(target_id))?;
+        children.reverse();
+        children.retain(|child| !mutation.nodes.contains(child));
+        let position = |sibling: u32| {
+            children
+                .iter()
+                .position(|child| *child == sibling)
+                .ok_or(VirtualDomError::UnknownSibling(sibling, target_id))
+        };
+        let index = match (mutation.prev_sibling, mutation.next_sibling) {
+            (Some(prev_sibling), _) => position(prev_sibling)? + 1,
+            (None, Some(next_sibling)) => position(next_sibling)?,
+            (None, None) => children.len(),
+        };
+        // an added node that's already in the dom was moved, it leaves where it was first.
+        for id in &mutation.nodes {
+            self.detach(*id);
+        }
+        children.splice(index..index, mutation.nodes.iter().copied());
+        children.reverse();
+        for (id, node) in added {
+            if let Some(previous) = self.nodes.insert(*id, node.clone()) {
+                self.unlink_children(&previous);
+            }
+            for child in node.child_nodes().unwrap_or_default() {
+                self.parents.insert(child, *id);
+            }
+        }
+        for id in &mutation.nodes {
+            self.parents.insert(*id, target_id);
+        }
+        self.set_children(target_id, children);
+        Ok(())
+    }
+    fn remove(&mut self, mutation: &MutationChildList) -> Result<(), VirtualDomError> {
+        let target_id = mutation.target_id;
+        let mut children = self
+            .nodes
+            .get(&target_id)
+            .and_then(SerializedNode::child_nodes)
+            .ok_or(VirtualDomError::NotAParent(target_id))?;
+        if let Some(id) = mutation
+            .nodes
+            .iter()
+            .find(|id| !children.contains(id) || !self.nodes.contains_key(id))
+        {
+            return Err(VirtualDomError::NotAChild(*id, target_id));
+        }
+        children.retain(|child| !mutation.nodes.contains(child));
+        self.set_children(target_id, children);
+        for id in &mutation.nodes {
+            self.parents.remove(id);
+            self.drop_tree(*id);
+        }
+        Ok(())
+    }
+    /// Takes id out of its parent's children.
+    fn detach(&mut self, id: u32) {
+        if let Some(parent) = self.parents.remove(&id) {
+            if let Some(mut children) = self.nodes.get(&parent).and_then(|n| n.child_nodes()) {
+                children.retain(|child| *child != id);
+                self.set_children(parent, children);
+            }
+        }
+    }
+    /// Forgets the parent of children of a node that's been replaced, those not adopted again are gone.
+    fn unlink_children(&mut self, node: &SerializedNode) {
+        for child in node.child_nodes().unwrap_or_default() {
+            if self.parents.get(&child) == Some(&node.id()) {
+                self.parents.remove(&child);
+            }
+        }
+    }
+    /// Removes id and everything below it.
+    fn drop_tree(&mut self, id: u32) {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.nodes.remove(&id) {
+                for child in node.child_nodes().unwrap_or_default() {
+                    self.parents.remove(&child);
+                    stack.push(child);
+                }
+            }
+        }
+    }
+    fn set_children(&mut self, id: u32, children: Vec<u32>) {
+        match self.nodes.get_mut(&id) {
+            Some(SerializedNode::DocumentNode(this)) => this.child_nodes = Some(children),
+            Some(SerializedNode::ElementNode(this)) => this.child_nodes = Some(children),
+            _ => {}
+        }
+    }
+    pub fn get(&self, id: u32) -> Option<&SerializedNode> {
+        self.nodes.get(&id)
+    }
+    pub fn parent(&self, id: u32) -> Option<u32> {
+        self.parents.get(&id).copied()
+    }
+    /// Child ids in document order, first child first.
+    pub fn children(&self, id: u32) -> Vec<u32> {
+        let mut children = self
+            .nodes
+            .get(&id)
+            .and_then(SerializedNode::child_nodes)
+            .unwrap_or_default();
+        children.reverse();
+        children
+    }
+    /// The document the snapshot was taken of, rebuild expects it at 0.
+    pub fn root(&self) -> Option<&SerializedNode> {
+        self.nodes.get(&0)
+    }
+    pub fn nodes(&self) -> &HashMap<u32, SerializedNode> {
+        &self.nodes
+    }
+    /// The nodes as a snapshot, which the player can rebuild.
+    pub fn into_nodes(self) -> HashMap<u32, SerializedNode> {
+        self.nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DocumentNode, ElementNode, MutationAttributes, MutationCharacterData, TextNode};
+
+    /// children in document order.
+    fn element(id: u32, children: &[u32]) -> SerializedNode {
+        SerializedNode::ElementNode(ElementNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: "div".to_string(),
+            attributes: Some(Vec::new()),
+            child_nodes: Some(children.iter().rev().copied().collect()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        })
+    }
+
+    fn text(id: u32, text_content: &str) -> SerializedNode {
+        SerializedNode::TextNode(TextNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            text_content: Some(text_content.to_string()),
+        })
+    }
+
+    /// A document with two divs, 1 holding the text 3 and 2 holding 4, which holds the text 5.
+    fn page() -> VirtualDom {
+        let document = SerializedNode::DocumentNode(DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(vec![2, 1]),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: None,
+            scroll: None,
+        });
+        VirtualDom::new(HashMap::from([
+            (0, document),
+            (1, element(1, &[3])),
+            (2, element(2, &[4])),
+            (3, text(3, "one")),
+            (4, element(4, &[5])),
+            (5, text(5, "two")),
+        ]))
+    }
+
+    fn child_list(
+        target_id: u32,
+        prev_sibling: Option<u32>,
+        next_sibling: Option<u32>,
+        nodes: &[u32],
+    ) -> MutationChildList {
+        MutationChildList {
+            target_id,
+            millis: 0.,
+            prev_sibling,
+            next_sibling,
+            nodes: nodes.to_vec(),
+        }
+    }
+
+    fn added(
+        list: MutationChildList,
+        nodes: impl IntoIterator<Item = SerializedNode>,
+    ) -> MutationVariant {
+        let nodes = nodes.into_iter().map(|node| (node.id(), node)).collect();
+        MutationVariant::ChildListAdded((list, nodes))
+    }
+
+    #[test]
+    fn adds_after_the_previous_sibling() {
+        let mut dom = page();
+        dom.apply(&added(child_list(0, Some(1), None, &[6]), [text(6, "new")]))
+            .unwrap();
+        assert_eq!(dom.children(0), [1, 6, 2]);
+        assert_eq!(dom.parent(6), Some(0));
+    }
+
+    #[test]
+    fn adds_before_the_next_sibling() {
+        let mut dom = page();
+        dom.apply(&added(
+            child_list(0, None, Some(1), &[6, 7]),
+            [text(6, "a"), text(7, "b")],
+        ))
+        .unwrap();
+        assert_eq!(dom.children(0), [6, 7, 1, 2]);
+    }
+
+    #[test]
+    fn adds_at_the_end_without_siblings() {
+        let mut dom = page();
+        dom.apply(&added(
+            child_list(1, None, None, &[6]),
+            [element(6, &[7]), text(7, "in")],
+        ))
+        .unwrap();
+        assert_eq!(dom.children(1), [3, 6]);
+        assert_eq!(dom.parent(7), Some(6));
+    }
+
+    #[test]
+    fn moves_nodes_between_parents() {
+        let mut dom = page();
+        // the recorder serializes a moved node and what's below it again.
+        dom.apply(&added(
+            child_list(1, Some(3), None, &[4]),
+            [element(4, &[5]), text(5, "two")],
+        ))
+        .unwrap();
+        assert_eq!(dom.children(1), [3, 4]);
+        assert_eq!(dom.children(2), Vec::<u32>::new());
+        assert_eq!(dom.parent(4), Some(1));
+        assert_eq!(dom.parent(5), Some(4));
+        // within the same parent.
+        dom.apply(&added(
+            child_list(1, None, Some(3), &[4]),
+            [element(4, &[5]), text(5, "two")],
+        ))
+        .unwrap();
+        assert_eq!(dom.children(1), [4, 3]);
+    }
+
+    #[test]
+    fn removes_whole_subtrees() {
+        let mut dom = page();
+        dom.apply(&MutationVariant::ChildListRemoved(child_list(
+            0,
+            Some(1),
+            None,
+            &[2],
+        )))
+        .unwrap();
+        assert_eq!(dom.children(0), [1]);
+        for id in [2, 4, 5] {
+            assert_eq!(dom.get(id), None);
+            assert_eq!(dom.parent(id), None);
+        }
+        assert!(dom.get(3).is_some());
+    }
+
+    #[test]
+    fn changes_text_and_attributes() {
+        let mut dom = page();
+        dom.apply(&MutationVariant::CharacterData(MutationCharacterData {
+            target_id: 3,
+            millis: 0.,
+            text_content: Some("changed".to_string()),
+        }))
+        .unwrap();
+        assert_eq!(dom.get(3), Some(&text(3, "changed")));
+        dom.apply(&MutationVariant::Attributes(MutationAttributes {
+            target_id: 1,
+            millis: 0.,
+            attribute: Some(("class".to_string(), "open".to_string())),
+        }))
+        .unwrap();
+        let Some(SerializedNode::ElementNode(element)) = dom.get(1) else {
+            panic!("1 is an element");
+        };
+        assert_eq!(
+            element.attributes,
+            Some(vec![("class".to_string(), "open".to_string())])
+        );
+    }
+
+    #[test]
+    fn leaves_the_dom_as_it_was_when_a_mutation_fails() {
+        let cases = [
+            (
+                MutationVariant::Attributes(MutationAttributes {
+                    target_id: 9,
+                    millis: 0.,
+                    attribute: None,
+                }),
+                VirtualDomError::UnknownTarget(9),
+            ),
+            (
+                added(child_list(1, None, None, &[6, 7]), [text(6, "a")]),
+                VirtualDomError::MissingAddedNode(7),
+            ),
+            (
+                added(child_list(1, Some(5), None, &[6]), [text(6, "a")]),
+                VirtualDomError::UnknownSibling(5, 1),
+            ),
+            (
+                added(child_list(3, None, None, &[6]), [text(6, "a")]),
+                VirtualDomError::NotAParent(3),
+            ),
+            (
+                MutationVariant::ChildListRemoved(child_list(1, None, None, &[3, 4])),
+                VirtualDomError::NotAChild(4, 1),
+            ),
+            (
+                MutationVariant::Attributes(MutationAttributes {
+                    target_id: 3,
+                    millis: 0.,
+                    attribute: Some(("class".to_string(), "open".to_string())),
+                }),
+                VirtualDomError::NotAnElement(3),
+            ),
+            (
+                MutationVariant::CharacterData(MutationCharacterData {
+                    target_id: 1,
+                    millis: 0.,
+                    text_content: None,
+                }),
+                VirtualDomError::NotCharacterData(1),
+            ),
+        ];
+        for (mutation, error) in cases {
+            let mut dom = page();
+            assert_eq!(dom.apply(&mutation), Err(error));
+            assert_eq!(dom, page());
+        }
+    }
+
+    #[test]
+    fn applies_mutations_in_recorded_order_up_to_millis() {
+        let set = |millis: f64, text_content: &str| {
+            MutationVariant::CharacterData(MutationCharacterData {
+                target_id: 3,
+                millis,
+                text_content: Some(text_content.to_string()),
+            })
+        };
+        let mutations = [
+            set(2., "second"),
+            set(1., "first"),
+            MutationVariant::ChildListRemoved(child_list(1, None, None, &[9])),
+            set(3., "too late"),
+        ];
+        let mut dom = page();
+        let errors = dom.apply_until(&mutations, 2.);
+        assert_eq!(errors, [(2, VirtualDomError::NotAChild(9, 1))]);
+        assert_eq!(dom.get(3), Some(&text(3, "second")));
+    }
+}
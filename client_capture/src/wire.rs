@@ -0,0 +1,1110 @@
+//! The formats snapshots and mutation chunks are uploaded and stored in.
+//!
+//! Every encoding identifies itself, so a stored payload can be decoded without knowing which codec wrote it.
+//! Bincode payloads are framed: the 4 byte MAGIC, the schema version as a little endian u16, one byte for the
+//! payload kind and then the bincode payload. Payloads without the magic come from recorders that predate framing.
+//! Since version 2 the payload of a bincode mutation chunk is in the compact layout instead, see compact.
+//! Version 3 added CaptureEvent::Error and CaptureEvent::Click, the layouts are the same as version 2's.
+//! JSON and CBOR payloads are an Envelope holding the version and kind next to the payload.
+//! Every supported version decodes into the current types, servers that keep what they decode, like replay-server,
+//! upgrade older uploads as they ingest them. Payloads stored as bytes can be upgraded by decoding and encoding them.
+//! Uploads sent over the recorder's WebSocket are wrapped in a SocketFrame and answered with a SocketAck.
+//! Refused uploads are answered with an IngestError.
+use std::collections::HashMap;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{CaptureEvent, MutationVariant, SerializedNode};
+
+mod compact;
+
+pub const MAGIC: [u8; 4] = *b"CPRS";
+/// Bump this whenever a serialized type changes shape, adding an enum variant included, so older decoders answer
+/// with UnknownVersion instead of failing on the new shape. Keep the previous layout in a module below so it still decodes.
+pub const WIRE_VERSION: u16 = 3;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 1;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum PayloadKind {
+    Snapshot = 0,
+    Mutations = 1,
+    /// Timestamped CaptureEvents, uploaded since version 1 so they're always framed.
+    Events = 2,
+}
+
+impl TryFrom<u8> for PayloadKind {
+    type Error = WireError;
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(Self::Snapshot),
+            1 => Ok(Self::Mutations),
+            2 => Ok(Self::Events),
+            kind => Err(WireError::Malformed(format!("unknown payload kind {kind}"))),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WireError {
+    #[error("wire version {0} is newer than this build understands")]
+    UnknownVersion(u16),
+    #[error("expected a {expected:?} payload but found kind {found}")]
+    WrongKind { expected: PayloadKind, found: u8 },
+    #[error("no codec for content type {0}")]
+    UnknownContentType(String),
+    #[error("malformed payload: {0}")]
+    Malformed(String),
+}
+
+impl From<bincode::Error> for WireError {
+    fn from(err: bincode::Error) -> Self {
+        Self::Malformed(err.to_string())
+    }
+}
+
+pub trait Codec: std::fmt::Debug {
+    /// Uploads in this codec are sent with this Content-Type.
+    fn content_type(&self) -> &'static str;
+    fn encode_snapshot(&self, snapshot: &HashMap<u32, SerializedNode>) -> Vec<u8>;
+    fn encode_mutations(&self, mutations: &[MutationVariant]) -> Vec<u8>;
+    fn decode_snapshot(&self, bytes: &[u8]) -> Result<HashMap<u32, SerializedNode>, WireError>;
+    fn decode_mutations(&self, bytes: &[u8]) -> Result<Vec<MutationVariant>, WireError>;
+    /// About how many bytes mutation adds to a chunk encode_mutations writes, erring on the high side.
+    fn mutation_len(&self, mutation: &MutationVariant) -> u64 {
+        let empty = self.encode_mutations(&[]).len();
+        let alone = self.encode_mutations(std::slice::from_ref(mutation)).len();
+        // the separator or length prefix that grows with every element.
+        (alone - empty + 1) as u64
+    }
+    fn encode_events(&self, events: &[(f64, CaptureEvent)]) -> Vec<u8>;
+    fn decode_events(&self, bytes: &[u8]) -> Result<Vec<(f64, CaptureEvent)>, WireError>;
+    /// What a payload holds without decoding all of it.
+    fn payload_kind(&self, bytes: &[u8]) -> Result<PayloadKind, WireError>;
+    /// The wire version a payload of kind was written in.
+    fn payload_version(&self, bytes: &[u8], kind: PayloadKind) -> Result<u16, WireError>;
+}
+
+/// The codec and wire version a payload was written in, what a server records next to what it stores.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PayloadFormat {
+    /// The codec's content type.
+    pub codec: &'static str,
+    pub version: u16,
+}
+
+/// The default, smallest and fastest but only readable from Rust.
+#[derive(Clone, Copy, Debug)]
+pub struct Bincode;
+/// Readable in the browser's network tab and by any tooling.
+#[derive(Clone, Copy, Debug)]
+pub struct Json;
+/// A self-describing binary format with libraries in most languages.
+#[derive(Clone, Copy, Debug)]
+pub struct Cbor;
+
+pub const CODECS: [&dyn Codec; 3] = [&Bincode, &Json, &Cbor];
+
+/// Matches the media type, ignoring parameters like charset.
+pub fn codec_for_content_type(content_type: &str) -> Option<&'static dyn Codec> {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    CODECS
+        .into_iter()
+        .find(|codec| codec.content_type().eq_ignore_ascii_case(media_type))
+}
+
+/// Decodes with the codec for content_type, or works out the codec from the payload when there's no Content-Type,
+/// which is the case for beacons and for recorders from before codecs.
+pub fn decode_snapshot(
+    bytes: &[u8],
+    content_type: Option<&str>,
+) -> Result<HashMap<u32, SerializedNode>, WireError> {
+    codec(bytes, content_type)?.decode_snapshot(bytes)
+}
+
+/// See decode_snapshot.
+pub fn decode_mutations(
+    bytes: &[u8],
+    content_type: Option<&str>,
+) -> Result<Vec<MutationVariant>, WireError> {
+    codec(bytes, content_type)?.decode_mutations(bytes)
+}
+
+/// See decode_snapshot.
+pub fn decode_events(
+    bytes: &[u8],
+    content_type: Option<&str>,
+) -> Result<Vec<(f64, CaptureEvent)>, WireError> {
+    codec(bytes, content_type)?.decode_events(bytes)
+}
+
+/// Lets a payload that didn't come from a kind specific endpoint, like one sent over the recorder's WebSocket, be routed.
+pub fn payload_kind(bytes: &[u8], content_type: Option<&str>) -> Result<PayloadKind, WireError> {
+    codec(bytes, content_type)?.payload_kind(bytes)
+}
+
+/// See decode_snapshot for how the codec is worked out.
+pub fn payload_format(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    kind: PayloadKind,
+) -> Result<PayloadFormat, WireError> {
+    let codec = codec(bytes, content_type)?;
+    Ok(PayloadFormat {
+        codec: codec.content_type(),
+        version: codec.payload_version(bytes, kind)?,
+    })
+}
+
+fn codec(bytes: &[u8], content_type: Option<&str>) -> Result<&'static dyn Codec, WireError> {
+    match content_type {
+        // browsers send octet-stream or nothing for raw bytes.
+        Some(content_type) if !content_type.starts_with("application/octet-stream") => {
+            codec_for_content_type(content_type)
+                .ok_or_else(|| WireError::UnknownContentType(content_type.to_string()))
+        }
+        _ if bytes.starts_with(&MAGIC) => Ok(&Bincode),
+        _ if serde_json::from_slice::<Envelope<serde::de::IgnoredAny>>(bytes).is_ok() => Ok(&Json),
+        _ if ciborium::from_reader::<Envelope<serde::de::IgnoredAny>, _>(bytes).is_ok() => {
+            Ok(&Cbor)
+        }
+        _ => Ok(&Bincode),
+    }
+}
+
+impl Codec for Bincode {
+    fn content_type(&self) -> &'static str {
+        "application/x-bincode"
+    }
+    fn encode_snapshot(&self, snapshot: &HashMap<u32, SerializedNode>) -> Vec<u8> {
+        frame(PayloadKind::Snapshot, snapshot)
+    }
+    fn encode_mutations(&self, mutations: &[MutationVariant]) -> Vec<u8> {
+        let mut frame = header(PayloadKind::Mutations);
+        frame.extend(compact::encode(mutations));
+        frame
+    }
+    fn decode_snapshot(&self, bytes: &[u8]) -> Result<HashMap<u32, SerializedNode>, WireError> {
+        match unframe(bytes, PayloadKind::Snapshot)? {
+            (1..=3, payload) => strict(payload),
+            (0, payload) => {
+                let snapshot = strict::<HashMap<u32, v0::SerializedNode>>(payload)?;
+                Ok(snapshot
+                    .into_iter()
+                    .map(|(id, node)| (id, node.into()))
+                    .collect())
+            }
+            (version, _) => Err(WireError::UnknownVersion(version)),
+        }
+    }
+    fn decode_mutations(&self, bytes: &[u8]) -> Result<Vec<MutationVariant>, WireError> {
+        match unframe(bytes, PayloadKind::Mutations)? {
+            (2 | 3, payload) => compact::decode(payload),
+            (1, payload) => strict(payload),
+            (0, payload) => {
+                let mutations = strict::<Vec<v0::MutationVariant>>(payload)?;
+                Ok(mutations.into_iter().map(Into::into).collect())
+            }
+            (version, _) => Err(WireError::UnknownVersion(version)),
+        }
+    }
+    fn encode_events(&self, events: &[(f64, CaptureEvent)]) -> Vec<u8> {
+        frame(PayloadKind::Events, events)
+    }
+    fn decode_events(&self, bytes: &[u8]) -> Result<Vec<(f64, CaptureEvent)>, WireError> {
+        match unframe(bytes, PayloadKind::Events)? {
+            (1..=3, payload) => strict(payload),
+            (version, _) => Err(WireError::UnknownVersion(version)),
+        }
+    }
+    fn payload_kind(&self, bytes: &[u8]) -> Result<PayloadKind, WireError> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+            return Err(WireError::Malformed(
+                "unframed payloads only come from their own endpoint".to_string(),
+            ));
+        }
+        PayloadKind::try_from(bytes[6])
+    }
+    fn payload_version(&self, bytes: &[u8], kind: PayloadKind) -> Result<u16, WireError> {
+        unframe(bytes, kind).map(|(version, _)| version)
+    }
+}
+
+/// How JSON and CBOR payloads are laid out. Both codecs were added after version 1, so they have no older layouts.
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u16,
+    kind: PayloadKind,
+    payload: T,
+}
+
+impl<T> Envelope<T> {
+    fn new(kind: PayloadKind, payload: T) -> Self {
+        Self {
+            version: WIRE_VERSION,
+            kind,
+            payload,
+        }
+    }
+    /// The version of a payload of kind expected, newer versions than this build's included.
+    fn version_of(&self, expected: PayloadKind) -> Result<u16, WireError> {
+        if self.kind != expected {
+            return Err(WireError::WrongKind {
+                expected,
+                found: self.kind as u8,
+            });
+        }
+        Ok(self.version)
+    }
+    fn into_payload(self, expected: PayloadKind) -> Result<T, WireError> {
+        if self.version > WIRE_VERSION {
+            return Err(WireError::UnknownVersion(self.version));
+        }
+        if self.kind != expected {
+            return Err(WireError::WrongKind {
+                expected,
+                found: self.kind as u8,
+            });
+        }
+        Ok(self.payload)
+    }
+}
+
+/// The payload of a decoded envelope. A payload that doesn't decode as T may be of another kind or of a newer
+/// version, read_header decodes the envelope without its payload to tell which.
+fn open_envelope<T, E: std::fmt::Display>(
+    envelope: Result<Envelope<T>, E>,
+    read_header: impl FnOnce() -> Result<Envelope<serde::de::IgnoredAny>, E>,
+    expected: PayloadKind,
+) -> Result<T, WireError> {
+    match envelope {
+        Ok(envelope) => envelope.into_payload(expected),
+        Err(err) => {
+            if let Ok(header) = read_header() {
+                let version = header.version_of(expected)?;
+                if version > WIRE_VERSION {
+                    return Err(WireError::UnknownVersion(version));
+                }
+            }
+            Err(WireError::Malformed(err.to_string()))
+        }
+    }
+}
+
+impl Codec for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+    fn encode_snapshot(&self, snapshot: &HashMap<u32, SerializedNode>) -> Vec<u8> {
+        serde_json::to_vec(&Envelope::new(PayloadKind::Snapshot, snapshot))
+            .expect("serializing into a vec to succeed")
+    }
+    fn encode_mutations(&self, mutations: &[MutationVariant]) -> Vec<u8> {
+        serde_json::to_vec(&Envelope::new(PayloadKind::Mutations, mutations))
+            .expect("serializing into a vec to succeed")
+    }
+    fn decode_snapshot(&self, bytes: &[u8]) -> Result<HashMap<u32, SerializedNode>, WireError> {
+        open_envelope(
+            serde_json::from_slice(bytes),
+            || serde_json::from_slice(bytes),
+            PayloadKind::Snapshot,
+        )
+    }
+    fn decode_mutations(&self, bytes: &[u8]) -> Result<Vec<MutationVariant>, WireError> {
+        open_envelope(
+            serde_json::from_slice(bytes),
+            || serde_json::from_slice(bytes),
+            PayloadKind::Mutations,
+        )
+    }
+    fn encode_events(&self, events: &[(f64, CaptureEvent)]) -> Vec<u8> {
+        serde_json::to_vec(&Envelope::new(PayloadKind::Events, events))
+            .expect("serializing into a vec to succeed")
+    }
+    fn decode_events(&self, bytes: &[u8]) -> Result<Vec<(f64, CaptureEvent)>, WireError> {
+        open_envelope(
+            serde_json::from_slice(bytes),
+            || serde_json::from_slice(bytes),
+            PayloadKind::Events,
+        )
+    }
+    fn payload_kind(&self, bytes: &[u8]) -> Result<PayloadKind, WireError> {
+        serde_json::from_slice::<Envelope<serde::de::IgnoredAny>>(bytes)
+            .map(|envelope| envelope.kind)
+            .map_err(|err| WireError::Malformed(err.to_string()))
+    }
+    fn payload_version(&self, bytes: &[u8], kind: PayloadKind) -> Result<u16, WireError> {
+        let envelope = serde_json::from_slice::<Envelope<serde::de::IgnoredAny>>(bytes)
+            .map_err(|err| WireError::Malformed(err.to_string()))?;
+        envelope.version_of(kind)
+    }
+}
+
+impl Codec for Cbor {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+    fn encode_snapshot(&self, snapshot: &HashMap<u32, SerializedNode>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&Envelope::new(PayloadKind::Snapshot, snapshot), &mut bytes)
+            .expect("serializing into a vec to succeed");
+        bytes
+    }
+    fn encode_mutations(&self, mutations: &[MutationVariant]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(
+            &Envelope::new(PayloadKind::Mutations, mutations),
+            &mut bytes,
+        )
+        .expect("serializing into a vec to succeed");
+        bytes
+    }
+    fn decode_snapshot(&self, bytes: &[u8]) -> Result<HashMap<u32, SerializedNode>, WireError> {
+        open_envelope(
+            ciborium::from_reader(bytes),
+            || ciborium::from_reader(bytes),
+            PayloadKind::Snapshot,
+        )
+    }
+    fn decode_mutations(&self, bytes: &[u8]) -> Result<Vec<MutationVariant>, WireError> {
+        open_envelope(
+            ciborium::from_reader(bytes),
+            || ciborium::from_reader(bytes),
+            PayloadKind::Mutations,
+        )
+    }
+    fn encode_events(&self, events: &[(f64, CaptureEvent)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&Envelope::new(PayloadKind::Events, events), &mut bytes)
+            .expect("serializing into a vec to succeed");
+        bytes
+    }
+    fn decode_events(&self, bytes: &[u8]) -> Result<Vec<(f64, CaptureEvent)>, WireError> {
+        open_envelope(
+            ciborium::from_reader(bytes),
+            || ciborium::from_reader(bytes),
+            PayloadKind::Events,
+        )
+    }
+    fn payload_kind(&self, bytes: &[u8]) -> Result<PayloadKind, WireError> {
+        ciborium::from_reader::<Envelope<serde::de::IgnoredAny>, _>(bytes)
+            .map(|envelope| envelope.kind)
+            .map_err(|err| WireError::Malformed(err.to_string()))
+    }
+    fn payload_version(&self, bytes: &[u8], kind: PayloadKind) -> Result<u16, WireError> {
+        let envelope = ciborium::from_reader::<Envelope<serde::de::IgnoredAny>, _>(bytes)
+            .map_err(|err| WireError::Malformed(err.to_string()))?;
+        envelope.version_of(kind)
+    }
+}
+
+/// Snapshots are uploaded with the URL of the page they were taken of.
+pub const PAGE_URL_HEADER: &str = "x-capture-url";
+/// Every upload is numbered, see upload::next_seq. Beacons can't set headers and put it in the query string as seq.
+pub const SEQ_HEADER: &str = "x-capture-seq";
+/// Event uploads carry what the recorder dropped since its last report as a JSON queue::DropCounts. Reports ride
+/// on uploads, so one in an upload that's lost, or sent as a beacon, which can't set headers, is lost with it.
+pub const DROPPED_HEADER: &str = "x-capture-dropped";
+/// SocketFrames carry the session they belong to, the socket is shared by the uploads of every session a tab sends,
+/// its own and the ones it adopted from closed tabs.
+pub const SESSION_HEADER: &str = "x-capture-session";
+/// Snapshots are uploaded with performance.timeOrigin, the unix millis the recorded millis of the page count from.
+pub const TIME_ORIGIN_HEADER: &str = "x-capture-time-origin";
+
+/// An upload sent over the recorder's WebSocket, with the headers it would have been posted with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SocketFrame {
+    pub seq: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The server's answer to a SocketFrame, status is the HTTP status the upload would have gotten if it was posted.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SocketAck {
+    pub seq: u32,
+    pub status: u16,
+    /// How long to wait before sending again when status is 429, 0 otherwise.
+    pub retry_after_millis: u32,
+}
+
+/// Acks from servers that predate retry_after_millis.
+#[derive(Deserialize)]
+struct SocketAckV0 {
+    seq: u32,
+    status: u16,
+}
+
+/// Why the server refused an upload, the JSON body of its 4xx answers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IngestError {
+    pub code: IngestErrorCode,
+    pub message: String,
+    /// How long to wait before sending again, set when code is RateLimited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_millis: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestErrorCode {
+    /// The body couldn't be decoded, sending it again won't help.
+    Malformed,
+    UnsupportedMediaType,
+    /// The body, or what it decompresses to, is over the server's limit.
+    TooLarge,
+    /// The session or the client sent too many uploads, wait retry_after_millis.
+    RateLimited,
+    /// The api key is missing or unknown.
+    Unauthorized,
+    /// The project doesn't allow the origin the upload came from.
+    Forbidden,
+    Internal,
+}
+
+impl IngestError {
+    pub fn new(code: IngestErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after_millis: None,
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("serializing into a vec to succeed")
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, WireError> {
+        serde_json::from_slice(bytes).map_err(|err| WireError::Malformed(err.to_string()))
+    }
+}
+
+impl SocketFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing into a vec to succeed")
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, WireError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+impl SocketAck {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serializing into a vec to succeed")
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, WireError> {
+        if let Ok(ack) = bincode::deserialize(bytes) {
+            return Ok(ack);
+        }
+        let ack = bincode::deserialize::<SocketAckV0>(bytes)?;
+        Ok(Self {
+            seq: ack.seq,
+            status: ack.status,
+            retry_after_millis: 0,
+        })
+    }
+}
+
+fn frame<T: Serialize + ?Sized>(kind: PayloadKind, payload: &T) -> Vec<u8> {
+    let mut frame = header(kind);
+    bincode::serialize_into(&mut frame, payload).expect("serializing into a vec to succeed");
+    frame
+}
+
+fn header(kind: PayloadKind) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&WIRE_VERSION.to_le_bytes());
+    header.push(kind as u8);
+    header
+}
+
+/// Returns the version and the payload.
+fn unframe(bytes: &[u8], expected: PayloadKind) -> Result<(u16, &[u8]), WireError> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Ok((unframed_version(bytes, expected), bytes));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if bytes[6] != expected as u8 {
+        return Err(WireError::WrongKind {
+            expected,
+            found: bytes[6],
+        });
+    }
+    Ok((version, &bytes[HEADER_LEN..]))
+}
+
+/// Unframed payloads were written by recorders from before the frame existed. The newest of them already had the
+/// version 1 layout, older ones have the version 0 layout. Strict decoding tells them apart because a payload in
+/// the wrong layout either fails or leaves bytes over.
+fn unframed_version(bytes: &[u8], kind: PayloadKind) -> u16 {
+    let is_version_1 = match kind {
+        PayloadKind::Snapshot => strict::<HashMap<u32, SerializedNode>>(bytes).is_ok(),
+        PayloadKind::Mutations => strict::<Vec<MutationVariant>>(bytes).is_ok(),
+        PayloadKind::Events => true,
+    };
+    if is_version_1 {
+        1
+    } else {
+        0
+    }
+}
+
+/// bincode::deserialize with the same encoding, but trailing bytes are an error.
+fn strict<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, WireError> {
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)?)
+}
+
+/// The layout before form state, viewports and scroll positions were recorded.
+mod v0 {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use crate::{
+        CDataNode, CommentNode, DocumentTypeNode, MutationAttributes, MutationCharacterData,
+        MutationChildList, TextNode,
+    };
+
+    // variant names have to match crate::SerializedNode.
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub enum SerializedNode {
+        DocumentNode(DocumentNode),
+        ElementNode(ElementNode),
+        TextNode(TextNode),
+        CommentNode(CommentNode),
+        CDataNode(CDataNode),
+        DocumentTypeNode(DocumentTypeNode),
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct DocumentNode {
+        pub id: u32,
+        pub root_id: u32,
+        pub is_shadow_host: bool,
+        pub is_shadow: bool,
+        pub child_nodes: Option<Vec<u32>>,
+        pub compat_mode: String,
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub struct ElementNode {
+        pub id: u32,
+        pub root_id: u32,
+        pub is_shadow_host: bool,
+        pub is_shadow: bool,
+        pub tag_name: String,
+        pub attributes: Option<Vec<(String, String)>>,
+        pub child_nodes: Option<Vec<u32>>,
+        pub is_svg: bool,
+        pub need_block: bool,
+        pub is_custom: bool,
+    }
+
+    #[derive(Deserialize)]
+    #[cfg_attr(test, derive(serde::Serialize))]
+    pub enum MutationVariant {
+        ChildListAdded((MutationChildList, HashMap<u32, SerializedNode>)),
+        ChildListRemoved(MutationChildList),
+        CharacterData(MutationCharacterData),
+        Attributes(MutationAttributes),
+    }
+
+    impl From<SerializedNode> for crate::SerializedNode {
+        fn from(node: SerializedNode) -> Self {
+            match node {
+                SerializedNode::DocumentNode(node) => Self::DocumentNode(crate::DocumentNode {
+                    id: node.id,
+                    root_id: node.root_id,
+                    is_shadow_host: node.is_shadow_host,
+                    is_shadow: node.is_shadow,
+                    child_nodes: node.child_nodes,
+                    compat_mode: node.compat_mode,
+                    viewport: None,
+                    scroll: None,
+                }),
+                SerializedNode::ElementNode(node) => Self::ElementNode(crate::ElementNode {
+                    id: node.id,
+                    root_id: node.root_id,
+                    is_shadow_host: node.is_shadow_host,
+                    is_shadow: node.is_shadow,
+                    tag_name: node.tag_name,
+                    attributes: node.attributes,
+                    child_nodes: node.child_nodes,
+                    is_svg: node.is_svg,
+                    need_block: node.need_block,
+                    is_custom: node.is_custom,
+                    form_state: None,
+                    scroll: None,
+                }),
+                SerializedNode::TextNode(node) => Self::TextNode(node),
+                SerializedNode::CommentNode(node) => Self::CommentNode(node),
+                SerializedNode::CDataNode(node) => Self::CDataNode(node),
+                SerializedNode::DocumentTypeNode(node) => Self::DocumentTypeNode(node),
+            }
+        }
+    }
+
+    impl From<MutationVariant> for crate::MutationVariant {
+        fn from(mutation: MutationVariant) -> Self {
+            match mutation {
+                MutationVariant::ChildListAdded((mutation, added)) => Self::ChildListAdded((
+                    mutation,
+                    added
+                        .into_iter()
+                        .map(|(id, node)| (id, node.into()))
+                        .collect(),
+                )),
+                MutationVariant::ChildListRemoved(mutation) => Self::ChildListRemoved(mutation),
+                MutationVariant::CharacterData(mutation) => Self::CharacterData(mutation),
+                MutationVariant::Attributes(mutation) => Self::Attributes(mutation),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MutationAttributes, MutationCharacterData, MutationChildList, TextNode};
+
+    fn mutations() -> Vec<MutationVariant> {
+        let text = SerializedNode::TextNode(TextNode {
+            id: 7,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            text_content: Some("added".to_string()),
+        });
+        let mut mutations = vec![MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id: 2,
+                millis: 12.5,
+                prev_sibling: Some(3),
+                next_sibling: None,
+                nodes: vec![7],
+            },
+            HashMap::from([(7, text)]),
+        ))];
+        for index in 0..300 {
+            mutations.push(MutationVariant::Attributes(MutationAttributes {
+                target_id: index % 7,
+                millis: 13. + index as f64,
+                attribute: Some(("class".to_string(), format!("item-{index}"))),
+            }));
+        }
+        mutations.push(MutationVariant::CharacterData(MutationCharacterData {
+            target_id: 7,
+            millis: 400.,
+            text_content: None,
+        }));
+        mutations.push(MutationVariant::ChildListRemoved(MutationChildList {
+            target_id: 2,
+            millis: 401.,
+            prev_sibling: None,
+            next_sibling: None,
+            nodes: vec![7],
+        }));
+        mutations
+    }
+
+    #[test]
+    fn mutation_lens_cover_the_encoded_chunk() {
+        let mutations = mutations();
+        for codec in CODECS {
+            let measured = mutations
+                .iter()
+                .map(|mutation| codec.mutation_len(mutation))
+                .sum::<u64>();
+            let encoded =
+                codec.encode_mutations(&mutations).len() - codec.encode_mutations(&[]).len();
+            assert!(
+                measured >= encoded as u64,
+                "{} measured {measured} of {encoded} bytes",
+                codec.content_type()
+            );
+        }
+    }
+
+    fn snapshot() -> HashMap<u32, SerializedNode> {
+        let document = SerializedNode::DocumentNode(crate::DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(vec![1]),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: None,
+            scroll: None,
+        });
+        let element = SerializedNode::ElementNode(crate::ElementNode {
+            id: 1,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: "html".to_string(),
+            attributes: Some(vec![("lang".to_string(), "en".to_string())]),
+            child_nodes: Some(Vec::new()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        });
+        HashMap::from([(0, document), (1, element)])
+    }
+
+    /// snapshot in the layout of version 0.
+    fn snapshot_v0() -> HashMap<u32, v0::SerializedNode> {
+        let document = v0::SerializedNode::DocumentNode(v0::DocumentNode {
+            id: 0,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(vec![1]),
+            compat_mode: "CSS1Compat".to_string(),
+        });
+        let element = v0::SerializedNode::ElementNode(v0::ElementNode {
+            id: 1,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: "html".to_string(),
+            attributes: Some(vec![("lang".to_string(), "en".to_string())]),
+            child_nodes: Some(Vec::new()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+        });
+        HashMap::from([(0, document), (1, element)])
+    }
+
+    fn added(snapshot: HashMap<u32, SerializedNode>) -> MutationVariant {
+        MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id: 5,
+                millis: 3.,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![0],
+            },
+            snapshot,
+        ))
+    }
+
+    /// A bincode frame as a build of version would write it.
+    fn framed<T: Serialize>(version: u16, kind: u8, payload: &T) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(version.to_le_bytes());
+        bytes.push(kind);
+        bytes.extend(bincode::serialize(payload).unwrap());
+        bytes
+    }
+
+    fn version(bytes: &[u8], content_type: Option<&str>, kind: PayloadKind) -> u16 {
+        payload_format(bytes, content_type, kind).unwrap().version
+    }
+
+    #[test]
+    fn unframed_payloads_decode_in_the_layout_they_were_written_in() {
+        let v1 = bincode::serialize(&snapshot()).unwrap();
+        let v0 = bincode::serialize(&snapshot_v0()).unwrap();
+        for content_type in [None, Some("application/octet-stream")] {
+            assert_eq!(decode_snapshot(&v1, content_type).unwrap(), snapshot());
+            assert_eq!(decode_snapshot(&v0, content_type).unwrap(), snapshot());
+            assert_eq!(version(&v1, content_type, PayloadKind::Snapshot), 1);
+            assert_eq!(version(&v0, content_type, PayloadKind::Snapshot), 0);
+        }
+
+        let v1 = bincode::serialize(&vec![added(snapshot())]).unwrap();
+        let v0 = bincode::serialize(&vec![v0::MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id: 5,
+                millis: 3.,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![0],
+            },
+            snapshot_v0(),
+        ))])
+        .unwrap();
+        assert_eq!(decode_mutations(&v1, None).unwrap(), [added(snapshot())]);
+        assert_eq!(decode_mutations(&v0, None).unwrap(), [added(snapshot())]);
+        assert_eq!(version(&v1, None, PayloadKind::Mutations), 1);
+        assert_eq!(version(&v0, None, PayloadKind::Mutations), 0);
+    }
+
+    #[test]
+    fn framed_payloads_of_older_versions_decode() {
+        let snapshot_v1 = framed(1, PayloadKind::Snapshot as u8, &snapshot());
+        assert_eq!(Bincode.decode_snapshot(&snapshot_v1).unwrap(), snapshot());
+        let mutations_v1 = framed(1, PayloadKind::Mutations as u8, &mutations());
+        assert_eq!(
+            Bincode.decode_mutations(&mutations_v1).unwrap(),
+            mutations()
+        );
+        assert_eq!(
+            Bincode
+                .payload_version(&mutations_v1, PayloadKind::Mutations)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn newer_versions_are_unknown() {
+        let newer = WIRE_VERSION + 1;
+        let snapshot_bytes = framed(newer, PayloadKind::Snapshot as u8, &snapshot());
+        assert!(matches!(
+            decode_snapshot(&snapshot_bytes, None),
+            Err(WireError::UnknownVersion(version)) if version == newer
+        ));
+        let mutations_bytes = framed(newer, PayloadKind::Mutations as u8, &mutations());
+        assert!(matches!(
+            decode_mutations(&mutations_bytes, None),
+            Err(WireError::UnknownVersion(_))
+        ));
+        let events = framed(
+            newer,
+            PayloadKind::Events as u8,
+            &Vec::<(f64, CaptureEvent)>::new(),
+        );
+        assert!(matches!(
+            decode_events(&events, None),
+            Err(WireError::UnknownVersion(_))
+        ));
+        // the version is still reported, so a server can say what it couldn't read.
+        assert_eq!(version(&snapshot_bytes, None, PayloadKind::Snapshot), newer);
+
+        let envelope = Envelope {
+            version: newer,
+            kind: PayloadKind::Snapshot,
+            payload: snapshot(),
+        };
+        let json = serde_json::to_vec(&envelope).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&envelope, &mut cbor).unwrap();
+        for (codec, bytes) in [(&Json as &dyn Codec, json), (&Cbor, cbor)] {
+            assert!(matches!(
+                codec.decode_snapshot(&bytes),
+                Err(WireError::UnknownVersion(version)) if version == newer
+            ));
+            assert_eq!(
+                codec
+                    .payload_version(&bytes, PayloadKind::Snapshot)
+                    .unwrap(),
+                newer
+            );
+        }
+        // a newer payload needn't decode as the current one.
+        let reshaped = format!(r#"{{"version":{newer},"kind":"Snapshot","payload":"nodes"}}"#);
+        assert!(matches!(
+            Json.decode_snapshot(reshaped.as_bytes()),
+            Err(WireError::UnknownVersion(version)) if version == newer
+        ));
+        assert!(matches!(
+            Json.decode_snapshot(br#"{"version":1,"kind":"Snapshot","payload":"nodes"}"#),
+            Err(WireError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn payloads_of_another_kind_are_refused() {
+        for codec in CODECS {
+            let bytes = codec.encode_snapshot(&snapshot());
+            assert!(matches!(
+                codec.decode_mutations(&bytes),
+                Err(WireError::WrongKind {
+                    expected: PayloadKind::Mutations,
+                    found: 0
+                })
+            ));
+            assert!(matches!(
+                codec.payload_version(&bytes, PayloadKind::Events),
+                Err(WireError::WrongKind {
+                    expected: PayloadKind::Events,
+                    found: 0
+                })
+            ));
+            assert_eq!(codec.payload_kind(&bytes).unwrap(), PayloadKind::Snapshot);
+        }
+        let unknown_kind = framed(WIRE_VERSION, 9, &snapshot());
+        assert!(matches!(
+            Bincode.payload_kind(&unknown_kind),
+            Err(WireError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn payloads_without_the_magic_arent_framed() {
+        let mut bytes = Bincode.encode_snapshot(&snapshot());
+        bytes[0] = b'X';
+        // taken as an unframed payload, which these bytes aren't.
+        assert!(matches!(
+            Bincode.decode_snapshot(&bytes),
+            Err(WireError::Malformed(_))
+        ));
+        assert!(matches!(
+            Bincode.payload_kind(&bytes),
+            Err(WireError::Malformed(_))
+        ));
+        assert!(matches!(
+            Bincode.payload_kind(&MAGIC),
+            Err(WireError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn envelopes_decode() {
+        let json =
+            br#"{"version":1,"kind":"Events","payload":[[1.5,{"MouseMove":{"x":1,"y":2}}]]}"#;
+        assert_eq!(
+            decode_events(json, Some("application/json; charset=utf-8")).unwrap(),
+            [(1.5, CaptureEvent::MouseMove { x: 1, y: 2 })]
+        );
+        assert_eq!(Json.payload_kind(json).unwrap(), PayloadKind::Events);
+        assert_eq!(version(json, None, PayloadKind::Events), 1);
+        assert!(matches!(
+            Json.decode_events(br#"{"version":1,"kind":"Events"}"#),
+            Err(WireError::Malformed(_))
+        ));
+
+        let mut cbor = Vec::new();
+        ciborium::into_writer(
+            &Envelope {
+                version: 2,
+                kind: PayloadKind::Mutations,
+                payload: mutations(),
+            },
+            &mut cbor,
+        )
+        .unwrap();
+        assert_eq!(decode_mutations(&cbor, None).unwrap(), mutations());
+        assert_eq!(version(&cbor, None, PayloadKind::Mutations), 2);
+    }
+
+    fn events() -> Vec<(f64, CaptureEvent)> {
+        vec![
+            (1., CaptureEvent::MouseMove { x: 1, y: -2 }),
+            (2., CaptureEvent::MouseClick { x: 3, y: 4 }),
+            (
+                3.5,
+                CaptureEvent::WindowResize {
+                    height: 720,
+                    width: 1280,
+                },
+            ),
+            (4., CaptureEvent::TouchMove { x: 5, y: 6 }),
+            (5., CaptureEvent::Scoll {}),
+            (
+                6.,
+                CaptureEvent::Error {
+                    line: 10,
+                    column: 0,
+                },
+            ),
+            (
+                7.25,
+                CaptureEvent::Click {
+                    x: 7,
+                    y: 8,
+                    page_x: 7,
+                    page_y: 908,
+                    target: Some(12),
+                },
+            ),
+            (
+                8.,
+                CaptureEvent::Click {
+                    x: 0,
+                    y: 0,
+                    page_x: 0,
+                    page_y: 0,
+                    target: None,
+                },
+            ),
+        ]
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        for codec in CODECS {
+            let name = codec.content_type();
+            let bytes = codec.encode_snapshot(&snapshot());
+            assert_eq!(codec.decode_snapshot(&bytes).unwrap(), snapshot(), "{name}");
+            assert_eq!(codec.payload_kind(&bytes).unwrap(), PayloadKind::Snapshot);
+
+            let bytes = codec.encode_mutations(&mutations());
+            assert_eq!(
+                codec.decode_mutations(&bytes).unwrap(),
+                mutations(),
+                "{name}"
+            );
+            assert_eq!(codec.payload_kind(&bytes).unwrap(), PayloadKind::Mutations);
+
+            let bytes = codec.encode_events(&events());
+            assert_eq!(codec.decode_events(&bytes).unwrap(), events(), "{name}");
+            assert_eq!(codec.payload_kind(&bytes).unwrap(), PayloadKind::Events);
+            assert_eq!(
+                codec.payload_version(&bytes, PayloadKind::Events).unwrap(),
+                WIRE_VERSION
+            );
+        }
+    }
+
+    #[test]
+    fn codecs_are_found_by_content_type() {
+        for codec in CODECS {
+            let found = codec_for_content_type(codec.content_type()).unwrap();
+            assert_eq!(found.content_type(), codec.content_type());
+        }
+        let json = codec_for_content_type("Application/JSON; charset=utf-8").unwrap();
+        assert_eq!(json.content_type(), "application/json");
+        assert!(codec_for_content_type("text/plain").is_none());
+        assert!(matches!(
+            decode_snapshot(&Json.encode_snapshot(&snapshot()), Some("text/plain")),
+            Err(WireError::UnknownContentType(content_type)) if content_type == "text/plain"
+        ));
+    }
+
+    /// Beacons carry no Content-Type, or octet-stream from browsers that drop a Blob's type.
+    #[test]
+    fn codecs_are_detected_without_content_type() {
+        for codec in CODECS {
+            let name = codec.content_type();
+            let snapshot_bytes = codec.encode_snapshot(&snapshot());
+            let mutation_bytes = codec.encode_mutations(&mutations());
+            let event_bytes = codec.encode_events(&events());
+            for content_type in [None, Some("application/octet-stream")] {
+                assert_eq!(
+                    decode_snapshot(&snapshot_bytes, content_type).unwrap(),
+                    snapshot()
+                );
+                assert_eq!(
+                    decode_mutations(&mutation_bytes, content_type).unwrap(),
+                    mutations()
+                );
+                assert_eq!(decode_events(&event_bytes, content_type).unwrap(), events());
+                assert_eq!(
+                    payload_kind(&mutation_bytes, content_type).unwrap(),
+                    PayloadKind::Mutations
+                );
+                let format =
+                    payload_format(&mutation_bytes, content_type, PayloadKind::Mutations).unwrap();
+                assert_eq!(
+                    format,
+                    PayloadFormat {
+                        codec: name,
+                        version: WIRE_VERSION
+                    }
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,894 @@
+//! The layout of bincode mutation chunks since version 2. Most mutations are a few bytes of change next to a u32 id
+//! and an f64 timestamp, so here ids are stored as the difference to the previous id in the chunk, timestamps as
+//! ticks since the chunk's first mutation and strings only once, later occurrences refer back to the first.
+//!
+//! Numbers are LEB128 varints, signed ones zigzag encoded. A string is its index in the chunk's table, followed by
+//! its length and bytes when the index is the next free one. A timestamp that ticks can't reproduce exactly is
+//! stored as its f64 bits instead, so decoding always yields the values that were encoded.
+use std::collections::HashMap;
+
+use super::WireError;
+use crate::{
+    CDataNode, CommentNode, DocumentNode, DocumentTypeNode, ElementNode, FormState,
+    MutationAttributes, MutationCharacterData, MutationChildList, MutationVariant, ScrollPosition,
+    SerializedNode, TextNode, Viewport,
+};
+
+/// millis() nudges equal timestamps apart by 0.0001.
+const TICKS_PER_MILLI: f64 = 10_000.;
+/// Past this the tick count can't be shifted into a varint tag.
+const MAX_TICKS: f64 = (1u64 << 52) as f64;
+
+const CHILD_LIST_ADDED: u8 = 0;
+const CHILD_LIST_REMOVED: u8 = 1;
+const CHARACTER_DATA: u8 = 2;
+const ATTRIBUTES: u8 = 3;
+
+const DOCUMENT: u8 = 0;
+const ELEMENT: u8 = 1;
+const TEXT: u8 = 2;
+const COMMENT: u8 = 3;
+const CDATA: u8 = 4;
+const DOCUMENT_TYPE: u8 = 5;
+
+const SHADOW_HOST: u8 = 1;
+const SHADOW: u8 = 1 << 1;
+const SVG: u8 = 1 << 2;
+const NEED_BLOCK: u8 = 1 << 3;
+const CUSTOM: u8 = 1 << 4;
+
+pub(super) fn encode(mutations: &[MutationVariant]) -> Vec<u8> {
+    let start = mutations
+        .first()
+        .map(MutationVariant::millis)
+        .unwrap_or_default();
+    let mut writer = Writer {
+        bytes: Vec::new(),
+        strings: HashMap::new(),
+        last_id: 0,
+        start,
+    };
+    writer.unsigned(mutations.len() as u64);
+    writer.f64(start);
+    for mutation in mutations {
+        writer.mutation(mutation);
+    }
+    writer.bytes
+}
+
+pub(super) fn decode(bytes: &[u8]) -> Result<Vec<MutationVariant>, WireError> {
+    let mut reader = Reader {
+        bytes,
+        strings: Vec::new(),
+        last_id: 0,
+        start: 0.,
+    };
+    let len = reader.len()?;
+    reader.start = reader.f64()?;
+    let mutations = (0..len)
+        .map(|_| reader.mutation())
+        .collect::<Result<Vec<_>, _>>()?;
+    if !reader.bytes.is_empty() {
+        return Err(malformed("trailing bytes after the last mutation"));
+    }
+    Ok(mutations)
+}
+
+fn malformed(reason: &str) -> WireError {
+    WireError::Malformed(reason.to_string())
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Both sides compute the timestamp this way, the writer only uses ticks when the result is bit for bit the same.
+fn from_ticks(start: f64, ticks: i64) -> f64 {
+    start + ticks as f64 / TICKS_PER_MILLI
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    strings: HashMap<String, u64>,
+    last_id: u32,
+    start: f64,
+}
+
+impl Writer {
+    fn unsigned(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+    fn signed(&mut self, value: i64) {
+        self.unsigned(zigzag(value))
+    }
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+    fn id(&mut self, id: u32) {
+        self.signed(id as i64 - self.last_id as i64);
+        self.last_id = id;
+    }
+    fn optional_id(&mut self, id: Option<u32>) {
+        match id {
+            Some(id) => {
+                self.bool(true);
+                self.id(id);
+            }
+            None => self.bool(false),
+        }
+    }
+    fn ids(&mut self, ids: &[u32]) {
+        self.unsigned(ids.len() as u64);
+        for id in ids {
+            self.id(*id);
+        }
+    }
+    fn optional_ids(&mut self, ids: &Option<Vec<u32>>) {
+        self.bool(ids.is_some());
+        if let Some(ids) = ids {
+            self.ids(ids);
+        }
+    }
+    /// The lowest bit tells ticks from raw f64 bits.
+    fn millis(&mut self, millis: f64) {
+        let ticks = ((millis - self.start) * TICKS_PER_MILLI).round();
+        if ticks.abs() < MAX_TICKS
+            && from_ticks(self.start, ticks as i64).to_bits() == millis.to_bits()
+        {
+            self.unsigned(zigzag(ticks as i64) << 1);
+        } else {
+            self.unsigned(1);
+            self.f64(millis);
+        }
+    }
+    fn string(&mut self, value: &str) {
+        if let Some(index) = self.strings.get(value) {
+            let index = *index;
+            self.unsigned(index);
+            return;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.insert(value.to_string(), index);
+        self.unsigned(index);
+        self.unsigned(value.len() as u64);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+    fn optional_string(&mut self, value: &Option<String>) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            self.string(value);
+        }
+    }
+    fn mutation(&mut self, mutation: &MutationVariant) {
+        match mutation {
+            MutationVariant::ChildListAdded((mutation, added)) => {
+                self.bytes.push(CHILD_LIST_ADDED);
+                self.child_list(mutation);
+                self.unsigned(added.len() as u64);
+                for (id, node) in added {
+                    self.id(*id);
+                    self.node(node);
+                }
+            }
+            MutationVariant::ChildListRemoved(mutation) => {
+                self.bytes.push(CHILD_LIST_REMOVED);
+                self.child_list(mutation);
+            }
+            MutationVariant::CharacterData(mutation) => {
+                self.bytes.push(CHARACTER_DATA);
+                self.millis(mutation.millis);
+                self.id(mutation.target_id);
+                self.optional_string(&mutation.text_content);
+            }
+            MutationVariant::Attributes(mutation) => {
+                self.bytes.push(ATTRIBUTES);
+                self.millis(mutation.millis);
+                self.id(mutation.target_id);
+                self.bool(mutation.attribute.is_some());
+                if let Some((name, value)) = &mutation.attribute {
+                    self.string(name);
+                    self.string(value);
+                }
+            }
+        }
+    }
+    fn child_list(&mut self, mutation: &MutationChildList) {
+        self.millis(mutation.millis);
+        self.id(mutation.target_id);
+        self.optional_id(mutation.prev_sibling);
+        self.optional_id(mutation.next_sibling);
+        self.ids(&mutation.nodes);
+    }
+    fn node(&mut self, node: &SerializedNode) {
+        match node {
+            SerializedNode::DocumentNode(node) => {
+                self.bytes.push(DOCUMENT);
+                self.common(
+                    node.id,
+                    node.root_id,
+                    node.is_shadow_host,
+                    node.is_shadow,
+                    0,
+                );
+                self.optional_ids(&node.child_nodes);
+                self.string(&node.compat_mode);
+                self.bool(node.viewport.is_some());
+                if let Some(viewport) = node.viewport {
+                    self.unsigned(viewport.width as u64);
+                    self.unsigned(viewport.height as u64);
+                    self.f64(viewport.device_pixel_ratio);
+                    self.f64(viewport.scale);
+                }
+                self.scroll(node.scroll);
+            }
+            SerializedNode::ElementNode(node) => {
+                self.bytes.push(ELEMENT);
+                let flags = (node.is_svg as u8 * SVG)
+                    | (node.need_block as u8 * NEED_BLOCK)
+                    | (node.is_custom as u8 * CUSTOM);
+                self.common(
+                    node.id,
+                    node.root_id,
+                    node.is_shadow_host,
+                    node.is_shadow,
+                    flags,
+                );
+                self.string(&node.tag_name);
+                self.bool(node.attributes.is_some());
+                if let Some(attributes) = &node.attributes {
+                    self.unsigned(attributes.len() as u64);
+                    for (name, value) in attributes {
+                        self.string(name);
+                        self.string(value);
+                    }
+                }
+                self.optional_ids(&node.child_nodes);
+                self.bool(node.form_state.is_some());
+                if let Some(form_state) = &node.form_state {
+                    self.optional_string(&form_state.value);
+                    for flag in [form_state.checked, form_state.selected] {
+                        // 0 for None, then false and true.
+                        self.bytes.push(flag.map_or(0, |flag| flag as u8 + 1));
+                    }
+                    self.bool(form_state.selected_index.is_some());
+                    if let Some(index) = form_state.selected_index {
+                        self.signed(index as i64);
+                    }
+                }
+                self.scroll(node.scroll);
+            }
+            SerializedNode::TextNode(TextNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }) => {
+                self.bytes.push(TEXT);
+                self.common(*id, *root_id, *is_shadow_host, *is_shadow, 0);
+                self.optional_string(text_content);
+            }
+            SerializedNode::CommentNode(CommentNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }) => {
+                self.bytes.push(COMMENT);
+                self.common(*id, *root_id, *is_shadow_host, *is_shadow, 0);
+                self.optional_string(text_content);
+            }
+            SerializedNode::CDataNode(CDataNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content,
+            }) => {
+                self.bytes.push(CDATA);
+                self.common(*id, *root_id, *is_shadow_host, *is_shadow, 0);
+                self.optional_string(text_content);
+            }
+            SerializedNode::DocumentTypeNode(node) => {
+                self.bytes.push(DOCUMENT_TYPE);
+                self.common(
+                    node.id,
+                    node.root_id,
+                    node.is_shadow_host,
+                    node.is_shadow,
+                    0,
+                );
+                self.string(&node.name);
+                self.string(&node.public_id);
+                self.string(&node.system_id);
+            }
+        }
+    }
+    fn common(&mut self, id: u32, root_id: u32, is_shadow_host: bool, is_shadow: bool, flags: u8) {
+        self.id(id);
+        self.id(root_id);
+        self.bytes
+            .push(flags | (is_shadow_host as u8 * SHADOW_HOST) | (is_shadow as u8 * SHADOW));
+    }
+    fn scroll(&mut self, scroll: Option<ScrollPosition>) {
+        self.bool(scroll.is_some());
+        if let Some(scroll) = scroll {
+            self.signed(scroll.top as i64);
+            self.signed(scroll.left as i64);
+        }
+    }
+}
+
+struct Reader<'a> {
+    /// What's left to read.
+    bytes: &'a [u8],
+    strings: Vec<String>,
+    last_id: u32,
+    start: f64,
+}
+
+/// The fields every serialized node has, and the element flags.
+struct Common {
+    id: u32,
+    root_id: u32,
+    is_shadow_host: bool,
+    is_shadow: bool,
+    flags: u8,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], WireError> {
+        if self.bytes.len() < len {
+            return Err(malformed("chunk ends early"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+    fn byte(&mut self) -> Result<u8, WireError> {
+        Ok(self.take(1)?[0])
+    }
+    fn unsigned(&mut self) -> Result<u64, WireError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed("varint is too long"))
+    }
+    fn signed(&mut self) -> Result<i64, WireError> {
+        self.unsigned().map(unzigzag)
+    }
+    /// A count of things that each take at least a byte, so a corrupt count can't allocate more than the chunk.
+    fn len(&mut self) -> Result<usize, WireError> {
+        let len = self.unsigned()?;
+        if len > self.bytes.len() as u64 {
+            return Err(malformed("length is longer than the chunk"));
+        }
+        Ok(len as usize)
+    }
+    fn u32(&mut self) -> Result<u32, WireError> {
+        u32::try_from(self.unsigned()?).map_err(|_| malformed("number is out of range"))
+    }
+    fn i32(&mut self) -> Result<i32, WireError> {
+        i32::try_from(self.signed()?).map_err(|_| malformed("number is out of range"))
+    }
+    fn f64(&mut self) -> Result<f64, WireError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+    }
+    fn bool(&mut self) -> Result<bool, WireError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(malformed("bool is neither 0 nor 1")),
+        }
+    }
+    fn id(&mut self) -> Result<u32, WireError> {
+        let id = u32::try_from(self.last_id as i64 + self.signed()?)
+            .map_err(|_| malformed("id is out of range"))?;
+        self.last_id = id;
+        Ok(id)
+    }
+    fn optional_id(&mut self) -> Result<Option<u32>, WireError> {
+        self.bool()?.then(|| self.id()).transpose()
+    }
+    fn ids(&mut self) -> Result<Vec<u32>, WireError> {
+        let len = self.len()?;
+        (0..len).map(|_| self.id()).collect()
+    }
+    fn optional_ids(&mut self) -> Result<Option<Vec<u32>>, WireError> {
+        self.bool()?.then(|| self.ids()).transpose()
+    }
+    fn millis(&mut self) -> Result<f64, WireError> {
+        match self.unsigned()? {
+            1 => self.f64(),
+            tagged if tagged & 1 == 0 => Ok(from_ticks(self.start, unzigzag(tagged >> 1))),
+            _ => Err(malformed("unknown timestamp tag")),
+        }
+    }
+    fn string(&mut self) -> Result<String, WireError> {
+        let index = self.unsigned()?;
+        if let Some(string) = self.strings.get(index as usize) {
+            return Ok(string.clone());
+        }
+        if index != self.strings.len() as u64 {
+            return Err(malformed("string refers past the table"));
+        }
+        let len = self.len()?;
+        let string = std::str::from_utf8(self.take(len)?)
+            .map_err(|err| malformed(&err.to_string()))?
+            .to_string();
+        self.strings.push(string.clone());
+        Ok(string)
+    }
+    fn optional_string(&mut self) -> Result<Option<String>, WireError> {
+        self.bool()?.then(|| self.string()).transpose()
+    }
+    fn mutation(&mut self) -> Result<MutationVariant, WireError> {
+        Ok(match self.byte()? {
+            CHILD_LIST_ADDED => {
+                let mutation = self.child_list()?;
+                let len = self.len()?;
+                let added = (0..len)
+                    .map(|_| Ok((self.id()?, self.node()?)))
+                    .collect::<Result<HashMap<_, _>, WireError>>()?;
+                MutationVariant::ChildListAdded((mutation, added))
+            }
+            CHILD_LIST_REMOVED => MutationVariant::ChildListRemoved(self.child_list()?),
+            CHARACTER_DATA => MutationVariant::CharacterData(MutationCharacterData {
+                millis: self.millis()?,
+                target_id: self.id()?,
+                text_content: self.optional_string()?,
+            }),
+            ATTRIBUTES => MutationVariant::Attributes(MutationAttributes {
+                millis: self.millis()?,
+                target_id: self.id()?,
+                attribute: self
+                    .bool()?
+                    .then(|| Ok::<_, WireError>((self.string()?, self.string()?)))
+                    .transpose()?,
+            }),
+            kind => {
+                return Err(WireError::Malformed(format!(
+                    "unknown mutation kind {kind}"
+                )))
+            }
+        })
+    }
+    fn child_list(&mut self) -> Result<MutationChildList, WireError> {
+        Ok(MutationChildList {
+            millis: self.millis()?,
+            target_id: self.id()?,
+            prev_sibling: self.optional_id()?,
+            next_sibling: self.optional_id()?,
+            nodes: self.ids()?,
+        })
+    }
+    fn node(&mut self) -> Result<SerializedNode, WireError> {
+        let kind = self.byte()?;
+        let Common {
+            id,
+            root_id,
+            is_shadow_host,
+            is_shadow,
+            flags,
+        } = self.common()?;
+        Ok(match kind {
+            DOCUMENT => SerializedNode::DocumentNode(DocumentNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                child_nodes: self.optional_ids()?,
+                compat_mode: self.string()?,
+                viewport: self
+                    .bool()?
+                    .then(|| {
+                        Ok::<_, WireError>(Viewport {
+                            width: self.u32()?,
+                            height: self.u32()?,
+                            device_pixel_ratio: self.f64()?,
+                            scale: self.f64()?,
+                        })
+                    })
+                    .transpose()?,
+                scroll: self.scroll()?,
+            }),
+            ELEMENT => SerializedNode::ElementNode(ElementNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                tag_name: self.string()?,
+                attributes: self
+                    .bool()?
+                    .then(|| {
+                        let len = self.len()?;
+                        (0..len)
+                            .map(|_| Ok((self.string()?, self.string()?)))
+                            .collect::<Result<Vec<_>, WireError>>()
+                    })
+                    .transpose()?,
+                child_nodes: self.optional_ids()?,
+                is_svg: flags & SVG != 0,
+                need_block: flags & NEED_BLOCK != 0,
+                is_custom: flags & CUSTOM != 0,
+                form_state: self.bool()?.then(|| self.form_state()).transpose()?,
+                scroll: self.scroll()?,
+            }),
+            TEXT => SerializedNode::TextNode(TextNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content: self.optional_string()?,
+            }),
+            COMMENT => SerializedNode::CommentNode(CommentNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content: self.optional_string()?,
+            }),
+            CDATA => SerializedNode::CDataNode(CDataNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                text_content: self.optional_string()?,
+            }),
+            DOCUMENT_TYPE => SerializedNode::DocumentTypeNode(DocumentTypeNode {
+                id,
+                root_id,
+                is_shadow_host,
+                is_shadow,
+                name: self.string()?,
+                public_id: self.string()?,
+                system_id: self.string()?,
+            }),
+            kind => return Err(WireError::Malformed(format!("unknown node kind {kind}"))),
+        })
+    }
+    fn common(&mut self) -> Result<Common, WireError> {
+        let id = self.id()?;
+        let root_id = self.id()?;
+        let flags = self.byte()?;
+        Ok(Common {
+            id,
+            root_id,
+            is_shadow_host: flags & SHADOW_HOST != 0,
+            is_shadow: flags & SHADOW != 0,
+            flags,
+        })
+    }
+    fn form_state(&mut self) -> Result<FormState, WireError> {
+        let value = self.optional_string()?;
+        let mut flag = || match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(false)),
+            2 => Ok(Some(true)),
+            _ => Err(malformed("unknown form flag")),
+        };
+        Ok(FormState {
+            value,
+            checked: flag()?,
+            selected: flag()?,
+            selected_index: self.bool()?.then(|| self.i32()).transpose()?,
+        })
+    }
+    fn scroll(&mut self) -> Result<Option<ScrollPosition>, WireError> {
+        self.bool()?
+            .then(|| {
+                Ok(ScrollPosition {
+                    top: self.i32()?,
+                    left: self.i32()?,
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: u32, tag_name: &str, attributes: &[(&str, &str)]) -> SerializedNode {
+        SerializedNode::ElementNode(ElementNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: tag_name.to_string(),
+            attributes: Some(
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+            ),
+            child_nodes: None,
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        })
+    }
+
+    fn attributes(target_id: u32, millis: f64, name: &str, value: &str) -> MutationVariant {
+        MutationVariant::Attributes(MutationAttributes {
+            target_id,
+            millis,
+            attribute: Some((name.to_string(), value.to_string())),
+        })
+    }
+
+    fn round_trip(mutations: &[MutationVariant]) {
+        let decoded = decode(&encode(mutations)).expect("encoded chunk to decode");
+        assert_eq!(decoded, mutations);
+        // and bit for bit, PartialEq lets 0. and -0. pass.
+        for (decoded, mutation) in decoded.iter().zip(mutations) {
+            assert_eq!(decoded.millis().to_bits(), mutation.millis().to_bits());
+        }
+    }
+
+    /// One node of every kind, with every optional field set.
+    fn every_node_kind() -> HashMap<u32, SerializedNode> {
+        let nodes = [
+            SerializedNode::DocumentNode(DocumentNode {
+                id: 10,
+                root_id: 10,
+                is_shadow_host: false,
+                is_shadow: true,
+                child_nodes: Some(vec![11, 12]),
+                compat_mode: "CSS1Compat".to_string(),
+                viewport: Some(Viewport {
+                    width: 1280,
+                    height: 720,
+                    device_pixel_ratio: 1.5,
+                    scale: 0.75,
+                }),
+                scroll: Some(ScrollPosition { top: 40, left: -3 }),
+            }),
+            SerializedNode::DocumentTypeNode(DocumentTypeNode {
+                id: 11,
+                root_id: 10,
+                is_shadow_host: false,
+                is_shadow: false,
+                name: "html".to_string(),
+                public_id: "-//W3C//DTD XHTML 1.0 Strict//EN".to_string(),
+                system_id: String::new(),
+            }),
+            SerializedNode::ElementNode(ElementNode {
+                id: 12,
+                root_id: 10,
+                is_shadow_host: true,
+                is_shadow: false,
+                tag_name: "select".to_string(),
+                attributes: Some(vec![("name".to_string(), "size".to_string())]),
+                child_nodes: Some(Vec::new()),
+                is_svg: true,
+                need_block: true,
+                is_custom: true,
+                form_state: Some(FormState {
+                    value: Some("m".to_string()),
+                    checked: Some(false),
+                    selected: Some(true),
+                    selected_index: Some(-1),
+                }),
+                scroll: Some(ScrollPosition { top: 0, left: 7 }),
+            }),
+            SerializedNode::TextNode(TextNode {
+                id: 13,
+                root_id: 10,
+                is_shadow_host: false,
+                is_shadow: false,
+                text_content: Some("héllo".to_string()),
+            }),
+            SerializedNode::CommentNode(CommentNode {
+                id: 14,
+                root_id: 10,
+                is_shadow_host: false,
+                is_shadow: false,
+                text_content: None,
+            }),
+            SerializedNode::CDataNode(CDataNode {
+                id: 15,
+                root_id: 10,
+                is_shadow_host: false,
+                is_shadow: false,
+                text_content: Some(String::new()),
+            }),
+        ];
+        nodes.into_iter().map(|node| (node.id(), node)).collect()
+    }
+
+    #[test]
+    fn round_trips_every_variant_and_node_kind() {
+        round_trip(&[
+            MutationVariant::ChildListAdded((
+                MutationChildList {
+                    target_id: 3,
+                    millis: 100.,
+                    prev_sibling: Some(4),
+                    next_sibling: None,
+                    nodes: vec![10],
+                },
+                every_node_kind(),
+            )),
+            MutationVariant::ChildListRemoved(MutationChildList {
+                target_id: 3,
+                millis: 100.0001,
+                prev_sibling: None,
+                next_sibling: Some(5),
+                nodes: vec![10, 2],
+            }),
+            MutationVariant::CharacterData(MutationCharacterData {
+                target_id: 13,
+                millis: 101.5,
+                text_content: Some("changed".to_string()),
+            }),
+            MutationVariant::CharacterData(MutationCharacterData {
+                target_id: 13,
+                millis: 102.,
+                text_content: None,
+            }),
+            attributes(12, 103., "class", "open"),
+            MutationVariant::Attributes(MutationAttributes {
+                target_id: 12,
+                millis: 104.,
+                attribute: None,
+            }),
+        ]);
+    }
+
+    #[test]
+    fn round_trips_an_element_without_optional_fields() {
+        let mut node = element(1, "div", &[]);
+        if let SerializedNode::ElementNode(element) = &mut node {
+            element.attributes = None;
+        }
+        round_trip(&[MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id: 0,
+                millis: 1.,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![1],
+            },
+            HashMap::from([(1, node)]),
+        ))]);
+    }
+
+    #[test]
+    fn round_trips_an_empty_chunk() {
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn keeps_timestamps_that_ticks_cant_reproduce() {
+        let start = 1234.5678;
+        round_trip(&[
+            attributes(1, start, "a", "1"),
+            // not a whole number of ticks after start.
+            attributes(1, start + 0.1 + 0.2, "a", "2"),
+            attributes(1, start + 0.000_012_345, "a", "3"),
+            // before the chunk's first mutation.
+            attributes(1, start - 5.25, "a", "4"),
+            attributes(1, -0., "a", "5"),
+            attributes(1, 1e300, "a", "6"),
+            attributes(1, f64::INFINITY, "a", "7"),
+        ]);
+    }
+
+    #[test]
+    fn round_trips_negative_id_deltas() {
+        round_trip(&[
+            attributes(u32::MAX, 1., "a", "b"),
+            attributes(0, 2., "a", "b"),
+            attributes(70_000, 3., "a", "b"),
+            attributes(1, 4., "a", "b"),
+        ]);
+    }
+
+    #[test]
+    fn stores_repeated_strings_once() {
+        let value = "a long attribute value that is repeated";
+        let mutations = (0..10)
+            .map(|index| attributes(index, index as f64, "data-state", value))
+            .collect::<Vec<_>>();
+        round_trip(&mutations);
+        let distinct = (0..10)
+            .map(|index| {
+                attributes(
+                    index,
+                    index as f64,
+                    "data-state",
+                    &format!("{value} {index}"),
+                )
+            })
+            .collect::<Vec<_>>();
+        let repeated = encode(&mutations).len();
+        let unique = encode(&distinct).len();
+        assert!(
+            repeated + 9 * value.len() < unique,
+            "{repeated} bytes with one value, {unique} with ten"
+        );
+        let mut added = HashMap::new();
+        for id in 1..4 {
+            added.insert(
+                id,
+                element(id, "li", &[("class", "item"), ("role", "item")]),
+            );
+        }
+        round_trip(&[MutationVariant::ChildListAdded((
+            MutationChildList {
+                target_id: 0,
+                millis: 0.,
+                prev_sibling: None,
+                next_sibling: None,
+                nodes: vec![1, 2, 3],
+            },
+            added,
+        ))]);
+    }
+
+    #[test]
+    fn refuses_truncated_chunks() {
+        let bytes = encode(&[
+            attributes(5, 1., "class", "open"),
+            MutationVariant::ChildListAdded((
+                MutationChildList {
+                    target_id: 0,
+                    millis: 2.,
+                    prev_sibling: None,
+                    next_sibling: None,
+                    nodes: vec![10],
+                },
+                every_node_kind(),
+            )),
+        ]);
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(decode(&bytes[..len]), Err(WireError::Malformed(_))),
+                "decoded the first {len} of {} bytes",
+                bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_trailing_bytes() {
+        let mut bytes = encode(&[attributes(5, 1., "class", "open")]);
+        bytes.push(0);
+        assert!(matches!(decode(&bytes), Err(WireError::Malformed(_))));
+    }
+
+    #[test]
+    fn refuses_unknown_tags() {
+        let mut bytes = encode(&[attributes(5, 1., "class", "open")]);
+        // the mutation kind follows the count and the start.
+        bytes[1 + 8] = 9;
+        assert!(matches!(decode(&bytes), Err(WireError::Malformed(_))));
+        // a string index past the table.
+        assert!(matches!(
+            decode(&[1, 0, 0, 0, 0, 0, 0, 0, 0, ATTRIBUTES, 0, 0, 1, 5]),
+            Err(WireError::Malformed(_))
+        ));
+    }
+}
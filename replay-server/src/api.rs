@@ -0,0 +1,468 @@
+//! What the session API sends and takes, shared by the server and the pages that call it.
+use std::cmp::Ordering;
+
+use client_capture::{queue::DropCounts, wire::PayloadKind};
+use serde::{Deserialize, Serialize};
+
+/// Where uploads go when the server runs without REPLAY_PROJECTS, its dashboard is served at /.
+pub const DEFAULT_PROJECT: &str = "default";
+
+/// A session without its recording, what the listing and detail endpoints return.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub id: String,
+    /// The page the session started on.
+    pub url: Option<String>,
+    /// Unix millis.
+    pub started_at: f64,
+    pub ended_at: f64,
+    pub duration_millis: f64,
+    /// Page loads, keyframes taken on the same page count once.
+    pub pages: usize,
+    pub clicks: usize,
+    pub errors: usize,
+    pub mutations: usize,
+    pub events: usize,
+    /// What the recorder reported it had to drop, across the session's pages.
+    #[serde(default)]
+    pub dropped: DropCounts,
+    pub keyframes: Vec<KeyframeSummary>,
+    /// The mutation and event uploads in the order they arrived, only listed in the session's detail.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkSummary>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyframeSummary {
+    pub url: Option<String>,
+    pub time_origin: f64,
+    pub mutation_index: usize,
+    pub event_index: usize,
+    pub format: StoredFormat,
+}
+
+/// An upload of mutations or events, the first of them is at index in the session's mutations or events.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkSummary {
+    pub kind: PayloadKind,
+    pub index: usize,
+    pub len: usize,
+    pub format: StoredFormat,
+}
+
+/// The codec an upload was sent in, as its content type, and the wire version the recorder wrote it in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredFormat {
+    pub codec: String,
+    pub version: u16,
+}
+
+/// Query parameters of the session listing. Times are unix millis and durations millis.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SessionFilter {
+    /// Sessions recorded at some point between from and to.
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    /// Part of the URL of a page recorded in the session.
+    pub url: Option<String>,
+    pub min_duration: Option<f64>,
+    pub max_duration: Option<f64>,
+    pub has_errors: Option<bool>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: Order,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    50
+}
+
+/// Pages can't be larger than this, whatever limit asks for.
+pub const MAX_LIMIT: usize = 500;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    StartedAt,
+    Duration,
+    Pages,
+    Clicks,
+    Errors,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortKey {
+    /// How the key is written in the query string.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::StartedAt => "started_at",
+            SortKey::Duration => "duration",
+            SortKey::Pages => "pages",
+            SortKey::Clicks => "clicks",
+            SortKey::Errors => "errors",
+        }
+    }
+    pub fn compare(&self, a: &SessionSummary, b: &SessionSummary) -> Ordering {
+        match self {
+            SortKey::StartedAt => a.started_at.total_cmp(&b.started_at),
+            SortKey::Duration => a.duration_millis.total_cmp(&b.duration_millis),
+            SortKey::Pages => a.pages.cmp(&b.pages),
+            SortKey::Clicks => a.clicks.cmp(&b.clicks),
+            SortKey::Errors => a.errors.cmp(&b.errors),
+        }
+    }
+}
+
+impl Order {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        }
+    }
+    pub fn reversed(&self) -> Self {
+        match self {
+            Order::Asc => Order::Desc,
+            Order::Desc => Order::Asc,
+        }
+    }
+}
+
+impl SessionFilter {
+    pub fn matches(&self, session: &SessionSummary) -> bool {
+        self.from.is_none_or(|from| session.ended_at >= from)
+            && self.to.is_none_or(|to| session.started_at <= to)
+            && self.url.as_ref().is_none_or(|url| {
+                session.keyframes.iter().any(|keyframe| {
+                    keyframe
+                        .url
+                        .as_ref()
+                        .is_some_and(|recorded| recorded.contains(url.as_str()))
+                })
+            })
+            && self
+                .min_duration
+                .is_none_or(|min| session.duration_millis >= min)
+            && self
+                .max_duration
+                .is_none_or(|max| session.duration_millis <= max)
+            && self
+                .has_errors
+                .is_none_or(|has_errors| has_errors == (session.errors > 0))
+    }
+    /// The sessions that match, sorted and cut to the page the filter asks for.
+    pub fn page(&self, sessions: impl IntoIterator<Item = SessionSummary>) -> SessionPage {
+        let mut matching = sessions
+            .into_iter()
+            .filter(|session| self.matches(session))
+            .collect::<Vec<_>>();
+        matching.sort_by(|a, b| match self.order {
+            Order::Asc => self.sort.compare(a, b),
+            Order::Desc => self.sort.compare(b, a),
+        });
+        SessionPage {
+            total: matching.len(),
+            offset: self.offset,
+            sessions: matching
+                .into_iter()
+                .skip(self.offset)
+                .take(self.limit.min(MAX_LIMIT))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionPage {
+    /// How many sessions match the filter, across all pages.
+    pub total: usize,
+    pub offset: usize,
+    pub sessions: Vec<SessionSummary>,
+}
+
+/// What a purge removed from a project, or would remove when it's a dry run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PurgeReport {
+    pub project: String,
+    pub dry_run: bool,
+    pub purged: Vec<PurgedSession>,
+    /// Sessions left in the project and the bytes they hold.
+    pub kept: usize,
+    pub kept_bytes: usize,
+}
+
+impl PurgeReport {
+    pub fn purged_bytes(&self) -> usize {
+        self.purged.iter().map(|session| session.bytes).sum()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PurgedSession {
+    pub id: String,
+    pub reason: PurgeReason,
+    /// Unix millis of the last thing recorded.
+    pub ended_at: f64,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeReason {
+    /// Older than the project's max age.
+    Expired,
+    /// Among the oldest sessions of a project over its max total size.
+    OverSize,
+}
+
+/// A page of a project that was clicked, pages are URLs without their query and fragment.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeatmapPage {
+    pub url: String,
+    pub clicks: usize,
+    pub sessions: usize,
+}
+
+/// Clicks with a target across every session of a page.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub url: String,
+    pub clicks: usize,
+    /// The latest recording of the page, which the clicks are shown over.
+    pub snapshot: Option<HeatmapSnapshot>,
+    /// Clicks by where they landed, most clicked first.
+    pub cells: Vec<HeatCell>,
+    /// Clicks by the element they landed on, most clicked first.
+    pub targets: Vec<TargetClicks>,
+}
+
+/// Where the page can be rendered, see the session html endpoint.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapSnapshot {
+    pub session: String,
+    /// Millis into the session.
+    pub offset: f64,
+    /// The viewport width it was recorded at.
+    pub width: u32,
+}
+
+/// Clicks are binned by the fraction of the viewport width they were at, so recordings of
+/// different widths line up, and by how far down the page they were. Rows are in page pixels at
+/// whatever width was recorded: content reflows to other heights at other widths, and not in
+/// proportion to the width, so rows only line up across recordings of similar widths.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeatCell {
+    /// The cell's column, of HEAT_COLUMNS across the viewport.
+    pub column: u32,
+    /// The cell's row, of HEAT_ROW_PX each from the top of the page.
+    pub row: u32,
+    pub clicks: usize,
+}
+
+pub const HEAT_COLUMNS: u32 = 100;
+pub const HEAT_ROW_PX: u32 = 10;
+/// Steps from an iframe's selector into its document, CSS selectors can't.
+pub const FRAME_SEPARATOR: &str = " >> ";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TargetClicks {
+    /// A CSS selector of the element, by ids and element positions rather than classes, which change with state.
+    /// Elements in an iframe have the iframe's selector and theirs in its document, joined by FRAME_SEPARATOR.
+    pub selector: String,
+    pub clicks: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> SessionFilter {
+        SessionFilter {
+            from: None,
+            to: None,
+            url: None,
+            min_duration: None,
+            max_duration: None,
+            has_errors: None,
+            sort: SortKey::default(),
+            order: Order::default(),
+            offset: 0,
+            limit: default_limit(),
+        }
+    }
+
+    fn session(id: &str, started_at: f64, ended_at: f64, errors: usize) -> SessionSummary {
+        SessionSummary {
+            id: id.to_string(),
+            url: Some(format!("https://shop.example/{id}")),
+            started_at,
+            ended_at,
+            duration_millis: ended_at - started_at,
+            pages: 1,
+            clicks: 0,
+            errors,
+            mutations: 0,
+            events: 0,
+            dropped: DropCounts::default(),
+            keyframes: vec![KeyframeSummary {
+                url: Some(format!("https://shop.example/{id}")),
+                time_origin: started_at,
+                mutation_index: 0,
+                event_index: 0,
+                format: StoredFormat {
+                    codec: "application/octet-stream".to_string(),
+                    version: 1,
+                },
+            }],
+            chunks: Vec::new(),
+        }
+    }
+
+    /// a from 0 to 10s with errors, b from 20s to 25s, c from 30s to 90s.
+    fn sessions() -> Vec<SessionSummary> {
+        vec![
+            session("a", 0., 10_000., 2),
+            session("b", 20_000., 25_000., 0),
+            session("c", 30_000., 90_000., 0),
+        ]
+    }
+
+    fn ids(page: &SessionPage) -> Vec<&str> {
+        page.sessions
+            .iter()
+            .map(|session| session.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn sessions_are_filtered_by_errors() {
+        let with_errors = SessionFilter {
+            has_errors: Some(true),
+            ..filter()
+        };
+        assert_eq!(ids(&with_errors.page(sessions())), ["a"]);
+        let without_errors = SessionFilter {
+            has_errors: Some(false),
+            ..filter()
+        };
+        assert_eq!(ids(&without_errors.page(sessions())), ["c", "b"]);
+    }
+
+    #[test]
+    fn sessions_are_filtered_by_duration() {
+        let long = SessionFilter {
+            min_duration: Some(10_000.),
+            ..filter()
+        };
+        assert_eq!(ids(&long.page(sessions())), ["c", "a"]);
+        let short = SessionFilter {
+            min_duration: Some(5_000.),
+            max_duration: Some(10_000.),
+            ..filter()
+        };
+        assert_eq!(ids(&short.page(sessions())), ["b", "a"]);
+    }
+
+    #[test]
+    fn sessions_recorded_at_some_point_between_from_and_to_match() {
+        let overlapping = SessionFilter {
+            from: Some(10_000.),
+            to: Some(30_000.),
+            ..filter()
+        };
+        // a ends at from and c starts at to, both still overlap.
+        assert_eq!(ids(&overlapping.page(sessions())), ["c", "b", "a"]);
+        let between = SessionFilter {
+            from: Some(15_000.),
+            to: Some(28_000.),
+            ..filter()
+        };
+        assert_eq!(ids(&between.page(sessions())), ["b"]);
+        let after = SessionFilter {
+            from: Some(100_000.),
+            ..filter()
+        };
+        assert!(after.page(sessions()).sessions.is_empty());
+    }
+
+    #[test]
+    fn sessions_are_filtered_by_a_part_of_their_pages_url() {
+        let url = SessionFilter {
+            url: Some("example/b".to_string()),
+            ..filter()
+        };
+        assert_eq!(ids(&url.page(sessions())), ["b"]);
+    }
+
+    #[test]
+    fn sessions_are_newest_first_by_default() {
+        assert_eq!(ids(&filter().page(sessions())), ["c", "b", "a"]);
+        let oldest_first = SessionFilter {
+            order: Order::Asc,
+            ..filter()
+        };
+        assert_eq!(ids(&oldest_first.page(sessions())), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn sessions_are_sorted_by_the_key() {
+        let by_duration = SessionFilter {
+            sort: SortKey::Duration,
+            ..filter()
+        };
+        assert_eq!(ids(&by_duration.page(sessions())), ["c", "a", "b"]);
+        let by_errors = SessionFilter {
+            sort: SortKey::Errors,
+            order: Order::Asc,
+            ..filter()
+        };
+        assert_eq!(ids(&by_errors.page(sessions()))[2], "a");
+    }
+
+    #[test]
+    fn pages_count_every_match_and_hold_the_requested_slice() {
+        let second = SessionFilter {
+            offset: 1,
+            limit: 1,
+            ..filter()
+        };
+        let page = second.page(sessions());
+        assert_eq!(page.total, 3);
+        assert_eq!(page.offset, 1);
+        assert_eq!(ids(&page), ["b"]);
+        let past_the_end = SessionFilter {
+            offset: 5,
+            ..filter()
+        };
+        let page = past_the_end.page(sessions());
+        assert_eq!(page.total, 3);
+        assert!(page.sessions.is_empty());
+    }
+
+    #[test]
+    fn pages_hold_at_most_max_limit() {
+        let many = (0..MAX_LIMIT + 10)
+            .map(|index| session(&index.to_string(), index as f64, index as f64 + 1., 0))
+            .collect::<Vec<_>>();
+        let unlimited = SessionFilter {
+            limit: usize::MAX,
+            ..filter()
+        };
+        let page = unlimited.page(many);
+        assert_eq!(page.total, MAX_LIMIT + 10);
+        assert_eq!(page.sessions.len(), MAX_LIMIT);
+    }
+}
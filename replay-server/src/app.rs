@@ -1,8 +1,17 @@
-use leptos::prelude::*;
+use crate::api::{
+    Heatmap, HeatmapPage, Order, SessionPage, SortKey, TargetClicks, DEFAULT_PROJECT,
+    FRAME_SEPARATOR, HEAT_COLUMNS, HEAT_ROW_PX,
+};
+use client_capture::{
+    live::{spectate, Spectator},
+    player::Player,
+};
+use leptos::{prelude::*, spawn::spawn_local};
 use leptos_meta::{provide_meta_context, MetaTags, Stylesheet, Title};
 use leptos_router::{
     components::{Route, Router, Routes},
-    StaticSegment,
+    hooks::{use_params_map, use_query_map},
+    ParamSegment, StaticSegment,
 };
 
 pub fn shell(options: LeptosOptions) -> impl IntoView {
@@ -33,29 +42,773 @@ pub fn App() -> impl IntoView {
         // id=leptos means cargo-leptos will hot-reload this stylesheet
         <Stylesheet id="leptos" href="/pkg/replay-server.css"/>
 
-        // sets the document title
-        <Title text="Welcome to Leptos"/>
+        <Title text="Session replay"/>
 
-        // content for this welcome page
+        // Pages are under /projects/:project rather than /sessions/:id, sessions are stored per project, the
+        // same id can be recorded under two projects, and reading them takes that project's read key.
         <Router>
             <main>
                 <Routes fallback=|| "Page not found.".into_view()>
-                    <Route path=StaticSegment("") view=HomePage/>
+                    <Route path=StaticSegment("") view=SessionsPage/>
+                    <Route
+                        path=(StaticSegment("projects"), ParamSegment("project"), StaticSegment("sessions"))
+                        view=SessionsPage
+                    />
+                    <Route
+                        path=(StaticSegment("projects"), ParamSegment("project"), StaticSegment("heatmaps"))
+                        view=HeatmapsPage
+                    />
+                    <Route
+                        path=(
+                            StaticSegment("projects"),
+                            ParamSegment("project"),
+                            StaticSegment("sessions"),
+                            ParamSegment("id"),
+                        )
+                        view=PlayerPage
+                    />
+                    <Route
+                        path=(
+                            StaticSegment("projects"),
+                            ParamSegment("project"),
+                            StaticSegment("sessions"),
+                            ParamSegment("id"),
+                            StaticSegment("live"),
+                        )
+                        view=LivePage
+                    />
                 </Routes>
             </main>
         </Router>
     }
 }
 
-/// Renders the home page of your application.
+/// Follows a session while it's recorded, e.g. in another tab. The recorder logs its session id to the console.
+#[component]
+fn LivePage() -> impl IntoView {
+    let params = use_params_map();
+    let project = use_project();
+    let session_id = move || params.read().get("id").unwrap_or_default();
+    let key = use_read_key();
+    let spectator = StoredValue::new_local(None::<Spectator>);
+    // the socket reconnects until it's stopped, so it's stopped when the page goes.
+    let stop = move || {
+        spectator.update_value(|spectator| {
+            if let Some(spectator) = spectator.take() {
+                spectator.stop();
+            }
+        })
+    };
+    on_cleanup(stop);
+    Effect::new(move |_| {
+        stop();
+        let location = window().location();
+        let scheme = match location.protocol().as_deref() {
+            Ok("https:") => "wss:",
+            _ => "ws:",
+        };
+        let url = with_key(
+            format!(
+                "{scheme}//{}/api/projects/{}/sessions/{}/live",
+                location.host().unwrap_or_default(),
+                project(),
+                session_id()
+            ),
+            key.get(),
+        );
+        match spectate("live".to_string(), url) {
+            Ok(following) => spectator.set_value(Some(following)),
+            Err(err) => leptos::logging::error!("couldn't follow session: {err:?}"),
+        }
+    });
+
+    view! {
+        <h1>"Live: " {session_id}</h1>
+        <div style="width: 100%; height: 80vh; overflow: hidden;">
+            <iframe id="live"></iframe>
+        </div>
+    }
+}
+
+/// Plays a recorded session, it's loaded from the session API once the page hydrates.
+#[component]
+fn PlayerPage() -> impl IntoView {
+    let params = use_params_map();
+    let project = use_project();
+    let session_id = move || params.read().get("id").unwrap_or_default();
+    let player = StoredValue::new_local(None::<Player>);
+    let offset = RwSignal::new(0.);
+    let duration = RwSignal::new(0.);
+    let playing = RwSignal::new(false);
+    let error = RwSignal::new(None::<String>);
+    let key = use_read_key();
+    Effect::new(move |_| {
+        let session_url = with_key(
+            format!("/api/projects/{}/sessions/{}", project(), session_id()),
+            key.get(),
+        );
+        spawn_local(async move {
+            match Player::load("player".to_string(), session_url).await {
+                Ok(loaded) => {
+                    duration.set(loaded.duration());
+                    loaded.on_progress(move |at, is_playing| {
+                        offset.set(at);
+                        playing.set(is_playing);
+                    });
+                    player.set_value(Some(loaded));
+                }
+                Err(err) => error.set(Some(err.to_string())),
+            }
+        });
+    });
+    let toggle = move |_| {
+        player.with_value(|player| match player {
+            Some(player) if player.is_playing() => player.pause(),
+            Some(player) => player.play(),
+            None => {}
+        })
+    };
+    let seek = move |ev| {
+        let at = event_target_value(&ev).parse().unwrap_or_default();
+        player.with_value(|player| {
+            if let Some(player) = player {
+                player.seek(at);
+            }
+        })
+    };
+
+    view! {
+        <h1>"Session " {session_id}</h1>
+        <div>
+            <button on:click=toggle>{move || if playing.get() { "Pause" } else { "Play" }}</button>
+            <input
+                type="range"
+                min="0"
+                max=move || duration.get()
+                prop:value=move || offset.get()
+                on:input=seek
+            />
+            <span>{move || format!("{} / {}", clock(offset.get()), clock(duration.get()))}</span>
+        </div>
+        {move || error.get().map(|error| view! { <p>"Couldn't load the session: " {error}</p> })}
+        <div style="width: 100%; height: 80vh; overflow: hidden;">
+            <iframe id="player"></iframe>
+        </div>
+    }
+}
+
+/// Millis as minutes and seconds.
+fn clock(millis: f64) -> String {
+    let seconds = (millis / 1000.) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Sessions shown per page of the dashboard.
+const PAGE_LEN: usize = 50;
+const DAY_MILLIS: f64 = 86_400_000.;
+
+/// Lists recorded sessions with what happened in them, so the one a support ticket is about can be found quickly.
+/// The list is loaded from the session API once the page hydrates, and again whenever a filter changes.
 #[component]
-fn HomePage() -> impl IntoView {
-    // Creates a reactive value to update the button
-    let count = RwSignal::new(0);
-    let on_click = move |_| *count.write() += 1;
+fn SessionsPage() -> impl IntoView {
+    let project = use_project();
+    let url = RwSignal::new(String::new());
+    // "", "true" or "false".
+    let has_errors = RwSignal::new(String::new());
+    // seconds.
+    let min_duration = RwSignal::new(String::new());
+    // dates as yyyy-mm-dd.
+    let from = RwSignal::new(String::new());
+    let to = RwSignal::new(String::new());
+    let sort = RwSignal::new(SortKey::StartedAt);
+    let order = RwSignal::new(Order::Desc);
+    let offset = RwSignal::new(0usize);
+    let page = RwSignal::new(None::<SessionPage>);
+    let error = RwSignal::new(None::<String>);
+    let key = use_read_key();
+    // responses to older requests are ignored, they may arrive after newer ones.
+    let request = StoredValue::new(0u32);
+    Effect::new(move |_| {
+        let mut query = vec![
+            ("sort", sort.get().as_str().to_string()),
+            ("order", order.get().as_str().to_string()),
+            ("offset", offset.get().to_string()),
+            ("limit", PAGE_LEN.to_string()),
+        ];
+        let project = project();
+        if !url.get().is_empty() {
+            query.push(("url", url.get()));
+        }
+        if !has_errors.get().is_empty() {
+            query.push(("has_errors", has_errors.get()));
+        }
+        if let Ok(seconds) = min_duration.get().parse::<f64>() {
+            query.push(("min_duration", (seconds * 1000.).to_string()));
+        }
+        if let Some(from) = date_millis(&from.get()) {
+            query.push(("from", from.to_string()));
+        }
+        if let Some(to) = date_millis(&to.get()) {
+            query.push(("to", (to + DAY_MILLIS).to_string()));
+        }
+        request.update_value(|request| *request += 1);
+        let this_request = request.get_value();
+        let key = key.get();
+        spawn_local(async move {
+            let result = fetch_json(&format!("/api/projects/{project}/sessions"), query, key).await;
+            if request.get_value() != this_request {
+                return;
+            }
+            match result {
+                Ok(loaded) => {
+                    page.set(Some(loaded));
+                    error.set(None);
+                }
+                Err(err) => error.set(Some(err)),
+            }
+        });
+    });
+    // changing a filter starts over at the first page.
+    let filter = move |signal: RwSignal<String>| {
+        move |ev| {
+            signal.set(event_target_value(&ev));
+            offset.set(0);
+        }
+    };
+    let sort_by = move |key: SortKey| {
+        move |_| {
+            if sort.get() == key {
+                order.update(|order| *order = order.reversed());
+            } else {
+                sort.set(key);
+                order.set(Order::Desc);
+            }
+            offset.set(0);
+        }
+    };
+    let header = move |key: SortKey, label: &'static str| {
+        let arrow = move || match (sort.get() == key, order.get()) {
+            (false, _) => "",
+            (true, Order::Asc) => " ▲",
+            (true, Order::Desc) => " ▼",
+        };
+        view! {
+            <th style="cursor: pointer;" on:click=sort_by(key)>
+                {label}
+                {arrow}
+            </th>
+        }
+    };
+    let total = move || page.with(|page| page.as_ref().map_or(0, |page| page.total));
 
     view! {
-        <h1>"Welcome to Leptos!"</h1>
-        <button on:click=on_click>"Click Me: " {count}</button>
+        <h1>"Sessions of " {project}</h1>
+        <a href=move || {
+            with_key(format!("/projects/{}/heatmaps", project()), key.get())
+        }>"Heatmaps"</a>
+        <div>
+            <input type="search" placeholder="URL contains" on:input=filter(url)/>
+            <select on:change=filter(has_errors)>
+                <option value="">"With or without errors"</option>
+                <option value="true">"With errors"</option>
+                <option value="false">"Without errors"</option>
+            </select>
+            <input
+                type="number"
+                min="0"
+                placeholder="Min seconds"
+                on:input=filter(min_duration)
+            />
+            <label>"From " <input type="date" on:change=filter(from)/></label>
+            <label>"To " <input type="date" on:change=filter(to)/></label>
+        </div>
+        {move || error.get().map(|error| view! { <p>"Couldn't load sessions: " {error}</p> })}
+        <table>
+            <thead>
+                <tr>
+                    {header(SortKey::StartedAt, "Started")}
+                    {header(SortKey::Duration, "Duration")}
+                    {header(SortKey::Pages, "Pages")}
+                    {header(SortKey::Clicks, "Clicks")}
+                    {header(SortKey::Errors, "Errors")}
+                    <th>"Entry URL"</th>
+                </tr>
+            </thead>
+            <tbody>
+                {move || {
+                    page.get()
+                        .map(|page| {
+                            page.sessions
+                                .into_iter()
+                                .map(|session| {
+                                    view! {
+                                        <tr>
+                                            <td>
+                                                <a href=with_key(
+                                                    format!(
+                                                        "/projects/{}/sessions/{}",
+                                                        project(),
+                                                        session.id,
+                                                    ),
+                                                    key.get(),
+                                                )>{date_time(session.started_at)}</a>
+                                            </td>
+                                            <td>{clock(session.duration_millis)}</td>
+                                            <td>{session.pages}</td>
+                                            <td>{session.clicks}</td>
+                                            <td>{session.errors}</td>
+                                            <td>{session.url.unwrap_or_default()}</td>
+                                        </tr>
+                                    }
+                                })
+                                .collect_view()
+                        })
+                }}
+            </tbody>
+        </table>
+        <div>
+            <button
+                disabled=move || offset.get() == 0
+                on:click=move |_| offset.update(|offset| *offset = offset.saturating_sub(PAGE_LEN))
+            >
+                "Previous"
+            </button>
+            <span>
+                {move || {
+                    let shown = (offset.get() + PAGE_LEN).min(total());
+                    format!(" {}-{shown} of {} ", (offset.get() + 1).min(shown), total())
+                }}
+            </span>
+            <button
+                disabled=move || offset.get() + PAGE_LEN >= total()
+                on:click=move |_| offset.update(|offset| *offset += PAGE_LEN)
+            >
+                "Next"
+            </button>
+        </div>
+    }
+}
+
+/// The project in the path, / is the default project's dashboard.
+fn use_project() -> impl Fn() -> String + Copy + Send + Sync + 'static {
+    let params = use_params_map();
+    move || {
+        params
+            .read()
+            .get("project")
+            .unwrap_or_else(|| DEFAULT_PROJECT.to_string())
+    }
+}
+
+/// The project's read key, which dashboard pages take as key in their query string. It's passed on in the
+/// query string of links, iframes and sockets, which can't send headers, and as a bearer token otherwise.
+fn use_read_key() -> Memo<Option<String>> {
+    let query = use_query_map();
+    Memo::new(move |_| query.read().get("key"))
+}
+
+/// url with the read key added to its query string.
+fn with_key(url: String, key: Option<String>) -> String {
+    let Some(key) = key else {
+        return url;
+    };
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let key = key
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+    format!("{url}{separator}key={key}")
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(
+    url: &str,
+    query: Vec<(&'static str, String)>,
+    key: Option<String>,
+) -> Result<T, String> {
+    let mut request = gloo_net::http::Request::get(url).query(query);
+    if let Some(key) = key {
+        request = request.header("Authorization", &format!("Bearer {key}"));
+    }
+    let response = request.send().await.map_err(|err| err.to_string())?;
+    if !response.ok() {
+        return Err(format!("server answered {}", response.status()));
+    }
+    response.json().await.map_err(|err| err.to_string())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum HeatmapMode {
+    /// Clicks by where on the page they landed.
+    Coordinates,
+    /// Clicks by the element they landed on, which stays put when the layout around it changes.
+    Elements,
+}
+
+/// An element's box in the rendered page, in px from its top left.
+#[derive(Clone, PartialEq)]
+struct ElementBox {
+    left: f64,
+    top: f64,
+    width: f64,
+    height: f64,
+    clicks: usize,
+}
+
+/// The box of the element target's selector picks out in document, through the iframes it's in.
+fn element_box(document: &web_sys::Document, target: &TargetClicks) -> Option<ElementBox> {
+    use wasm_bindgen::JsCast;
+    let mut document = document.clone();
+    let (mut left, mut top) = (0., 0.);
+    let mut selectors = target.selector.split(FRAME_SEPARATOR).peekable();
+    while let Some(selector) = selectors.next() {
+        let element = document.query_selector(selector).ok()??;
+        let rect = element.get_bounding_client_rect();
+        left += rect.left();
+        top += rect.top();
+        if selectors.peek().is_none() {
+            return Some(ElementBox {
+                left,
+                top,
+                width: rect.width(),
+                height: rect.height(),
+                clicks: target.clicks,
+            });
+        }
+        // the frame's document starts inside its border.
+        left += element.client_left() as f64;
+        top += element.client_top() as f64;
+        document = element
+            .dyn_into::<web_sys::HtmlIFrameElement>()
+            .ok()?
+            .content_document()?;
+    }
+    None
+}
+
+/// Where a page's visitors click, across sessions, shown over the latest recording of the page.
+/// The recording is rendered by the session html endpoint in a same-origin iframe, the clicked elements are
+/// found in it by their selectors once it loads.
+#[component]
+fn HeatmapsPage() -> impl IntoView {
+    let project = use_project();
+    let pages = RwSignal::new(Vec::<HeatmapPage>::new());
+    let url = RwSignal::new(None::<String>);
+    let heatmap = RwSignal::new(None::<Heatmap>);
+    let mode = RwSignal::new(HeatmapMode::Coordinates);
+    let boxes = RwSignal::new(Vec::<ElementBox>::new());
+    // the rendered page's height, the iframe is made as tall so the page doesn't scroll inside it.
+    let height = RwSignal::new(0.);
+    let error = RwSignal::new(None::<String>);
+    let key = use_read_key();
+    // responses to older requests are ignored, they may arrive after newer ones.
+    let request = StoredValue::new(0u32);
+    Effect::new(move |_| {
+        let project = project();
+        let key = key.get();
+        spawn_local(async move {
+            let pages_url = format!("/api/projects/{project}/heatmaps");
+            match fetch_json::<Vec<HeatmapPage>>(&pages_url, Vec::new(), key).await {
+                Ok(loaded) => {
+                    if url.get_untracked().is_none() {
+                        url.set(loaded.first().map(|page| page.url.clone()));
+                    }
+                    pages.set(loaded);
+                }
+                Err(err) => error.set(Some(err)),
+            }
+        });
+    });
+    Effect::new(move |_| {
+        let Some(page_url) = url.get() else {
+            return;
+        };
+        let project = project();
+        request.update_value(|request| *request += 1);
+        let this_request = request.get_value();
+        let key = key.get();
+        spawn_local(async move {
+            let result = fetch_json::<Heatmap>(
+                &format!("/api/projects/{project}/heatmap"),
+                vec![("url", page_url)],
+                key,
+            )
+            .await;
+            if request.get_value() != this_request {
+                return;
+            }
+            match result {
+                Ok(loaded) => {
+                    boxes.set(Vec::new());
+                    heatmap.set(Some(loaded));
+                    error.set(None);
+                }
+                Err(err) => error.set(Some(err)),
+            }
+        });
+    });
+    let snapshot = Memo::new(move |_| {
+        heatmap.with(|heatmap| {
+            heatmap
+                .as_ref()
+                .and_then(|heatmap| heatmap.snapshot.clone())
+        })
+    });
+    let measure = move |ev: web_sys::Event| {
+        let Some(document) = event_target::<web_sys::HtmlIFrameElement>(&ev).content_document()
+        else {
+            return;
+        };
+        if let Some(root) = document.document_element() {
+            height.set(root.scroll_height() as f64);
+        }
+        let targets = heatmap.with_untracked(|heatmap| {
+            heatmap
+                .as_ref()
+                .map(|heatmap| heatmap.targets.clone())
+                .unwrap_or_default()
+        });
+        boxes.set(
+            targets
+                .into_iter()
+                .filter_map(|target| element_box(&document, &target))
+                .collect(),
+        );
+    };
+    // more clicks are more opaque, relative to the most clicked.
+    let heat = |clicks: usize, max: usize| {
+        format!(
+            "rgba(255, 0, 0, {:.2})",
+            0.15 + 0.6 * clicks as f64 / max.max(1) as f64
+        )
+    };
+    let overlay = move || {
+        let heatmap = heatmap.get()?;
+        let snapshot = heatmap.snapshot?;
+        Some(match mode.get() {
+            HeatmapMode::Coordinates => {
+                let column_width = snapshot.width as f64 / HEAT_COLUMNS as f64;
+                let max = heatmap.cells.first().map_or(0, |cell| cell.clicks);
+                heatmap
+                    .cells
+                    .into_iter()
+                    .map(|cell| {
+                        let style = format!(
+                            "position: absolute; left: {}px; top: {}px; width: {column_width}px; height: {HEAT_ROW_PX}px; background: {};",
+                            cell.column as f64 * column_width,
+                            cell.row * HEAT_ROW_PX,
+                            heat(cell.clicks, max),
+                        );
+                        view! { <div style=style></div> }
+                    })
+                    .collect_view()
+            }
+            HeatmapMode::Elements => {
+                let boxes = boxes.get();
+                let max = boxes
+                    .iter()
+                    .map(|element| element.clicks)
+                    .max()
+                    .unwrap_or_default();
+                boxes
+                    .into_iter()
+                    .map(|element| {
+                        let color = heat(element.clicks, max);
+                        let style = format!(
+                            "position: absolute; left: {}px; top: {}px; width: {}px; height: {}px; background: {color}; outline: 2px solid {color};",
+                            element.left, element.top, element.width, element.height,
+                        );
+                        view! { <div style=style></div> }
+                    })
+                    .collect_view()
+            }
+        })
+    };
+    let targets = move || {
+        heatmap
+            .get()
+            .map(|heatmap| heatmap.targets)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|TargetClicks { selector, clicks }| {
+                view! {
+                    <tr>
+                        <td><code>{selector}</code></td>
+                        <td>{clicks}</td>
+                    </tr>
+                }
+            })
+            .collect_view()
+    };
+
+    view! {
+        <h1>"Heatmaps of " {project}</h1>
+        <div>
+            <select on:change=move |ev| url.set(Some(event_target_value(&ev)))>
+                {move || {
+                    pages
+                        .get()
+                        .into_iter()
+                        .map(|page| {
+                            let selected = url.get().as_ref() == Some(&page.url);
+                            view! {
+                                <option value=page.url.clone() selected=selected>
+                                    {format!(
+                                        "{} ({} clicks in {} sessions)",
+                                        page.url,
+                                        page.clicks,
+                                        page.sessions,
+                                    )}
+                                </option>
+                            }
+                        })
+                        .collect_view()
+                }}
+            </select>
+            <label>
+                <input
+                    type="radio"
+                    name="mode"
+                    checked=move || mode.get() == HeatmapMode::Coordinates
+                    on:change=move |_| mode.set(HeatmapMode::Coordinates)
+                />
+                "Coordinates"
+            </label>
+            <label>
+                <input
+                    type="radio"
+                    name="mode"
+                    checked=move || mode.get() == HeatmapMode::Elements
+                    on:change=move |_| mode.set(HeatmapMode::Elements)
+                />
+                "Elements"
+            </label>
+        </div>
+        {move || error.get().map(|error| view! { <p>"Couldn't load the heatmap: " {error}</p> })}
+        {move || {
+            heatmap
+                .get()
+                .filter(|heatmap| heatmap.snapshot.is_none())
+                .map(|_| view! { <p>"There's no recording of the page with its viewport width to show the clicks over."</p> })
+        }}
+        <div style="position: relative; overflow: auto; max-height: 80vh;">
+            {move || {
+                snapshot
+                    .get()
+                    .map(|snapshot| {
+                        view! {
+                            <iframe
+                                src=with_key(
+                                    format!(
+                                        "/api/projects/{}/sessions/{}/html?t={}",
+                                        project(),
+                                        snapshot.session,
+                                        snapshot.offset,
+                                    ),
+                                    key.get(),
+                                )
+                                style=format!("display: block; border: 0; width: {}px;", snapshot.width)
+                                style:height=move || format!("{}px", height.get().max(600.))
+                                on:load=measure
+                            ></iframe>
+                        }
+                    })
+            }}
+            <div style="position: absolute; top: 0; left: 0; pointer-events: none;">{overlay}</div>
+        </div>
+        <table>
+            <thead>
+                <tr>
+                    <th>"Element"</th>
+                    <th>"Clicks"</th>
+                </tr>
+            </thead>
+            <tbody>{targets}</tbody>
+        </table>
+    }
+}
+
+/// Unix millis of midnight UTC at the start of a yyyy-mm-dd date.
+fn date_millis(date: &str) -> Option<f64> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146_097 + day_of_era - 719_468) as f64 * DAY_MILLIS)
+}
+
+/// Unix millis as a yyyy-mm-dd hh:mm UTC date.
+fn date_time(millis: f64) -> String {
+    let days = (millis / DAY_MILLIS).floor() as i64;
+    let minutes = ((millis - days as f64 * DAY_MILLIS) / 60_000.) as i64;
+    // civil from days, the inverse of date_millis.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year}-{month:02}-{day:02} {:02}:{:02}",
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_millis_is_midnight_utc() {
+        assert_eq!(date_millis("1970-01-01"), Some(0.));
+        assert_eq!(date_millis("2024-02-29"), Some(1_709_164_800_000.));
+        assert_eq!(date_millis("2000-03-01"), Some(951_868_800_000.));
+        assert_eq!(date_millis("1969-12-31"), Some(-DAY_MILLIS));
+    }
+
+    #[test]
+    fn date_millis_refuses_other_formats() {
+        assert_eq!(date_millis(""), None);
+        assert_eq!(date_millis("2024-02"), None);
+        assert_eq!(date_millis("2024-feb-01"), None);
+    }
+
+    #[test]
+    fn date_time_formats_utc() {
+        assert_eq!(date_time(0.), "1970-01-01 00:00");
+        assert_eq!(date_time(1_709_210_096_000.), "2024-02-29 12:34");
+        assert_eq!(date_time(-60_000.), "1969-12-31 23:59");
+    }
+
+    #[test]
+    fn date_time_inverts_date_millis() {
+        for date in [
+            "1900-03-01",
+            "1999-12-31",
+            "2000-02-29",
+            "2100-01-01",
+            "2400-12-31",
+        ] {
+            let millis = date_millis(date).unwrap();
+            assert_eq!(date_time(millis), format!("{date} 00:00"));
+            assert_eq!(date_time(millis + DAY_MILLIS - 1.), format!("{date} 23:59"));
+        }
     }
 }
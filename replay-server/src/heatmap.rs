@@ -0,0 +1,488 @@
+//! Where a page's visitors click, aggregated across sessions by position and by the element clicked.
+use std::collections::HashMap;
+
+use client_capture::{vdom::VirtualDom, CaptureEvent, MutationVariant, SerializedNode};
+
+use crate::api::{
+    HeatCell, Heatmap, HeatmapSnapshot, TargetClicks, FRAME_SEPARATOR, HEAT_COLUMNS, HEAT_ROW_PX,
+};
+
+/// The page url is of, the same page with another query or fragment is the same page.
+pub fn page_url(url: &str) -> String {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    url[..end].trim_end_matches('/').to_string()
+}
+
+/// A keyframe with the mutations and events recorded on its page after it.
+pub struct RecordedPage<'a> {
+    pub snapshot: &'a HashMap<u32, SerializedNode>,
+    pub mutations: &'a [MutationVariant],
+    pub events: &'a [(f64, CaptureEvent)],
+}
+
+impl RecordedPage<'_> {
+    /// Clicks with a target, recorders before CaptureEvent::Click didn't record where on the page they were.
+    pub fn click_count(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|(_, event)| matches!(event, CaptureEvent::Click { .. }))
+            .count()
+    }
+    /// The viewport width the snapshot was taken at.
+    pub fn width(&self) -> Option<u32> {
+        match self.snapshot.get(&0)? {
+            SerializedNode::DocumentNode(document) => document.viewport.map(|v| v.width),
+            _ => None,
+        }
+    }
+}
+
+/// A RecordedPage copied out of the session store, so its clicks can be worked out without holding the store's lock.
+pub struct OwnedPage {
+    snapshot: HashMap<u32, SerializedNode>,
+    mutations: Vec<MutationVariant>,
+    events: Vec<(f64, CaptureEvent)>,
+}
+
+impl OwnedPage {
+    pub fn page(&self) -> RecordedPage<'_> {
+        RecordedPage {
+            snapshot: &self.snapshot,
+            mutations: &self.mutations,
+            events: &self.events,
+        }
+    }
+}
+
+impl From<RecordedPage<'_>> for OwnedPage {
+    fn from(page: RecordedPage<'_>) -> Self {
+        Self {
+            snapshot: page.snapshot.clone(),
+            mutations: page.mutations.to_vec(),
+            events: page.events.to_vec(),
+        }
+    }
+}
+
+pub struct Click {
+    /// (column, row) of the cell the click is in, None when the viewport width isn't known.
+    pub cell: Option<(u32, u32)>,
+    /// None when the target isn't an element in the page.
+    pub selector: Option<String>,
+}
+
+/// The page's clicks, each with the element it landed on as the page was when it was clicked.
+pub fn clicks(page: &RecordedPage) -> Vec<Click> {
+    if page.click_count() == 0 {
+        return Vec::new();
+    }
+    let mut width = page.width();
+    let mut mutations = page.mutations.iter().collect::<Vec<_>>();
+    mutations.sort_by(|a, b| a.millis().total_cmp(&b.millis()));
+    let mut mutations = mutations.into_iter().peekable();
+    let mut dom = VirtualDom::new(page.snapshot.clone());
+    let mut clicks = Vec::new();
+    for (millis, event) in page.events {
+        match *event {
+            CaptureEvent::WindowResize { width: resized, .. } => width = Some(resized),
+            CaptureEvent::Click {
+                page_x,
+                page_y,
+                target,
+                ..
+            } => {
+                while let Some(mutation) = mutations.next_if(|m| m.millis() <= *millis) {
+                    // what can't be applied leaves the page as it was, the session's validation reports it.
+                    _ = dom.apply(mutation);
+                }
+                let cell = width.filter(|width| *width > 0).map(|width| {
+                    let column = (page_x.max(0) as f64 / width as f64 * HEAT_COLUMNS as f64) as u32;
+                    // rows aren't scaled by width, see HeatCell.
+                    let row = page_y.max(0) as u32 / HEAT_ROW_PX;
+                    (column.min(HEAT_COLUMNS - 1), row)
+                });
+                clicks.push(Click {
+                    cell,
+                    selector: target.and_then(|target| selector(&dom, target)),
+                });
+            }
+            _ => {}
+        }
+    }
+    clicks
+}
+
+/// A selector of the element id is or is in, from its closest ancestor with an id or the document.
+/// Elements are picked out by position among their siblings of the same tag, classes change with hover and
+/// other state and would split one element's clicks. Elements in an iframe's document are picked out from the
+/// iframe, the same path in the top document is another element.
+pub fn selector(dom: &VirtualDom, id: u32) -> Option<String> {
+    let mut id = id;
+    // clicks on text are on its element.
+    while !matches!(dom.get(id)?, SerializedNode::ElementNode(_)) {
+        id = dom.parent(id)?;
+    }
+    let mut parts = Vec::new();
+    while let Some(SerializedNode::ElementNode(el)) = dom.get(id) {
+        let tag = tag_name(el.is_svg, &el.tag_name);
+        let element_id = el
+            .attributes
+            .iter()
+            .flatten()
+            .find(|(name, value)| name == "id" && !value.is_empty())
+            .map(|(_, value)| value);
+        if let Some(element_id) = element_id {
+            if is_identifier(element_id) {
+                parts.push(format!("{tag}#{element_id}"));
+            } else {
+                parts.push(format!("{tag}[id=\"{}\"]", element_id.replace('"', "\\\"")));
+            }
+            break;
+        }
+        let parent = dom.parent(id);
+        let siblings = parent
+            .map(|parent| {
+                dom.children(parent)
+                    .into_iter()
+                    .filter(|sibling| {
+                        matches!(dom.get(*sibling), Some(SerializedNode::ElementNode(other)) if other.tag_name == el.tag_name)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        match siblings.iter().position(|sibling| *sibling == id) {
+            Some(index) if siblings.len() > 1 => {
+                parts.push(format!("{tag}:nth-of-type({})", index + 1))
+            }
+            _ => parts.push(tag),
+        }
+        match parent {
+            Some(parent) => id = parent,
+            None => break,
+        }
+    }
+    parts.reverse();
+    let selector = parts.join(" > ");
+    match frame_of(dom, id) {
+        Some(frame) => Some(format!(
+            "{}{FRAME_SEPARATOR}{selector}",
+            self::selector(dom, frame)?
+        )),
+        None => Some(selector),
+    }
+}
+
+/// The iframe whose document id is in, None for nodes of the top document.
+fn frame_of(dom: &VirtualDom, id: u32) -> Option<u32> {
+    let mut id = id;
+    while !matches!(dom.get(id)?, SerializedNode::DocumentNode(_)) {
+        id = dom.parent(id)?;
+    }
+    let frame = dom.parent(id)?;
+    matches!(dom.get(frame)?, SerializedNode::ElementNode(_)).then_some(frame)
+}
+
+/// Tags are matched case insensitively in HTML, svg's aren't.
+fn tag_name(is_svg: bool, tag_name: &str) -> String {
+    if is_svg {
+        tag_name.to_string()
+    } else {
+        tag_name.to_lowercase()
+    }
+}
+
+/// Whether value can follow a # in a selector as is.
+fn is_identifier(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '-')
+}
+
+/// Adds up clicks into the page's heatmap.
+pub fn heatmap(
+    url: String,
+    clicks: impl IntoIterator<Item = Click>,
+    snapshot: Option<HeatmapSnapshot>,
+) -> Heatmap {
+    let mut count = 0;
+    let mut cells = HashMap::<(u32, u32), usize>::new();
+    let mut targets = HashMap::<String, usize>::new();
+    for click in clicks {
+        count += 1;
+        if let Some(cell) = click.cell {
+            *cells.entry(cell).or_default() += 1;
+        }
+        if let Some(selector) = click.selector {
+            *targets.entry(selector).or_default() += 1;
+        }
+    }
+    let mut cells = cells
+        .into_iter()
+        .map(|((column, row), clicks)| HeatCell {
+            column,
+            row,
+            clicks,
+        })
+        .collect::<Vec<_>>();
+    cells.sort_by(|a, b| {
+        b.clicks
+            .cmp(&a.clicks)
+            .then(a.row.cmp(&b.row))
+            .then(a.column.cmp(&b.column))
+    });
+    let mut targets = targets
+        .into_iter()
+        .map(|(selector, clicks)| TargetClicks { selector, clicks })
+        .collect::<Vec<_>>();
+    targets.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.selector.cmp(&b.selector)));
+    Heatmap {
+        url,
+        clicks: count,
+        snapshot,
+        cells,
+        targets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use client_capture::{DocumentNode, ElementNode, TextNode, Viewport};
+
+    use super::*;
+
+    fn document(id: u32, children: &[u32], width: Option<u32>) -> SerializedNode {
+        SerializedNode::DocumentNode(DocumentNode {
+            id,
+            root_id: id,
+            is_shadow_host: false,
+            is_shadow: false,
+            child_nodes: Some(children.iter().rev().copied().collect()),
+            compat_mode: "CSS1Compat".to_string(),
+            viewport: width.map(|width| Viewport {
+                width,
+                height: 800,
+                device_pixel_ratio: 1.,
+                scale: 1.,
+            }),
+            scroll: None,
+        })
+    }
+
+    fn element(id: u32, tag_name: &str, element_id: &str, children: &[u32]) -> SerializedNode {
+        let attributes = if element_id.is_empty() {
+            Vec::new()
+        } else {
+            vec![("id".to_string(), element_id.to_string())]
+        };
+        SerializedNode::ElementNode(ElementNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            tag_name: tag_name.to_uppercase(),
+            attributes: Some(attributes),
+            child_nodes: Some(children.iter().rev().copied().collect()),
+            is_svg: false,
+            need_block: false,
+            is_custom: false,
+            form_state: None,
+            scroll: None,
+        })
+    }
+
+    fn text(id: u32) -> SerializedNode {
+        SerializedNode::TextNode(TextNode {
+            id,
+            root_id: 0,
+            is_shadow_host: false,
+            is_shadow: false,
+            text_content: Some("text".to_string()),
+        })
+    }
+
+    /// A page with two divs, an iframe, a button with an id and one with an id that isn't an identifier.
+    /// The iframe's document holds a div at the same path as the page's first.
+    fn snapshot() -> HashMap<u32, SerializedNode> {
+        [
+            document(0, &[1], Some(1000)),
+            element(1, "html", "", &[2]),
+            element(2, "body", "", &[3, 4, 5, 8, 13]),
+            element(3, "div", "", &[6]),
+            element(4, "div", "", &[7]),
+            element(5, "iframe", "", &[9]),
+            text(6),
+            element(7, "a", "", &[]),
+            element(8, "button", "buy", &[]),
+            document(9, &[10], None),
+            element(10, "html", "", &[11]),
+            element(11, "body", "", &[12]),
+            element(12, "div", "", &[]),
+            element(13, "button", "1 \"a\"", &[]),
+        ]
+        .into_iter()
+        .map(|node| (node.id(), node))
+        .collect()
+    }
+
+    fn click(page_x: i32, page_y: i32, target: Option<u32>) -> CaptureEvent {
+        CaptureEvent::Click {
+            x: page_x,
+            y: page_y,
+            page_x,
+            page_y,
+            target,
+        }
+    }
+
+    fn page_clicks(events: &[(f64, CaptureEvent)]) -> Vec<Click> {
+        let snapshot = snapshot();
+        clicks(&RecordedPage {
+            snapshot: &snapshot,
+            mutations: &[],
+            events,
+        })
+    }
+
+    #[test]
+    fn urls_of_the_same_page_are_the_same() {
+        for url in [
+            "https://shop.example/cart",
+            "https://shop.example/cart/",
+            "https://shop.example/cart?item=1",
+            "https://shop.example/cart/?item=1#total",
+            "https://shop.example/cart#total",
+        ] {
+            assert_eq!(page_url(url), "https://shop.example/cart", "{url}");
+        }
+        assert_eq!(page_url("https://shop.example/"), "https://shop.example");
+    }
+
+    #[test]
+    fn elements_are_picked_out_among_siblings_of_their_tag() {
+        let dom = VirtualDom::new(snapshot());
+        assert_eq!(
+            selector(&dom, 3).unwrap(),
+            "html > body > div:nth-of-type(1)"
+        );
+        assert_eq!(
+            selector(&dom, 7).unwrap(),
+            "html > body > div:nth-of-type(2) > a"
+        );
+        // one of its tag needs no position.
+        assert_eq!(selector(&dom, 5).unwrap(), "html > body > iframe");
+    }
+
+    #[test]
+    fn clicks_on_text_are_on_its_element() {
+        let dom = VirtualDom::new(snapshot());
+        assert_eq!(selector(&dom, 6), selector(&dom, 3));
+    }
+
+    #[test]
+    fn selectors_start_at_the_closest_id() {
+        let dom = VirtualDom::new(snapshot());
+        assert_eq!(selector(&dom, 8).unwrap(), "button#buy");
+        assert_eq!(selector(&dom, 13).unwrap(), r#"button[id="1 \"a\""]"#);
+        assert_eq!(selector(&dom, 0), None);
+        assert_eq!(selector(&dom, 99), None);
+    }
+
+    #[test]
+    fn elements_in_an_iframe_are_picked_out_from_the_iframe() {
+        let dom = VirtualDom::new(snapshot());
+        let framed = selector(&dom, 12).unwrap();
+        assert_eq!(
+            framed,
+            format!("html > body > iframe{FRAME_SEPARATOR}html > body > div")
+        );
+        assert_ne!(Some(framed), selector(&dom, 3));
+    }
+
+    #[test]
+    fn clicks_are_placed_in_cells_across_the_viewport() {
+        let clicks = page_clicks(&[
+            (1., click(0, 0, Some(3))),
+            (2., click(505, 25, Some(6))),
+            (3., click(999, 10, None)),
+        ]);
+        let cells = clicks.iter().map(|click| click.cell).collect::<Vec<_>>();
+        assert_eq!(cells, [Some((0, 0)), Some((50, 2)), Some((99, 1))]);
+        let selectors = clicks.iter().map(|click| click.selector.as_deref());
+        assert_eq!(
+            selectors.collect::<Vec<_>>(),
+            [
+                Some("html > body > div:nth-of-type(1)"),
+                Some("html > body > div:nth-of-type(1)"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn clicks_outside_the_viewport_are_clamped_into_the_edge_cells() {
+        let clicks = page_clicks(&[
+            (1., click(1500, 0, None)),
+            (2., click(-20, -5, None)),
+            (3., click(1000, 0, None)),
+        ]);
+        let cells = clicks.iter().map(|click| click.cell).collect::<Vec<_>>();
+        assert_eq!(cells, [Some((99, 0)), Some((0, 0)), Some((99, 0))]);
+    }
+
+    #[test]
+    fn clicks_are_placed_at_the_width_the_page_was_resized_to() {
+        let clicks = page_clicks(&[
+            (1., click(250, 0, None)),
+            (
+                2.,
+                CaptureEvent::WindowResize {
+                    height: 800,
+                    width: 500,
+                },
+            ),
+            (3., click(250, 0, None)),
+        ]);
+        let cells = clicks.iter().map(|click| click.cell).collect::<Vec<_>>();
+        assert_eq!(cells, [Some((25, 0)), Some((50, 0))]);
+    }
+
+    #[test]
+    fn heatmaps_add_up_clicks_by_cell_and_target() {
+        let click = |cell, selector: Option<&str>| Click {
+            cell,
+            selector: selector.map(String::from),
+        };
+        let heatmap = heatmap(
+            "https://shop.example".to_string(),
+            [
+                click(Some((1, 1)), Some("button#buy")),
+                click(Some((2, 0)), Some("button#buy")),
+                click(Some((2, 0)), Some("html > body")),
+                click(None, None),
+            ],
+            None,
+        );
+        assert_eq!(heatmap.clicks, 4);
+        let cells = heatmap
+            .cells
+            .iter()
+            .map(|cell| (cell.column, cell.row, cell.clicks))
+            .collect::<Vec<_>>();
+        assert_eq!(cells, [(2, 0, 2), (1, 1, 1)]);
+        assert_eq!(
+            heatmap.targets,
+            [
+                TargetClicks {
+                    selector: "button#buy".to_string(),
+                    clicks: 2
+                },
+                TargetClicks {
+                    selector: "html > body".to_string(),
+                    clicks: 1
+                },
+            ]
+        );
+    }
+}
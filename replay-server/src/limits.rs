@@ -0,0 +1,400 @@
+//! Limits on what recorders can upload, so one misbehaving client can't exhaust the server, and the rejections
+//! uploads over them are answered with.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::Read,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    extract::rejection::BytesRejection,
+    response::{IntoResponse, Response},
+};
+use client_capture::wire::{IngestError, IngestErrorCode, WireError};
+use flate2::read::GzDecoder;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    HeaderMap, HeaderValue, StatusCode,
+};
+
+use crate::projects::SessionKey;
+
+/// Buckets are pruned once there are this many, keeping those used within the last minute.
+const PRUNE_LEN: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IngestLimits {
+    /// The largest body taken, compressed or decompressed.
+    pub max_body_bytes: usize,
+    /// Uploads a session can send per minute, 0 for no limit.
+    pub session_uploads_per_minute: u32,
+    /// Uploads a client address can send per minute across its sessions, 0 for no limit.
+    pub ip_uploads_per_minute: u32,
+}
+
+impl Default for IngestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 8 * 1024 * 1024,
+            session_uploads_per_minute: 600,
+            ip_uploads_per_minute: 3_000,
+        }
+    }
+}
+
+impl IngestLimits {
+    /// The defaults, overridden by REPLAY_MAX_BODY_BYTES, REPLAY_SESSION_UPLOADS_PER_MINUTE
+    /// and REPLAY_IP_UPLOADS_PER_MINUTE.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        let defaults = Self::default();
+        Self {
+            max_body_bytes: var("REPLAY_MAX_BODY_BYTES").unwrap_or(defaults.max_body_bytes),
+            session_uploads_per_minute: var("REPLAY_SESSION_UPLOADS_PER_MINUTE")
+                .unwrap_or(defaults.session_uploads_per_minute),
+            ip_uploads_per_minute: var("REPLAY_IP_UPLOADS_PER_MINUTE")
+                .unwrap_or(defaults.ip_uploads_per_minute),
+        }
+    }
+}
+
+/// Token buckets that fill at per_minute a minute, up to a minute's worth, so bursts like a page load's
+/// snapshot and first chunks get through.
+struct RateLimiter<K> {
+    per_minute: u32,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Takes a token for key, or returns how many millis until there is one.
+    fn take(&self, key: K) -> Result<(), u32> {
+        self.take_at(key, Instant::now())
+    }
+    fn take_at(&self, key: K, now: Instant) -> Result<(), u32> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = self.per_minute as f64;
+        let per_milli = capacity / 60_000.;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.len() >= PRUNE_LEN {
+            buckets.retain(|_, bucket| now - bucket.updated < Duration::from_secs(60));
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = (now - bucket.updated).as_secs_f64() * 1000.;
+        bucket.tokens = (bucket.tokens + elapsed * per_milli).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1. {
+            return Err(((1. - bucket.tokens) / per_milli).ceil() as u32);
+        }
+        bucket.tokens -= 1.;
+        Ok(())
+    }
+}
+
+/// Shared by every ingest handler.
+pub struct Limiter {
+    pub limits: IngestLimits,
+    sessions: RateLimiter<SessionKey>,
+    ips: RateLimiter<IpAddr>,
+}
+
+impl Limiter {
+    pub fn new(limits: IngestLimits) -> Self {
+        Self {
+            limits,
+            sessions: RateLimiter::new(limits.session_uploads_per_minute),
+            ips: RateLimiter::new(limits.ip_uploads_per_minute),
+        }
+    }
+    /// Counts an upload for the session and the address it came from, or returns how many millis to wait
+    /// before the next one is taken.
+    pub fn take(&self, key: &SessionKey, ip: IpAddr) -> Result<(), u32> {
+        self.ips.take(ip)?;
+        self.sessions.take(key.clone())
+    }
+}
+
+/// Undoes the Content-Encoding the recorder applied to an upload, as long as it stays under max_bytes.
+/// Beacons can't set headers, so bodies without one are taken as is.
+pub fn decode_body(headers: &HeaderMap, body: Bytes, max_bytes: usize) -> Result<Bytes, Rejection> {
+    if body.len() > max_bytes {
+        return Err(Rejection::too_large(max_bytes));
+    }
+    match headers.get(CONTENT_ENCODING).map(|value| value.as_bytes()) {
+        None | Some(b"identity") => Ok(body),
+        Some(b"gzip") => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(body.as_ref())
+                .take(max_bytes as u64 + 1)
+                .read_to_end(&mut decoded)
+                .map_err(|err| {
+                    Rejection::new(
+                        StatusCode::BAD_REQUEST,
+                        IngestErrorCode::Malformed,
+                        err.to_string(),
+                    )
+                })?;
+            if decoded.len() > max_bytes {
+                return Err(Rejection::too_large(max_bytes));
+            }
+            Ok(decoded.into())
+        }
+        Some(encoding) => Err(Rejection::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            IngestErrorCode::UnsupportedMediaType,
+            format!(
+                "unsupported content encoding {}",
+                String::from_utf8_lossy(encoding)
+            ),
+        )),
+    }
+}
+
+/// An upload the server refuses, answered with its status and an IngestError the recorder understands.
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub error: IngestError,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, code: IngestErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: IngestError::new(code, message),
+        }
+    }
+    pub fn too_large(max_bytes: usize) -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            IngestErrorCode::TooLarge,
+            format!("bodies are limited to {max_bytes} bytes"),
+        )
+    }
+    pub fn rate_limited(retry_after_millis: u32) -> Self {
+        let mut rejection = Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            IngestErrorCode::RateLimited,
+            "too many uploads",
+        );
+        rejection.error.retry_after_millis = Some(retry_after_millis);
+        rejection
+    }
+}
+
+impl From<StatusCode> for Rejection {
+    fn from(status: StatusCode) -> Self {
+        let code = match status {
+            StatusCode::BAD_REQUEST => IngestErrorCode::Malformed,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => IngestErrorCode::UnsupportedMediaType,
+            StatusCode::PAYLOAD_TOO_LARGE => IngestErrorCode::TooLarge,
+            StatusCode::TOO_MANY_REQUESTS => IngestErrorCode::RateLimited,
+            StatusCode::UNAUTHORIZED => IngestErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => IngestErrorCode::Forbidden,
+            _ => IngestErrorCode::Internal,
+        };
+        Self::new(status, code, status.canonical_reason().unwrap_or_default())
+    }
+}
+
+impl From<WireError> for Rejection {
+    fn from(error: WireError) -> Self {
+        match error {
+            WireError::UnknownContentType(_) => Self::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                IngestErrorCode::UnsupportedMediaType,
+                error.to_string(),
+            ),
+            _ => Self::new(
+                StatusCode::BAD_REQUEST,
+                IngestErrorCode::Malformed,
+                error.to_string(),
+            ),
+        }
+    }
+}
+
+/// axum's own rejections, like a body over the DefaultBodyLimit.
+impl From<BytesRejection> for Rejection {
+    fn from(rejection: BytesRejection) -> Self {
+        let mut from_status = Self::from(rejection.status());
+        from_status.error.message = rejection.body_text();
+        from_status
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status,
+            [(CONTENT_TYPE, "application/json")],
+            self.error.encode(),
+        )
+            .into_response();
+        if let Some(millis) = self.error.retry_after_millis {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(millis.div_ceil(1000)));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn key(session: &str) -> SessionKey {
+        SessionKey {
+            project: "shop".to_string(),
+            session: session.to_string(),
+        }
+    }
+
+    fn gzip(bytes: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    fn encoded(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+        headers
+    }
+
+    #[test]
+    fn buckets_take_a_burst_then_wait_for_a_token() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..60 {
+            assert_eq!(limiter.take_at("client", start), Ok(()));
+        }
+        assert_eq!(limiter.take_at("client", start), Err(1_000));
+        assert_eq!(
+            limiter.take_at("client", start + Duration::from_millis(400)),
+            Err(600)
+        );
+        // other keys have their own bucket.
+        assert_eq!(limiter.take_at("other", start), Ok(()));
+    }
+
+    #[test]
+    fn buckets_refill_over_time_up_to_a_minutes_worth() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..60 {
+            limiter.take_at("client", start).unwrap();
+        }
+        let later = start + Duration::from_secs(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.take_at("client", later), Ok(()));
+        }
+        assert!(limiter.take_at("client", later).is_err());
+        let idle = later + Duration::from_secs(3_600);
+        for _ in 0..60 {
+            assert_eq!(limiter.take_at("client", idle), Ok(()));
+        }
+        assert!(limiter.take_at("client", idle).is_err());
+    }
+
+    #[test]
+    fn zero_is_no_limit() {
+        let limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        for _ in 0..1_000 {
+            assert_eq!(limiter.take_at("client", now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn uploads_count_against_their_session_and_address() {
+        let limiter = Limiter::new(IngestLimits {
+            session_uploads_per_minute: 2,
+            ip_uploads_per_minute: 3,
+            ..IngestLimits::default()
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(limiter.take(&key("a"), ip), Ok(()));
+        assert_eq!(limiter.take(&key("a"), ip), Ok(()));
+        assert!(limiter.take(&key("a"), ip).is_err());
+        // the address spent its third token on the session's refused upload.
+        assert!(limiter.take(&key("b"), ip).is_err());
+        assert_eq!(
+            limiter.take(&key("b"), IpAddr::from([127, 0, 0, 2])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn bodies_are_taken_as_is_without_an_encoding() {
+        let body = Bytes::from_static(b"payload");
+        for headers in [HeaderMap::new(), encoded("identity")] {
+            assert_eq!(decode_body(&headers, body.clone(), 7).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn gzip_bodies_are_decoded() {
+        let decoded = decode_body(&encoded("gzip"), gzip(b"payload"), 100).unwrap();
+        assert_eq!(decoded.as_ref(), b"payload");
+    }
+
+    #[test]
+    fn oversized_bodies_are_too_large() {
+        let body = Bytes::from_static(b"payload");
+        let rejection = decode_body(&HeaderMap::new(), body, 6).unwrap_err();
+        assert_eq!(rejection.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(rejection.error.code, IngestErrorCode::TooLarge);
+        // what counts is the size decoded, a small gzip body can inflate past the limit.
+        let bomb = gzip(&[0; 10_000]);
+        assert!(bomb.len() < 1_000);
+        let rejection = decode_body(&encoded("gzip"), bomb, 1_000).unwrap_err();
+        assert_eq!(rejection.status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn bad_gzip_is_malformed() {
+        let body = Bytes::from_static(b"not gzip");
+        let rejection = decode_body(&encoded("gzip"), body, 100).unwrap_err();
+        assert_eq!(rejection.status, StatusCode::BAD_REQUEST);
+        assert_eq!(rejection.error.code, IngestErrorCode::Malformed);
+    }
+
+    #[test]
+    fn unknown_encodings_and_content_types_are_unsupported() {
+        let body = Bytes::from_static(b"payload");
+        let rejection = decode_body(&encoded("br"), body, 100).unwrap_err();
+        assert_eq!(rejection.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(rejection.error.code, IngestErrorCode::UnsupportedMediaType);
+        let error = client_capture::wire::decode_snapshot(b"payload", Some("text/plain"));
+        let rejection = Rejection::from(error.unwrap_err());
+        assert_eq!(rejection.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(rejection.error.code, IngestErrorCode::UnsupportedMediaType);
+    }
+}
@@ -1,39 +1,1105 @@
 #[cfg(feature = "ssr")]
 pub mod server {
     pub use axum::body::Bytes;
-    pub use axum::routing::post;
+    pub use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+    pub use axum::extract::{
+        rejection::BytesRejection, ConnectInfo, DefaultBodyLimit, Path, Query, Request,
+    };
+    pub use axum::middleware::{self, Next};
+    pub use axum::response::{IntoResponse, Response};
+    pub use axum::routing::{get, post};
     pub use axum::{Extension, Json, Router};
-    pub use client_capture::{MutationVariant, SerializedNode};
-    pub use http::StatusCode;
+    pub use client_capture::{
+        html,
+        queue::DropCounts,
+        validate::{validate, KeyframeRef, Recording, ValidationReport},
+        vdom::VirtualDom,
+        wire::{
+            codec_for_content_type, decode_events, decode_mutations, decode_snapshot,
+            payload_format, payload_kind, Bincode, Codec, Json as JsonCodec, PayloadFormat,
+            PayloadKind, SocketAck, SocketFrame, WireError, DROPPED_HEADER, PAGE_URL_HEADER,
+            SEQ_HEADER, SESSION_HEADER, TIME_ORIGIN_HEADER,
+        },
+        CaptureEvent, MutationVariant, SerializedNode,
+    };
+    pub use http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CONTENT_ENCODING, CONTENT_SECURITY_POLICY, CONTENT_TYPE, ORIGIN,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    };
     pub use leptos::prelude::*;
     pub use leptos_axum::{generate_route_list, LeptosRoutes};
-    pub use replay_server::app::*;
+    pub use replay_server::{
+        api::*,
+        app::*,
+        heatmap::{self, OwnedPage, RecordedPage},
+        limits::{decode_body, IngestLimits, Limiter, Rejection},
+        metrics::{self, Metrics},
+        projects::{Projects, SessionKey},
+        retention::{self, PurgeSchedule, StoredSession},
+    };
+    pub use serde::Deserialize;
     pub use std::collections::HashMap;
+    pub use std::net::SocketAddr;
     pub use std::sync::{Arc, RwLock};
+    pub use std::time::{SystemTime, UNIX_EPOCH};
+    pub use tokio::sync::broadcast::{self, error::RecvError};
+    pub use tower_http::cors::{AllowOrigin, CorsLayer};
 
-    pub async fn ingest_snapshot(
-        Extension(state): Extension<Arc<RwLock<HashMap<u32, SerializedNode>>>>,
-        body: Bytes,
-    ) -> Result<(), StatusCode> {
-        let body = bincode::deserialize(&body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        *state
+    /// Every recording by project, then by the recorder's session id.
+    pub type Sessions = Arc<RwLock<HashMap<String, HashMap<String, Session>>>>;
+
+    /// How many payloads a live viewer can fall behind before it's caught up from the latest keyframe again.
+    const LIVE_CAPACITY: usize = 256;
+
+    pub struct Session {
+        /// A snapshot for every page load in the session, oldest first.
+        pub keyframes: Vec<Keyframe>,
+        pub mutations: Vec<MutationVariant>,
+        /// The unix millis each mutation was recorded at.
+        pub mutation_times: Vec<f64>,
+        /// Capture events with the millis they were captured at.
+        pub events: Vec<(f64, CaptureEvent)>,
+        /// The unix millis each event was recorded at.
+        pub event_times: Vec<f64>,
+        /// The unix millis of the first and last thing recorded.
+        pub span: Option<(f64, f64)>,
+        /// The unix millis the session was created at, retention goes by it until something is recorded.
+        pub created_at: f64,
+        /// Uncaught errors among the events.
+        pub errors: usize,
+        pub clicks: usize,
+        /// What the recorder reported it dropped, summed over its reports.
+        pub dropped: DropCounts,
+        /// The decompressed size of every upload stored in the session, what retention limits.
+        pub bytes: usize,
+        /// The sequence number of every upload that had one, in the order they arrived.
+        pub seqs: Vec<u32>,
+        /// Every mutation and event upload, in the order they arrived.
+        pub chunks: Vec<Chunk>,
+        /// Every payload the session receives is forwarded to its live viewers, bincode encoded.
+        pub live: broadcast::Sender<Bytes>,
+    }
+
+    pub struct Keyframe {
+        pub snapshot: HashMap<u32, SerializedNode>,
+        /// The page the snapshot was taken of, recorders from before PAGE_URL_HEADER don't send it.
+        pub url: Option<String>,
+        /// The unix millis the page's recorded millis count from. Without a TIME_ORIGIN_HEADER
+        /// it's when the snapshot arrived, which is close since snapshots are taken on load.
+        pub time_origin: f64,
+        /// How many of the session's mutations and events arrived before this keyframe.
+        pub mutation_index: usize,
+        pub event_index: usize,
+        pub format: PayloadFormat,
+    }
+
+    /// Where an upload's mutations or events went and the format they were sent in.
+    pub struct Chunk {
+        pub kind: PayloadKind,
+        /// Where its first mutation or event is in the session's.
+        pub index: usize,
+        pub len: usize,
+        pub format: PayloadFormat,
+    }
+
+    fn stored_format(format: PayloadFormat) -> StoredFormat {
+        StoredFormat {
+            codec: format.codec.to_string(),
+            version: format.version,
+        }
+    }
+
+    impl Default for Session {
+        fn default() -> Self {
+            Self {
+                keyframes: Vec::new(),
+                mutations: Vec::new(),
+                mutation_times: Vec::new(),
+                events: Vec::new(),
+                event_times: Vec::new(),
+                span: None,
+                created_at: now_millis(),
+                errors: 0,
+                clicks: 0,
+                dropped: DropCounts::default(),
+                bytes: 0,
+                seqs: Vec::new(),
+                chunks: Vec::new(),
+                live: broadcast::channel(LIVE_CAPACITY).0,
+            }
+        }
+    }
+
+    impl Session {
+        /// Recorded millis count from the latest keyframe's time origin. Anything that arrives before the first keyframe
+        /// is placed as if it had just been recorded.
+        fn time(&self, millis: f64) -> f64 {
+            self.keyframes
+                .last()
+                .map(|keyframe| keyframe.time_origin)
+                .unwrap_or_else(|| now_millis() - millis)
+                + millis
+        }
+        fn extend_span(&mut self, time: f64) {
+            let (start, end) = self.span.get_or_insert((time, time));
+            *start = start.min(time);
+            *end = end.max(time);
+        }
+        /// Sessions a viewer opened but nothing was recorded for yet.
+        fn is_empty(&self) -> bool {
+            self.keyframes.is_empty() && self.mutations.is_empty() && self.events.is_empty()
+        }
+        pub fn summary(&self, id: &str) -> SessionSummary {
+            let (started_at, ended_at) = self.span.unwrap_or_default();
+            SessionSummary {
+                id: id.to_string(),
+                url: self
+                    .keyframes
+                    .iter()
+                    .find_map(|keyframe| keyframe.url.clone()),
+                started_at,
+                ended_at,
+                duration_millis: ended_at - started_at,
+                pages: self
+                    .keyframes
+                    .windows(2)
+                    .filter(|pair| pair[0].time_origin != pair[1].time_origin)
+                    .count()
+                    + usize::from(!self.keyframes.is_empty()),
+                clicks: self.clicks,
+                errors: self.errors,
+                mutations: self.mutations.len(),
+                events: self.events.len(),
+                dropped: self.dropped,
+                keyframes: self
+                    .keyframes
+                    .iter()
+                    .map(|keyframe| KeyframeSummary {
+                        url: keyframe.url.clone(),
+                        time_origin: keyframe.time_origin,
+                        mutation_index: keyframe.mutation_index,
+                        event_index: keyframe.event_index,
+                        format: stored_format(keyframe.format),
+                    })
+                    .collect(),
+                chunks: Vec::new(),
+            }
+        }
+        /// The summary with every upload's format listed.
+        pub fn detail(&self, id: &str) -> SessionSummary {
+            SessionSummary {
+                chunks: self
+                    .chunks
+                    .iter()
+                    .map(|chunk| ChunkSummary {
+                        kind: chunk.kind,
+                        index: chunk.index,
+                        len: chunk.len,
+                        format: stored_format(chunk.format),
+                    })
+                    .collect(),
+                ..self.summary(id)
+            }
+        }
+        /// The page offset millis into the session, counted from the first keyframe like the player does,
+        /// with the keyframe it was rebuilt from.
+        pub fn dom_at(&self, offset: f64) -> Option<(&Keyframe, VirtualDom)> {
+            let time = self.keyframes.first()?.time_origin + offset;
+            let index = self
+                .keyframes
+                .iter()
+                .rposition(|keyframe| keyframe.time_origin <= time)
+                .unwrap_or_default();
+            let keyframe = &self.keyframes[index];
+            let mutation_end = self
+                .keyframes
+                .get(index + 1)
+                .map_or(self.mutations.len(), |next| next.mutation_index);
+            let (dom, _) = VirtualDom::at(
+                keyframe.snapshot.clone(),
+                &self.mutations[keyframe.mutation_index..mutation_end],
+                time - keyframe.time_origin,
+            );
+            Some((keyframe, dom))
+        }
+        /// The keyframe at index with the mutations and events recorded on its page.
+        pub fn page(&self, index: usize) -> RecordedPage<'_> {
+            let keyframe = &self.keyframes[index];
+            let next = self.keyframes.get(index + 1);
+            RecordedPage {
+                snapshot: &keyframe.snapshot,
+                mutations: &self.mutations[keyframe.mutation_index
+                    ..next.map_or(self.mutations.len(), |next| next.mutation_index)],
+                events: &self.events
+                    [keyframe.event_index..next.map_or(self.events.len(), |next| next.event_index)],
+            }
+        }
+        pub fn validate(&self) -> ValidationReport {
+            validate(&Recording {
+                keyframes: self
+                    .keyframes
+                    .iter()
+                    .map(|keyframe| KeyframeRef {
+                        snapshot: &keyframe.snapshot,
+                        time_origin: keyframe.time_origin,
+                        mutation_index: keyframe.mutation_index,
+                        event_index: keyframe.event_index,
+                    })
+                    .collect(),
+                mutations: &self.mutations,
+                events: &self.events,
+                seqs: &self.seqs,
+            })
+        }
+        /// Counts an upload toward bytes and records its sequence number.
+        fn stored(&mut self, headers: &HeaderMap, bytes: usize) {
+            self.bytes += bytes;
+            if let Some(seq) = header(headers, SEQ_HEADER).and_then(|seq| seq.parse().ok()) {
+                self.seqs.push(seq);
+            }
+        }
+        /// Sends payload to live viewers, if there are any.
+        fn broadcast(&self, payload: impl FnOnce() -> Vec<u8>) {
+            if self.live.receiver_count() > 0 {
+                _ = self.live.send(payload().into());
+            }
+        }
+        /// What a viewer needs to rebuild the page as it is now, the latest keyframe and the mutations since.
+        fn catch_up(&self) -> Vec<Bytes> {
+            let Some(keyframe) = self.keyframes.last() else {
+                return Vec::new();
+            };
+            let mut payloads = vec![Bincode.encode_snapshot(&keyframe.snapshot).into()];
+            let mutations = &self.mutations[keyframe.mutation_index..];
+            if !mutations.is_empty() {
+                payloads.push(Bincode.encode_mutations(mutations).into());
+            }
+            payloads
+        }
+    }
+
+    /// Recorders put their session id in the query string so beacons, which can't set headers, carry it too.
+    /// Uploads from recorders without one go to the "default" session.
+    pub fn session_id(query: &HashMap<String, String>) -> String {
+        query
+            .get("session")
+            .cloned()
+            .unwrap_or_else(|| "default".to_string())
+    }
+
+    /// The project and session an upload belongs to. The API key is in the query string next to the session id.
+    pub fn session_key(
+        projects: &Projects,
+        query: &HashMap<String, String>,
+        headers: &HeaderMap,
+    ) -> Result<SessionKey, Rejection> {
+        let project = projects.authorize(
+            query.get("key").map(String::as_str),
+            header(headers, ORIGIN.as_str()),
+        )?;
+        Ok(SessionKey {
+            project,
+            session: session_id(query),
+        })
+    }
+
+    /// The session to store an upload in, created by its first upload.
+    fn session_mut(
+        sessions: &mut HashMap<String, HashMap<String, Session>>,
+        key: SessionKey,
+    ) -> &mut Session {
+        sessions
+            .entry(key.project)
+            .or_default()
+            .entry(key.session)
+            .or_default()
+    }
+
+    fn session<'a>(
+        sessions: &'a HashMap<String, HashMap<String, Session>>,
+        project: &str,
+        session_id: &str,
+    ) -> Option<&'a Session> {
+        sessions.get(project)?.get(session_id)
+    }
+
+    /// The codec is negotiated by Content-Type, without one the codec is worked out from the body.
+    pub fn content_type(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    pub fn now_millis() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as f64
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn store_snapshot(
+        sessions: &Sessions,
+        metrics: &Metrics,
+        key: SessionKey,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), Rejection> {
+        let snapshot = decode_snapshot(body, content_type(headers))?;
+        let format = payload_format(body, content_type(headers), PayloadKind::Snapshot)?;
+        let time_origin = header(headers, TIME_ORIGIN_HEADER)
+            .and_then(|origin| origin.parse().ok())
+            .unwrap_or_else(now_millis);
+        let mut sessions = sessions
             .write()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? = body;
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let url = header(headers, PAGE_URL_HEADER).map(String::from);
+        metrics.record(|| {
+            let now = now_millis();
+            let mut points = vec![metrics::chunk(
+                &key,
+                "snapshot",
+                body.len(),
+                snapshot.len(),
+                now,
+            )];
+            let is_new_page =
+                session(&sessions, &key.project, &key.session).is_none_or(|session| {
+                    session
+                        .keyframes
+                        .last()
+                        .is_none_or(|keyframe| keyframe.time_origin != time_origin)
+                });
+            if is_new_page {
+                points.push(metrics::page_view(&key, url.as_deref(), now));
+            }
+            points
+        });
+        let session = session_mut(&mut sessions, key);
+        session.broadcast(|| Bincode.encode_snapshot(&snapshot));
+        let keyframe = Keyframe {
+            snapshot,
+            url,
+            time_origin,
+            mutation_index: session.mutations.len(),
+            event_index: session.events.len(),
+            format,
+        };
+        session.extend_span(time_origin);
+        session.keyframes.push(keyframe);
+        session.stored(headers, body.len());
         Ok(())
     }
 
-    pub async fn ingest_mutation(
-        Extension(state): Extension<Arc<RwLock<Vec<MutationVariant>>>>,
-        body: Bytes,
-    ) -> Result<(), StatusCode> {
-        let body = bincode::deserialize::<Vec<MutationVariant>>(&body)
+    pub fn store_mutations(
+        sessions: &Sessions,
+        metrics: &Metrics,
+        key: SessionKey,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), Rejection> {
+        let mutations = decode_mutations(body, content_type(headers))?;
+        let format = payload_format(body, content_type(headers), PayloadKind::Mutations)?;
+        let mut sessions = sessions
+            .write()
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        state
+        metrics.record(|| {
+            let now = now_millis();
+            vec![
+                metrics::chunk(&key, "mutations", body.len(), mutations.len(), now),
+                metrics::mutations(&key, &mutations, now),
+            ]
+        });
+        let session = session_mut(&mut sessions, key);
+        session.broadcast(|| Bincode.encode_mutations(&mutations));
+        session.stored(headers, body.len());
+        session.chunks.push(Chunk {
+            kind: PayloadKind::Mutations,
+            index: session.mutations.len(),
+            len: mutations.len(),
+            format,
+        });
+        for mutation in mutations {
+            let time = session.time(mutation.millis());
+            session.extend_span(time);
+            session.mutation_times.push(time);
+            session.mutations.push(mutation);
+        }
+        Ok(())
+    }
+
+    pub fn store_events(
+        sessions: &Sessions,
+        metrics: &Metrics,
+        key: SessionKey,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<(), Rejection> {
+        let events = decode_events(body, content_type(headers))?;
+        let format = payload_format(body, content_type(headers), PayloadKind::Events)?;
+        let mut sessions = sessions
             .write()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .extend(body);
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        metrics.record(|| {
+            let now = now_millis();
+            let mut points = vec![metrics::chunk(
+                &key,
+                "events",
+                body.len(),
+                events.len(),
+                now,
+            )];
+            points.extend(metrics::events(&key, &events, now));
+            points
+        });
+        let session = session_mut(&mut sessions, key);
+        session.broadcast(|| Bincode.encode_events(&events));
+        session.stored(headers, body.len());
+        if let Some(dropped) = header(headers, DROPPED_HEADER)
+            .and_then(|dropped| serde_json::from_str::<DropCounts>(dropped).ok())
+        {
+            session.dropped.add(&dropped);
+        }
+        session.chunks.push(Chunk {
+            kind: PayloadKind::Events,
+            index: session.events.len(),
+            len: events.len(),
+            format,
+        });
+        for (millis, event) in events {
+            let time = session.time(millis);
+            session.extend_span(time);
+            match event {
+                CaptureEvent::Error { .. } => session.errors += 1,
+                CaptureEvent::MouseClick { .. } | CaptureEvent::Click { .. } => session.clicks += 1,
+                _ => {}
+            }
+            session.event_times.push(time);
+            session.events.push((millis, event));
+        }
         Ok(())
     }
+
+    /// Beacons can't set headers, they carry their sequence number in the query string instead.
+    fn with_query_seq(query: &HashMap<String, String>, mut headers: HeaderMap) -> HeaderMap {
+        if let Some(seq) = query
+            .get("seq")
+            .and_then(|seq| HeaderValue::from_str(seq).ok())
+        {
+            headers.entry(SEQ_HEADER).or_insert(seq);
+        }
+        headers
+    }
+
+    /// What the ingest handlers share.
+    #[derive(Clone)]
+    pub struct Ingest {
+        pub sessions: Sessions,
+        pub metrics: Metrics,
+        pub projects: Projects,
+        pub limiter: Arc<Limiter>,
+    }
+
+    impl Ingest {
+        /// Checks who an upload is from and whether they may send it, and decodes its body.
+        pub fn admit(
+            &self,
+            address: SocketAddr,
+            query: &HashMap<String, String>,
+            headers: &HeaderMap,
+            body: Bytes,
+        ) -> Result<(SessionKey, Bytes), Rejection> {
+            let key = session_key(&self.projects, query, headers)?;
+            self.limiter
+                .take(&key, address.ip())
+                .map_err(Rejection::rate_limited)?;
+            let body = decode_body(headers, body, self.limiter.limits.max_body_bytes)?;
+            Ok((key, body))
+        }
+    }
+
+    pub async fn ingest_snapshot(
+        Extension(ingest): Extension<Ingest>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Result<Bytes, BytesRejection>,
+    ) -> Result<(), Rejection> {
+        let headers = with_query_seq(&query, headers);
+        let (key, body) = ingest.admit(address, &query, &headers, body?)?;
+        store_snapshot(&ingest.sessions, &ingest.metrics, key, &headers, &body)
+    }
+
+    pub async fn ingest_mutation(
+        Extension(ingest): Extension<Ingest>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Result<Bytes, BytesRejection>,
+    ) -> Result<(), Rejection> {
+        let headers = with_query_seq(&query, headers);
+        let (key, body) = ingest.admit(address, &query, &headers, body?)?;
+        store_mutations(&ingest.sessions, &ingest.metrics, key, &headers, &body)
+    }
+
+    pub async fn ingest_events(
+        Extension(ingest): Extension<Ingest>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Result<Bytes, BytesRejection>,
+    ) -> Result<(), Rejection> {
+        let headers = with_query_seq(&query, headers);
+        let (key, body) = ingest.admit(address, &query, &headers, body?)?;
+        store_events(&ingest.sessions, &ingest.metrics, key, &headers, &body)
+    }
+
+    /// The recorder's WebSocket transport. Every SocketFrame is stored like the upload it wraps
+    /// would have been and answered with a SocketAck carrying the status it would have gotten.
+    /// The api key is checked once, when the socket connects, each frame names its own session.
+    pub async fn ingest_socket(
+        upgrade: WebSocketUpgrade,
+        Extension(ingest): Extension<Ingest>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+    ) -> Response {
+        let key = match session_key(&ingest.projects, &query, &headers) {
+            Ok(key) => key,
+            Err(rejection) => return rejection.into_response(),
+        };
+        // frames over the limit are answered with 413 rather than closing the socket, as long as they're not huge.
+        let upgrade = upgrade.max_message_size(ingest.limiter.limits.max_body_bytes * 2);
+        upgrade.on_upgrade(move |mut socket: WebSocket| async move {
+            while let Some(Ok(message)) = socket.recv().await {
+                let Message::Binary(bytes) = message else {
+                    continue;
+                };
+                let ack = match SocketFrame::decode(&bytes) {
+                    Ok(frame) => {
+                        let seq = frame.seq;
+                        let key = frame_key(&frame, &key);
+                        let result = ingest
+                            .limiter
+                            .take(&key, address.ip())
+                            .map_err(Rejection::rate_limited)
+                            .and_then(|()| ingest_frame(frame, &ingest, key));
+                        match result {
+                            Ok(()) => SocketAck {
+                                seq,
+                                status: StatusCode::OK.as_u16(),
+                                retry_after_millis: 0,
+                            },
+                            Err(rejection) => SocketAck {
+                                seq,
+                                status: rejection.status.as_u16(),
+                                retry_after_millis: rejection
+                                    .error
+                                    .retry_after_millis
+                                    .unwrap_or_default(),
+                            },
+                        }
+                    }
+                    // a frame starts with its seq, so one that's cut short or garbled after it can still be refused.
+                    Err(_) => match bytes.get(..4) {
+                        Some(seq) => SocketAck {
+                            seq: u32::from_le_bytes(seq.try_into().expect("4 bytes")),
+                            status: StatusCode::BAD_REQUEST.as_u16(),
+                            retry_after_millis: 0,
+                        },
+                        // there's nothing to answer, and a recorder sending these isn't one this server speaks with.
+                        None => break,
+                    },
+                };
+                if socket.send(Message::Binary(ack.encode())).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// The session a frame belongs to, recorders from before SESSION_HEADER send only their own session's.
+    fn frame_key(frame: &SocketFrame, key: &SessionKey) -> SessionKey {
+        let session = frame
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(SESSION_HEADER))
+            .map(|(_, session)| session.clone());
+        SessionKey {
+            project: key.project.clone(),
+            session: session.unwrap_or_else(|| key.session.clone()),
+        }
+    }
+
+    /// Frames don't come from a kind specific endpoint, so they're routed by the kind their payload records.
+    pub fn ingest_frame(
+        frame: SocketFrame,
+        ingest: &Ingest,
+        key: SessionKey,
+    ) -> Result<(), Rejection> {
+        let Ingest {
+            sessions,
+            metrics,
+            limiter,
+            ..
+        } = ingest;
+        let headers = frame
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    HeaderValue::from_str(value).ok()?,
+                ))
+            })
+            .collect::<HeaderMap>();
+        let body = decode_body(&headers, frame.body.into(), limiter.limits.max_body_bytes)?;
+        match payload_kind(&body, content_type(&headers))? {
+            PayloadKind::Snapshot => store_snapshot(sessions, metrics, key, &headers, &body),
+            PayloadKind::Mutations => store_mutations(sessions, metrics, key, &headers, &body),
+            PayloadKind::Events => store_events(sessions, metrics, key, &headers, &body),
+        }
+    }
+
+    /// Streams a session to a live viewer as bincode payloads, starting with the latest keyframe and the mutations since.
+    /// Only sessions a recorder has uploaded to can be followed, others are answered with 404.
+    pub async fn spectate(
+        upgrade: WebSocketUpgrade,
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+    ) -> Response {
+        // subscribing under the same lock as reading the catch up means nothing falls between them.
+        let subscribe = move || {
+            let sessions = sessions.read().ok()?;
+            let session = session(&sessions, &project, &session_id)?;
+            Some((session.live.subscribe(), session.catch_up()))
+        };
+        let Some(mut subscribed) = subscribe() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        upgrade.on_upgrade(move |mut socket: WebSocket| async move {
+            loop {
+                let (mut live, catch_up) = subscribed;
+                for payload in catch_up {
+                    if socket.send(Message::Binary(payload.into())).await.is_err() {
+                        return;
+                    }
+                }
+                loop {
+                    match live.recv().await {
+                        Ok(payload) => {
+                            if socket.send(Message::Binary(payload.into())).await.is_err() {
+                                return;
+                            }
+                        }
+                        // the viewer missed payloads, start it over from the latest keyframe.
+                        Err(RecvError::Lagged(_)) => break,
+                        Err(RecvError::Closed) => return,
+                    }
+                }
+                // the session was purged while it was followed.
+                let Some(resubscribed) = subscribe() else {
+                    return;
+                };
+                subscribed = resubscribed;
+            }
+        })
+    }
+
+    /// Guards every /api/projects/:project route. The read key is sent as a bearer token, or as key in the query
+    /// string by what can't set headers, like the dashboard's iframes and sockets.
+    pub async fn authorize_read(
+        Extension(ingest): Extension<Ingest>,
+        Path(params): Path<HashMap<String, String>>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let key = header(&headers, AUTHORIZATION.as_str())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .or(query.get("key").map(String::as_str));
+        let project = params.get("project").map_or("", String::as_str);
+        match ingest.projects.authorize_read(project, key) {
+            Ok(()) => next.run(request).await,
+            Err(status) => status.into_response(),
+        }
+    }
+
+    /// Purges what each project's retention no longer keeps, or only reports it when dry_run is set.
+    pub fn purge(
+        sessions: &Sessions,
+        projects: &Projects,
+        only_project: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<PurgeReport>, StatusCode> {
+        let mut sessions = sessions
+            .write()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let now = now_millis();
+        let mut reports = Vec::new();
+        for (project, project_sessions) in sessions.iter_mut() {
+            if only_project.is_some_and(|only| only != project) {
+                continue;
+            }
+            let stored = project_sessions
+                .iter()
+                .map(|(id, session)| {
+                    StoredSession::new(
+                        id.clone(),
+                        session.span,
+                        session.created_at,
+                        session.bytes,
+                        session.errors > 0,
+                    )
+                })
+                .collect();
+            let purged = retention::plan(&projects.retention(project), now, stored);
+            if !dry_run {
+                for session in &purged {
+                    project_sessions.remove(&session.id);
+                }
+            }
+            let (kept, kept_bytes) = project_sessions
+                .iter()
+                // a dry run still holds what it would purge.
+                .filter(|(id, _)| !purged.iter().any(|session| &session.id == *id))
+                .fold((0, 0), |(count, bytes), (_, session)| {
+                    (count + 1, bytes + session.bytes)
+                });
+            reports.push(PurgeReport {
+                project: project.clone(),
+                dry_run,
+                purged,
+                kept,
+                kept_bytes,
+            });
+        }
+        sessions.retain(|_, project_sessions| !project_sessions.is_empty());
+        Ok(reports)
+    }
+
+    /// Runs purge on schedule for as long as the server runs.
+    pub async fn purge_periodically(
+        sessions: Sessions,
+        projects: Projects,
+        schedule: PurgeSchedule,
+    ) {
+        let mut interval = tokio::time::interval(schedule.interval);
+        loop {
+            interval.tick().await;
+            let Ok(reports) = purge(&sessions, &projects, None, schedule.dry_run) else {
+                continue;
+            };
+            for report in reports.iter().filter(|report| !report.purged.is_empty()) {
+                log!(
+                    "{} {} sessions ({} bytes) of project {}, keeping {} ({} bytes)",
+                    if report.dry_run {
+                        "would purge"
+                    } else {
+                        "purged"
+                    },
+                    report.purged.len(),
+                    report.purged_bytes(),
+                    report.project,
+                    report.kept,
+                    report.kept_bytes,
+                );
+            }
+        }
+    }
+
+    /// What retention would purge from the project right now, without purging it.
+    pub async fn retention_report(
+        Extension(ingest): Extension<Ingest>,
+        Path(project): Path<String>,
+    ) -> Result<Json<PurgeReport>, StatusCode> {
+        let report = purge(&ingest.sessions, &ingest.projects, Some(&project), true)?
+            .pop()
+            .unwrap_or_else(|| PurgeReport {
+                project,
+                dry_run: true,
+                ..Default::default()
+            });
+        Ok(Json(report))
+    }
+
+    /// Recorded times are unix millis, either end can be left open.
+    #[derive(Deserialize)]
+    pub struct TimeWindow {
+        pub from: Option<f64>,
+        pub to: Option<f64>,
+    }
+
+    impl TimeWindow {
+        fn contains(&self, time: f64) -> bool {
+            self.from.is_none_or(|from| time >= from) && self.to.is_none_or(|to| time <= to)
+        }
+    }
+
+    /// Newest first, unless the filter sorts otherwise.
+    pub async fn list_sessions(
+        Extension(sessions): Extension<Sessions>,
+        Path(project): Path<String>,
+        Query(filter): Query<SessionFilter>,
+    ) -> Result<Json<SessionPage>, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let project_sessions = sessions.get(&project).into_iter().flatten();
+        Ok(Json(
+            filter.page(
+                project_sessions
+                    .filter(|(_, session)| !session.is_empty())
+                    .map(|(id, session)| session.summary(id)),
+            ),
+        ))
+    }
+
+    pub async fn session_detail(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+    ) -> Result<Json<SessionSummary>, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let session = session(&sessions, &project, &session_id)
+            .filter(|session| !session.is_empty())
+            .ok_or(StatusCode::NOT_FOUND)?;
+        Ok(Json(session.detail(&session_id)))
+    }
+
+    #[derive(Deserialize)]
+    pub struct At {
+        /// Millis from the start of the session, the end when left out.
+        pub t: Option<f64>,
+    }
+
+    /// The page as it was t millis into the session, as a static HTML document. Scripts are left out of the
+    /// document and blocked by its Content-Security-Policy in case any got through.
+    pub async fn session_html(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+        Query(at): Query<At>,
+    ) -> Result<Response, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let (keyframe, dom) = session(&sessions, &project, &session_id)
+            .and_then(|session| session.dom_at(at.t.unwrap_or(f64::MAX)))
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let document = html::render(&dom, keyframe.url.as_deref());
+        Ok((
+            [
+                (CONTENT_TYPE, "text/html; charset=utf-8"),
+                (
+                    CONTENT_SECURITY_POLICY,
+                    "script-src 'none'; object-src 'none'; frame-ancestors 'self'",
+                ),
+            ],
+            document,
+        )
+            .into_response())
+    }
+
+    /// What would break the session's replay, see client_capture::validate.
+    pub async fn session_validation(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+    ) -> Result<Json<ValidationReport>, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let session = session(&sessions, &project, &session_id)
+            .filter(|session| !session.is_empty())
+            .ok_or(StatusCode::NOT_FOUND)?;
+        Ok(Json(session.validate()))
+    }
+
+    /// Every page of the project with clicks on it, most clicked first.
+    pub async fn list_heatmaps(
+        Extension(sessions): Extension<Sessions>,
+        Path(project): Path<String>,
+    ) -> Result<Json<Vec<HeatmapPage>>, StatusCode> {
+        // the click counts of each session's pages, counted under the lock and added up after.
+        let counts = {
+            let sessions = sessions
+                .read()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            sessions
+                .get(&project)
+                .into_iter()
+                .flat_map(HashMap::values)
+                .map(|session| {
+                    session
+                        .keyframes
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, keyframe)| {
+                            let url = keyframe.url.as_deref().map(heatmap::page_url)?;
+                            Some((url, session.page(index).click_count()))
+                        })
+                        .filter(|(_, clicks)| *clicks > 0)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut pages = HashMap::<String, HeatmapPage>::new();
+        for session in counts {
+            let mut counted = Vec::new();
+            for (url, clicks) in session {
+                let page = pages.entry(url.clone()).or_insert_with(|| HeatmapPage {
+                    url: url.clone(),
+                    clicks: 0,
+                    sessions: 0,
+                });
+                page.clicks += clicks;
+                if !counted.contains(&url) {
+                    page.sessions += 1;
+                    counted.push(url);
+                }
+            }
+        }
+        let mut pages = pages.into_values().collect::<Vec<_>>();
+        pages.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.url.cmp(&b.url)));
+        Ok(Json(pages))
+    }
+
+    #[derive(Deserialize)]
+    pub struct HeatmapQuery {
+        /// The page, its query and fragment are ignored.
+        pub url: String,
+    }
+
+    /// The clicks on a page across the project's sessions, with the latest recording of the page to show them over.
+    pub async fn page_heatmap(
+        Extension(sessions): Extension<Sessions>,
+        Path(project): Path<String>,
+        Query(query): Query<HeatmapQuery>,
+    ) -> Result<Json<Heatmap>, StatusCode> {
+        let url = heatmap::page_url(&query.url);
+        // pages are copied out, replaying them to find what was clicked would hold up ingest.
+        let mut pages = Vec::new();
+        let mut latest: Option<(f64, HeatmapSnapshot)> = None;
+        {
+            let sessions = sessions
+                .read()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            for (id, session) in sessions.get(&project).into_iter().flatten() {
+                for (index, keyframe) in session.keyframes.iter().enumerate() {
+                    if keyframe.url.as_deref().map(heatmap::page_url).as_ref() != Some(&url) {
+                        continue;
+                    }
+                    let page = session.page(index);
+                    let width = page.width();
+                    if page.click_count() > 0 {
+                        pages.push(OwnedPage::from(page));
+                    }
+                    let Some(width) = width else {
+                        continue;
+                    };
+                    if latest
+                        .as_ref()
+                        .is_none_or(|(time, _)| keyframe.time_origin > *time)
+                    {
+                        latest = Some((
+                            keyframe.time_origin,
+                            HeatmapSnapshot {
+                                session: id.clone(),
+                                offset: keyframe.time_origin - session.keyframes[0].time_origin,
+                                width,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        let clicks = pages
+            .iter()
+            .flat_map(|page| heatmap::clicks(&page.page()))
+            .collect::<Vec<_>>();
+        Ok(Json(heatmap::heatmap(
+            url,
+            clicks,
+            latest.map(|(_, snapshot)| snapshot),
+        )))
+    }
+
+    /// Recordings are sent in the first codec the Accept header lists, JSON if it lists none of them.
+    fn accepted_codec(headers: &HeaderMap) -> &'static dyn Codec {
+        header(headers, ACCEPT.as_str())
+            .and_then(|accept| accept.split(',').find_map(codec_for_content_type))
+            .unwrap_or(&JsonCodec)
+    }
+
+    fn encoded(codec: &dyn Codec, body: Vec<u8>) -> Response {
+        ([(CONTENT_TYPE, codec.content_type())], body).into_response()
+    }
+
+    /// The snapshot of the keyframe at index, keyframes are listed in the session's detail.
+    pub async fn session_keyframe(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id, index)): Path<(String, String, usize)>,
+        headers: HeaderMap,
+    ) -> Result<Response, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let keyframe = session(&sessions, &project, &session_id)
+            .and_then(|session| session.keyframes.get(index))
+            .ok_or(StatusCode::NOT_FOUND)?;
+        let codec = accepted_codec(&headers);
+        Ok(encoded(codec, codec.encode_snapshot(&keyframe.snapshot)))
+    }
+
+    /// The mutations recorded within the window, in the order they arrived.
+    pub async fn session_mutations(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+        Query(window): Query<TimeWindow>,
+        headers: HeaderMap,
+    ) -> Result<Response, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let session = session(&sessions, &project, &session_id).ok_or(StatusCode::NOT_FOUND)?;
+        let mutations = session
+            .mutations
+            .iter()
+            .zip(&session.mutation_times)
+            .filter(|(_, time)| window.contains(**time))
+            .map(|(mutation, _)| mutation.clone())
+            .collect::<Vec<_>>();
+        let codec = accepted_codec(&headers);
+        Ok(encoded(codec, codec.encode_mutations(&mutations)))
+    }
+
+    /// The events recorded within the window, with the millis they were captured at.
+    pub async fn session_events(
+        Extension(sessions): Extension<Sessions>,
+        Path((project, session_id)): Path<(String, String)>,
+        Query(window): Query<TimeWindow>,
+        headers: HeaderMap,
+    ) -> Result<Response, StatusCode> {
+        let sessions = sessions
+            .read()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let session = session(&sessions, &project, &session_id).ok_or(StatusCode::NOT_FOUND)?;
+        let events = session
+            .events
+            .iter()
+            .zip(&session.event_times)
+            .filter(|(_, time)| window.contains(**time))
+            .map(|(event, _)| *event)
+            .collect::<Vec<_>>();
+        let codec = accepted_codec(&headers);
+        Ok(encoded(codec, codec.encode_events(&events)))
+    }
 }
 #[cfg(feature = "ssr")]
 #[tokio::main]
@@ -45,30 +1111,103 @@ async fn main() {
     // Generate the list of routes in your Leptos App
     let routes = generate_route_list(App);
 
-    let app = Router::new()
+    let projects = Projects::from_env().expect("valid REPLAY_PROJECTS");
+    if projects.is_open() {
+        log!("REPLAY_PROJECTS isn't set, taking uploads without an api key");
+    }
+    let limiter = Arc::new(Limiter::new(IngestLimits::from_env()));
+    let sessions = Sessions::default();
+    tokio::spawn(purge_periodically(
+        sessions.clone(),
+        projects.clone(),
+        PurgeSchedule::from_env(),
+    ));
+    // recorders upload from the sites they record, which are allowed per project.
+    let ingest = Router::new()
         .route("/api/ingest_snapshot", post(ingest_snapshot))
         .route("/api/ingest_mutation", post(ingest_mutation))
+        .route("/api/ingest_events", post(ingest_events))
+        .layer(DefaultBodyLimit::max(limiter.limits.max_body_bytes))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::predicate({
+                    let projects = projects.clone();
+                    move |origin, request| {
+                        let query = Query::<HashMap<String, String>>::try_from_uri(&request.uri)
+                            .map(|Query(query)| query)
+                            .unwrap_or_default();
+                        projects.allows_origin(query.get("key").map(String::as_str), origin)
+                    }
+                }))
+                .allow_methods([Method::POST])
+                .allow_headers([
+                    CONTENT_TYPE,
+                    CONTENT_ENCODING,
+                    HeaderName::from_static(PAGE_URL_HEADER),
+                    HeaderName::from_static(TIME_ORIGIN_HEADER),
+                    HeaderName::from_static(SEQ_HEADER),
+                    HeaderName::from_static(DROPPED_HEADER),
+                ]),
+        );
+
+    // the session API, only for who holds the project's read key.
+    let reads = Router::new()
+        .route("/api/projects/:project/sessions", get(list_sessions))
+        .route("/api/projects/:project/sessions/:id", get(session_detail))
+        .route(
+            "/api/projects/:project/sessions/:id/keyframes/:index",
+            get(session_keyframe),
+        )
+        .route(
+            "/api/projects/:project/sessions/:id/mutations",
+            get(session_mutations),
+        )
+        .route(
+            "/api/projects/:project/sessions/:id/events",
+            get(session_events),
+        )
+        .route(
+            "/api/projects/:project/sessions/:id/html",
+            get(session_html),
+        )
+        .route(
+            "/api/projects/:project/sessions/:id/validation",
+            get(session_validation),
+        )
+        .route("/api/projects/:project/sessions/:id/live", get(spectate))
+        .route("/api/projects/:project/retention", get(retention_report))
+        .route("/api/projects/:project/heatmaps", get(list_heatmaps))
+        .route("/api/projects/:project/heatmap", get(page_heatmap))
+        .route_layer(middleware::from_fn(authorize_read));
+
+    let app = Router::new()
+        .merge(ingest)
+        .merge(reads)
+        .route("/api/socket", get(ingest_socket))
         .leptos_routes(&leptos_options, routes, {
             let leptos_options = leptos_options.clone();
             move || shell(leptos_options.clone())
         })
         .fallback(leptos_axum::file_and_error_handler(shell))
         .with_state(leptos_options)
-        .layer(Extension(Arc::new(RwLock::new(HashMap::<
-            u32,
-            SerializedNode,
-        >::new()))))
-        .layer(Extension(Arc::new(RwLock::new(
-            Vec::<MutationVariant>::new(),
-        ))));
+        .layer(Extension(sessions.clone()))
+        .layer(Extension(Ingest {
+            sessions,
+            metrics: Metrics::from_env(),
+            projects,
+            limiter,
+        }));
 
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
     log!("listening on http://{}", &addr);
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 #[cfg(not(feature = "ssr"))]
@@ -0,0 +1,408 @@
+//! Per-session time series of what recorders upload, written to InfluxDB as line protocol.
+//! Metrics are only written when the server is built with the influxdb feature and INFLUXDB_URL is set.
+//! Points are tagged by project and kind only, the session is a field, every tag value starts a series of its own.
+use client_capture::{CaptureEvent, MutationVariant};
+use tokio::sync::mpsc;
+
+use crate::projects::SessionKey;
+
+/// A line of line protocol, https://docs.influxdata.com/influxdb/v1/write_protocols/line_protocol_reference/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Point {
+    measurement: &'static str,
+    tags: Vec<(&'static str, String)>,
+    fields: Vec<(&'static str, Field)>,
+    /// Unix millis.
+    time: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Point {
+    pub fn new(measurement: &'static str, time: f64) -> Self {
+        Self {
+            measurement,
+            tags: Vec::new(),
+            fields: Vec::new(),
+            time,
+        }
+    }
+    pub fn tag(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.tags.push((key, value.into()));
+        self
+    }
+    pub fn field(mut self, key: &'static str, value: Field) -> Self {
+        self.fields.push((key, value));
+        self
+    }
+    /// The point in line protocol with millisecond precision, without the trailing newline.
+    pub fn line(&self) -> String {
+        let mut line = escape(self.measurement, &[',', ' ']);
+        for (key, value) in &self.tags {
+            // empty tag values aren't allowed, the tag is left out instead.
+            if !value.is_empty() {
+                line += &format!(",{key}={}", escape(value, &[',', '=', ' ']));
+            }
+        }
+        let fields = self
+            .fields
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Field::Int(value) => format!("{value}i"),
+                    Field::Float(value) => format!("{value:?}"),
+                    Field::Str(value) => format!("\"{}\"", escape(value, &['"'])),
+                };
+                format!("{}={value}", escape(key, &[',', '=', ' ']))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{line} {fields} {}", self.time as i64)
+    }
+}
+
+/// Backslash escapes special, and backslashes themselves so they can't escape what follows.
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        if char == '\\' || special.contains(&char) {
+            escaped.push('\\');
+        }
+        escaped.push(char);
+    }
+    escaped
+}
+
+/// Where metrics are sent, cloned into every handler that records them.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    sender: Option<mpsc::Sender<Point>>,
+}
+
+impl Metrics {
+    /// Metrics that go nowhere.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+    /// Starts writing to InfluxDB if INFLUXDB_URL is set, see InfluxConfig::from_env.
+    /// Must be called from within the tokio runtime.
+    pub fn from_env() -> Self {
+        #[cfg(feature = "influxdb")]
+        if let Some(config) = influx::InfluxConfig::from_env() {
+            let (sender, receiver) = mpsc::channel(influx::QUEUE_CAPACITY);
+            tokio::spawn(influx::write(config, receiver));
+            return Self {
+                sender: Some(sender),
+            };
+        }
+        Self::disabled()
+    }
+    /// Queues the points, they're only built when metrics are enabled.
+    pub fn record(&self, points: impl FnOnce() -> Vec<Point>) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        for point in points() {
+            if sender.try_send(point).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// The size of an uploaded chunk and how many nodes, mutations or events it held.
+pub fn chunk(key: &SessionKey, kind: &'static str, bytes: usize, items: usize, time: f64) -> Point {
+    Point::new("capture_chunk", time)
+        .tag("project", &key.project)
+        .tag("kind", kind)
+        .field("session", Field::Str(key.session.clone()))
+        .field("bytes", Field::Int(bytes as i64))
+        .field("items", Field::Int(items as i64))
+}
+
+pub fn page_view(key: &SessionKey, url: Option<&str>, time: f64) -> Point {
+    Point::new("capture_page_view", time)
+        .tag("project", &key.project)
+        .field("session", Field::Str(key.session.clone()))
+        .field("count", Field::Int(1))
+        .field("url", Field::Str(url.unwrap_or_default().to_string()))
+}
+
+/// How many mutations a chunk held, and how many per second were recorded over the time the chunk covers.
+pub fn mutations(key: &SessionKey, mutations: &[MutationVariant], time: f64) -> Point {
+    let mut point = Point::new("capture_mutations", time)
+        .tag("project", &key.project)
+        .field("session", Field::Str(key.session.clone()))
+        .field("count", Field::Int(mutations.len() as i64));
+    let (first, last) = mutations
+        .iter()
+        .map(MutationVariant::millis)
+        .fold((f64::MAX, f64::MIN), |(first, last), millis| {
+            (first.min(millis), last.max(millis))
+        });
+    if last > first {
+        let per_second = mutations.len() as f64 / ((last - first) / 1000.);
+        point = point.field("per_second", Field::Float(per_second));
+    }
+    point
+}
+
+/// A point per kind of event in a chunk, with how many there were. Errors are also counted on their own.
+pub fn events(key: &SessionKey, events: &[(f64, CaptureEvent)], time: f64) -> Vec<Point> {
+    let mut counts = Vec::<(&'static str, i64)>::new();
+    for (_, event) in events {
+        let name = event_name(event);
+        match counts.iter_mut().find(|(counted, _)| *counted == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((name, 1)),
+        }
+    }
+    let errors = counts
+        .iter()
+        .find(|(name, _)| *name == "error")
+        .map(|(_, count)| *count);
+    let mut points = counts
+        .into_iter()
+        .map(|(name, count)| {
+            Point::new("capture_events", time)
+                .tag("project", &key.project)
+                .tag("type", name)
+                .field("session", Field::Str(key.session.clone()))
+                .field("count", Field::Int(count))
+        })
+        .collect::<Vec<_>>();
+    if let Some(errors) = errors {
+        points.push(
+            Point::new("capture_errors", time)
+                .tag("project", &key.project)
+                .field("session", Field::Str(key.session.clone()))
+                .field("count", Field::Int(errors)),
+        );
+    }
+    points
+}
+
+fn event_name(event: &CaptureEvent) -> &'static str {
+    match event {
+        CaptureEvent::MouseMove { .. } => "mouse_move",
+        CaptureEvent::MouseClick { .. } | CaptureEvent::Click { .. } => "click",
+        CaptureEvent::WindowResize { .. } => "resize",
+        CaptureEvent::TouchMove { .. } => "touch_move",
+        CaptureEvent::Scoll {} => "scroll",
+        CaptureEvent::Error { .. } => "error",
+    }
+}
+
+#[cfg(feature = "influxdb")]
+mod influx {
+    use std::time::Duration;
+
+    use leptos::logging::warn;
+    use tokio::{sync::mpsc, time::interval};
+
+    use super::Point;
+
+    /// Points waiting to be written. Once the writer falls this far behind, new points are dropped instead of
+    /// holding up ingest.
+    pub const QUEUE_CAPACITY: usize = 10_000;
+    /// Points are written at least this often, unless there are none.
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+    /// Or as soon as this many are waiting.
+    const BATCH_LEN: usize = 5_000;
+
+    pub struct InfluxConfig {
+        /// Where InfluxDB listens, like http://localhost:8086.
+        url: String,
+        database: String,
+        /// Sent as "Authorization: Token", which InfluxDB 2 accepts on its 1.x compatible write endpoint.
+        token: Option<String>,
+    }
+
+    impl InfluxConfig {
+        /// INFLUXDB_URL, INFLUXDB_DATABASE (defaults to "capture") and INFLUXDB_TOKEN.
+        pub fn from_env() -> Option<Self> {
+            Some(Self {
+                url: std::env::var("INFLUXDB_URL").ok()?,
+                database: std::env::var("INFLUXDB_DATABASE")
+                    .unwrap_or_else(|_| "capture".to_string()),
+                token: std::env::var("INFLUXDB_TOKEN").ok(),
+            })
+        }
+    }
+
+    /// Writes points in batches until every Metrics is dropped. A batch InfluxDB doesn't take is logged and dropped,
+    /// metrics aren't worth holding memory for while it's down.
+    pub async fn write(config: InfluxConfig, mut points: mpsc::Receiver<Point>) {
+        let client = reqwest::Client::new();
+        let mut batch = Vec::new();
+        let mut flush = interval(FLUSH_INTERVAL);
+        loop {
+            let due = tokio::select! {
+                point = points.recv() => match point {
+                    Some(point) => {
+                        batch.push(point.line());
+                        batch.len() >= BATCH_LEN
+                    }
+                    None => {
+                        send(&client, &config, batch).await;
+                        return;
+                    }
+                },
+                _ = flush.tick() => true,
+            };
+            if due {
+                send(&client, &config, std::mem::take(&mut batch)).await;
+                flush.reset();
+            }
+        }
+    }
+
+    async fn send(client: &reqwest::Client, config: &InfluxConfig, lines: Vec<String>) {
+        if lines.is_empty() {
+            return;
+        }
+        let mut request = client
+            .post(format!("{}/write", config.url.trim_end_matches('/')))
+            .query(&[("db", config.database.as_str()), ("precision", "ms")])
+            .body(lines.join("\n"));
+        if let Some(token) = &config.token {
+            request = request.header("Authorization", format!("Token {token}"));
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => warn!("InfluxDB answered {}", response.status()),
+            Err(err) => warn!("couldn't write to InfluxDB: {err}"),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{
+            io::{BufRead, BufReader, Read, Write},
+            net::TcpListener,
+            thread,
+        };
+
+        use super::*;
+        use crate::{
+            metrics::{page_view, Field},
+            projects::SessionKey,
+        };
+
+        /// Answers one request with 204 and returns it, head and body.
+        fn accept_one(listener: TcpListener) -> thread::JoinHandle<(String, String)> {
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    head += &line;
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+                (head, String::from_utf8(body).unwrap())
+            })
+        }
+
+        #[test]
+        fn writes_pending_points_as_line_protocol() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let config = InfluxConfig {
+                url: format!("http://{}/", listener.local_addr().unwrap()),
+                database: "capture test".to_string(),
+                token: Some("secret".to_string()),
+            };
+            let request = accept_one(listener);
+            let key = SessionKey {
+                project: "shop, eu=1".to_string(),
+                session: "a \"b\"".to_string(),
+            };
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            sender
+                .try_send(page_view(
+                    &key,
+                    Some("https://x.test/?q=\\"),
+                    1_700_000_000_123.,
+                ))
+                .unwrap();
+            sender
+                .try_send(Point::new("capture chunk", 5.).field("n", Field::Int(2)))
+                .unwrap();
+            // every sender is gone, so write sends what it has and returns.
+            drop(sender);
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(write(config, receiver));
+            let (head, body) = request.join().unwrap();
+            assert!(
+                head.starts_with("POST /write?db=capture+test&precision=ms HTTP/1.1\r\n"),
+                "{head}"
+            );
+            assert!(head.contains("authorization: Token secret\r\n"), "{head}");
+            let lines = body.lines().collect::<Vec<_>>();
+            assert_eq!(
+                lines,
+                [
+                    r#"capture_page_view,project=shop\,\ eu\=1 session="a \"b\"",count=1i,url="https://x.test/?q=\\" 1700000000123"#,
+                    r#"capture\ chunk n=2i 5"#,
+                ]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_each_part_of_a_line() {
+        let point = Point::new("a b,c", 1.5)
+            .tag("tag key", "x=y, z\\")
+            .tag("empty", "")
+            .field("field,key", Field::Str("say \"hi\" \\".to_string()))
+            .field("int", Field::Int(-3))
+            .field("float", Field::Float(2.))
+            .field("fraction", Field::Float(0.25));
+        assert_eq!(
+            point.line(),
+            r#"a\ b\,c,tag key=x\=y\,\ z\\ field\,key="say \"hi\" \\",int=-3i,float=2.0,fraction=0.25 1"#
+        );
+    }
+
+    #[test]
+    fn keeps_sessions_out_of_the_tags() {
+        let key = SessionKey {
+            project: "shop".to_string(),
+            session: "s1".to_string(),
+        };
+        let point = chunk(&key, "mutations", 10, 2, 3.);
+        assert_eq!(
+            point.line(),
+            r#"capture_chunk,project=shop,kind=mutations session="s1",bytes=10i,items=2i 3"#
+        );
+        assert!(point.tags.iter().all(|(key, _)| *key != "session"));
+    }
+}
@@ -0,0 +1,305 @@
+//! Projects separate the recordings of different sites. Recorders send their project's API key with every upload,
+//! and the key decides which project the upload is stored under. Reading a project's sessions takes its read key.
+use std::sync::Arc;
+
+use http::{HeaderValue, StatusCode};
+use serde::Deserialize;
+
+pub use crate::api::DEFAULT_PROJECT;
+use crate::retention::Retention;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Project {
+    pub id: String,
+    pub api_key: String,
+    /// What the session API and the dashboard are read with. Without one the api_key reads too, but every page that
+    /// records into the project carries that, so set a read key unless the recordings may be public.
+    pub read_key: Option<String>,
+    /// Origins pages recording into the project are served from, like "https://shop.example". "*" allows any.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Falls back to the server's retention, see Retention::from_env.
+    pub retention: Option<Retention>,
+}
+
+impl Project {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+}
+
+/// Identifies a session across projects, session ids are only unique within a project.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub project: String,
+    pub session: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProjectsError {
+    #[error("couldn't read projects: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid projects: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("api key of project {0} is used by another project")]
+    DuplicateKey(String),
+}
+
+/// The configured projects, cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct Projects {
+    /// None when no projects are configured, then every upload is taken into DEFAULT_PROJECT without a key.
+    projects: Option<Arc<Vec<Project>>>,
+    /// For projects without their own.
+    retention: Retention,
+}
+
+impl Projects {
+    /// Reads the JSON array of projects at the path in REPLAY_PROJECTS, like
+    /// `[{"id": "shop", "api_key": "...", "read_key": "...", "allowed_origins": ["https://shop.example"], "retention": {"max_age_days": 30}}]`.
+    pub fn from_env() -> Result<Self, ProjectsError> {
+        let projects = match std::env::var("REPLAY_PROJECTS") {
+            Ok(path) => Self::from_json(&std::fs::read_to_string(path)?)?,
+            Err(_) => Self::default(),
+        };
+        Ok(Self {
+            retention: Retention::from_env(),
+            ..projects
+        })
+    }
+    pub fn from_json(json: &str) -> Result<Self, ProjectsError> {
+        let projects = serde_json::from_str::<Vec<Project>>(json)?;
+        for (index, project) in projects.iter().enumerate() {
+            if projects[..index]
+                .iter()
+                .any(|other| other.api_key == project.api_key)
+            {
+                return Err(ProjectsError::DuplicateKey(project.id.clone()));
+            }
+        }
+        Ok(Self {
+            projects: Some(Arc::new(projects)),
+            retention: Retention::default(),
+        })
+    }
+    pub fn is_open(&self) -> bool {
+        self.projects.is_none()
+    }
+    /// How long the project's sessions are kept.
+    pub fn retention(&self, project: &str) -> Retention {
+        self.projects
+            .iter()
+            .flat_map(|projects| projects.iter())
+            .find(|configured| configured.id == project)
+            .and_then(|configured| configured.retention)
+            .unwrap_or(self.retention)
+    }
+    fn find(&self, api_key: &str) -> Option<&Project> {
+        self.projects
+            .as_ref()?
+            .iter()
+            .find(|project| project.api_key == api_key)
+    }
+    /// The project an upload with api_key from origin is stored under. Uploads from outside a browser
+    /// don't send an Origin, those only need the key.
+    pub fn authorize(
+        &self,
+        api_key: Option<&str>,
+        origin: Option<&str>,
+    ) -> Result<String, StatusCode> {
+        if self.is_open() {
+            return Ok(DEFAULT_PROJECT.to_string());
+        }
+        let project = api_key
+            .and_then(|api_key| self.find(api_key))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        if origin.is_some_and(|origin| !project.allows_origin(origin)) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(project.id.clone())
+    }
+    /// Whether key may read the project's sessions. A key of another project is forbidden, no key or an unknown
+    /// one is unauthorized. Without projects the default project is open to read like it's open to upload to.
+    pub fn authorize_read(&self, project: &str, key: Option<&str>) -> Result<(), StatusCode> {
+        let Some(projects) = &self.projects else {
+            return Ok(());
+        };
+        let key = key.ok_or(StatusCode::UNAUTHORIZED)?;
+        let reads = |configured: &Project| {
+            configured
+                .read_key
+                .as_deref()
+                .unwrap_or(&configured.api_key)
+                == key
+        };
+        if projects
+            .iter()
+            .any(|configured| configured.id == project && reads(configured))
+        {
+            return Ok(());
+        }
+        if projects.iter().any(reads) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Err(StatusCode::UNAUTHORIZED)
+    }
+    /// Whether a browser on origin may upload with api_key, what CORS answers preflights with.
+    pub fn allows_origin(&self, api_key: Option<&str>, origin: &HeaderValue) -> bool {
+        if self.is_open() {
+            return true;
+        }
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        api_key
+            .and_then(|api_key| self.find(api_key))
+            .is_some_and(|project| project.allows_origin(origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn projects() -> Projects {
+        Projects::from_json(
+            r#"[
+                {"id": "shop", "api_key": "shop-api", "read_key": "shop-read", "allowed_origins": ["https://shop.example"]},
+                {"id": "blog", "api_key": "blog-api", "allowed_origins": ["*"]},
+                {"id": "internal", "api_key": "internal-api"}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn uploads_need_a_projects_api_key() {
+        let projects = projects();
+        assert_eq!(projects.authorize(Some("shop-api"), None).unwrap(), "shop");
+        assert_eq!(projects.authorize(Some("blog-api"), None).unwrap(), "blog");
+        for key in [None, Some(""), Some("wrong"), Some("shop-read")] {
+            assert_eq!(
+                projects.authorize(key, None),
+                Err(StatusCode::UNAUTHORIZED),
+                "{key:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn uploads_from_a_browser_need_an_allowed_origin() {
+        let projects = projects();
+        let shop = Some("shop-api");
+        assert_eq!(
+            projects
+                .authorize(shop, Some("https://shop.example"))
+                .unwrap(),
+            "shop"
+        );
+        assert_eq!(
+            projects.authorize(shop, Some("https://evil.example")),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            projects
+                .authorize(Some("blog-api"), Some("https://anywhere.example"))
+                .unwrap(),
+            "blog"
+        );
+        // without an allow-list no browser may upload, only clients that send no Origin.
+        assert_eq!(
+            projects.authorize(Some("internal-api"), Some("https://shop.example")),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            projects.authorize(Some("internal-api"), None).unwrap(),
+            "internal"
+        );
+    }
+
+    #[test]
+    fn without_projects_everything_goes_to_the_default_project() {
+        let projects = Projects::default();
+        for key in [None, Some("anything")] {
+            assert_eq!(
+                projects
+                    .authorize(key, Some("https://any.example"))
+                    .unwrap(),
+                DEFAULT_PROJECT
+            );
+            assert_eq!(projects.authorize_read(DEFAULT_PROJECT, key), Ok(()));
+        }
+        let origin = HeaderValue::from_static("https://any.example");
+        assert!(projects.allows_origin(None, &origin));
+    }
+
+    #[test]
+    fn reads_need_the_projects_read_key() {
+        let projects = projects();
+        assert_eq!(projects.authorize_read("shop", Some("shop-read")), Ok(()));
+        // the api key is in every recorded page, it doesn't read once there's a read key.
+        assert_eq!(
+            projects.authorize_read("shop", Some("shop-api")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        // without one it does.
+        assert_eq!(projects.authorize_read("blog", Some("blog-api")), Ok(()));
+        assert_eq!(
+            projects.authorize_read("shop", Some("blog-api")),
+            Err(StatusCode::FORBIDDEN)
+        );
+        for key in [None, Some("wrong")] {
+            assert_eq!(
+                projects.authorize_read("shop", key),
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+    }
+
+    #[test]
+    fn the_default_project_isnt_readable_once_projects_are_configured() {
+        let projects = projects();
+        assert_eq!(
+            projects.authorize_read(DEFAULT_PROJECT, None),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            projects.authorize_read(DEFAULT_PROJECT, Some("wrong")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            projects.authorize_read(DEFAULT_PROJECT, Some("shop-read")),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            projects.authorize_read("missing", Some("shop-read")),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn preflights_match_the_keys_allow_list() {
+        let projects = projects();
+        let shop = HeaderValue::from_static("https://shop.example");
+        let other = HeaderValue::from_static("https://other.example");
+        assert!(projects.allows_origin(Some("shop-api"), &shop));
+        assert!(!projects.allows_origin(Some("shop-api"), &other));
+        assert!(projects.allows_origin(Some("blog-api"), &other));
+        assert!(!projects.allows_origin(Some("internal-api"), &shop));
+        assert!(!projects.allows_origin(Some("shop-read"), &shop));
+        assert!(!projects.allows_origin(None, &shop));
+        let opaque = HeaderValue::from_bytes(b"https://\xffshop.example").unwrap();
+        assert!(!projects.allows_origin(Some("blog-api"), &opaque));
+    }
+
+    #[test]
+    fn api_keys_are_unique() {
+        let duplicate = r#"[{"id": "a", "api_key": "key"}, {"id": "b", "api_key": "key"}]"#;
+        assert!(matches!(
+            Projects::from_json(duplicate),
+            Err(ProjectsError::DuplicateKey(id)) if id == "b"
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+//! How long recordings are kept. Sessions are purged once they're older than their project allows,
+//! or when the project holds more than it allows, oldest first.
+//! Sessions are held in memory only and the server stores no assets apart from them, purging a session
+//! frees everything that was recorded in it.
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::api::{PurgeReason, PurgedSession};
+
+const DAY_MILLIS: f64 = 86_400_000.;
+/// Sessions that recorded something this recently are still being recorded, size limits don't purge them.
+const ACTIVE_MILLIS: f64 = 5. * 60_000.;
+
+/// A project's retention, anything left out is kept forever.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub struct Retention {
+    /// Days after a session's last recording it's purged.
+    pub max_age_days: Option<f64>,
+    /// Sessions with errors are kept this many days instead, they're the ones support looks for.
+    pub error_max_age_days: Option<f64>,
+    /// The most a project's sessions may hold, as uploaded after decompression.
+    pub max_total_bytes: Option<usize>,
+}
+
+impl Retention {
+    /// Used for projects that don't set their own, and without REPLAY_PROJECTS. Read from
+    /// REPLAY_RETENTION_MAX_AGE_DAYS, REPLAY_RETENTION_ERROR_MAX_AGE_DAYS and REPLAY_RETENTION_MAX_TOTAL_BYTES.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok()?.parse().ok()
+        }
+        Self {
+            max_age_days: var("REPLAY_RETENTION_MAX_AGE_DAYS"),
+            error_max_age_days: var("REPLAY_RETENTION_ERROR_MAX_AGE_DAYS"),
+            max_total_bytes: var("REPLAY_RETENTION_MAX_TOTAL_BYTES"),
+        }
+    }
+    fn max_age_millis(&self, has_errors: bool) -> Option<f64> {
+        let days = if has_errors {
+            self.error_max_age_days.or(self.max_age_days)
+        } else {
+            self.max_age_days
+        };
+        days.map(|days| days * DAY_MILLIS)
+    }
+}
+
+/// How the background purge runs, from REPLAY_PURGE_INTERVAL_SECS (an hour by default)
+/// and REPLAY_PURGE_DRY_RUN, which only logs what would be purged.
+#[derive(Clone, Copy, Debug)]
+pub struct PurgeSchedule {
+    pub interval: Duration,
+    pub dry_run: bool,
+}
+
+impl PurgeSchedule {
+    pub fn from_env() -> Self {
+        Self {
+            interval: Duration::from_secs(
+                std::env::var("REPLAY_PURGE_INTERVAL_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(3600),
+            ),
+            dry_run: std::env::var("REPLAY_PURGE_DRY_RUN").is_ok_and(|dry_run| dry_run != "0"),
+        }
+    }
+}
+
+/// What retention needs to know about a stored session.
+pub struct StoredSession {
+    pub id: String,
+    pub ended_at: f64,
+    pub bytes: usize,
+    pub has_errors: bool,
+}
+
+impl StoredSession {
+    /// span is when the first and last thing was recorded, sessions nothing was recorded in age from created_at.
+    pub fn new(
+        id: String,
+        span: Option<(f64, f64)>,
+        created_at: f64,
+        bytes: usize,
+        has_errors: bool,
+    ) -> Self {
+        Self {
+            id,
+            ended_at: span.map_or(created_at, |(_, ended_at)| ended_at),
+            bytes,
+            has_errors,
+        }
+    }
+}
+
+/// The sessions retention purges at now. Expired sessions go first, then the oldest until the project fits,
+/// sessions without errors before those with.
+pub fn plan(
+    retention: &Retention,
+    now: f64,
+    mut sessions: Vec<StoredSession>,
+) -> Vec<PurgedSession> {
+    let mut purged = Vec::new();
+    sessions.retain(|session| {
+        let expired = retention
+            .max_age_millis(session.has_errors)
+            .is_some_and(|max_age| now - session.ended_at > max_age);
+        if expired {
+            purged.push(purge(session, PurgeReason::Expired));
+        }
+        !expired
+    });
+    if let Some(max_total_bytes) = retention.max_total_bytes {
+        let mut total = sessions.iter().map(|session| session.bytes).sum::<usize>();
+        sessions.retain(|session| now - session.ended_at > ACTIVE_MILLIS);
+        sessions.sort_by(|a, b| {
+            a.has_errors
+                .cmp(&b.has_errors)
+                .then(a.ended_at.total_cmp(&b.ended_at))
+        });
+        for session in sessions {
+            if total <= max_total_bytes {
+                break;
+            }
+            total -= session.bytes;
+            purged.push(purge(&session, PurgeReason::OverSize));
+        }
+    }
+    purged
+}
+
+fn purge(session: &StoredSession, reason: PurgeReason) -> PurgedSession {
+    PurgedSession {
+        id: session.id.clone(),
+        reason,
+        ended_at: session.ended_at,
+        bytes: session.bytes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projects::Projects;
+
+    const NOW: f64 = 100. * DAY_MILLIS;
+
+    fn ended(id: &str, days_ago: f64, bytes: usize, has_errors: bool) -> StoredSession {
+        let ended_at = NOW - days_ago * DAY_MILLIS;
+        StoredSession::new(
+            id.to_string(),
+            Some((ended_at - 60_000., ended_at)),
+            ended_at - 60_000.,
+            bytes,
+            has_errors,
+        )
+    }
+
+    fn purged(purged: &[PurgedSession]) -> Vec<(&str, PurgeReason)> {
+        purged
+            .iter()
+            .map(|session| (session.id.as_str(), session.reason))
+            .collect()
+    }
+
+    #[test]
+    fn sessions_older_than_the_max_age_expire() {
+        let retention = Retention {
+            max_age_days: Some(30.),
+            ..Retention::default()
+        };
+        let sessions = vec![
+            ended("old", 31., 10, false),
+            ended("recent", 29., 10, false),
+            ended("today", 0., 10, false),
+        ];
+        assert_eq!(
+            purged(&plan(&retention, NOW, sessions)),
+            [("old", PurgeReason::Expired)]
+        );
+    }
+
+    #[test]
+    fn sessions_with_errors_keep_longer() {
+        let retention = Retention {
+            max_age_days: Some(30.),
+            error_max_age_days: Some(90.),
+            ..Retention::default()
+        };
+        let sessions = vec![
+            ended("old", 31., 10, false),
+            ended("old-errors", 31., 10, true),
+            ended("ancient-errors", 91., 10, true),
+        ];
+        assert_eq!(
+            purged(&plan(&retention, NOW, sessions)),
+            [
+                ("old", PurgeReason::Expired),
+                ("ancient-errors", PurgeReason::Expired)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_sessions_age_from_when_they_were_created() {
+        let created_at = NOW - 31. * DAY_MILLIS;
+        let empty = StoredSession::new("empty".to_string(), None, created_at, 0, false);
+        assert_eq!(empty.ended_at, created_at);
+        let retention = Retention {
+            max_age_days: Some(30.),
+            ..Retention::default()
+        };
+        let fresh = StoredSession::new("fresh".to_string(), None, NOW, 0, false);
+        assert_eq!(
+            purged(&plan(&retention, NOW, vec![empty, fresh])),
+            [("empty", PurgeReason::Expired)]
+        );
+    }
+
+    #[test]
+    fn projects_over_size_lose_their_oldest_sessions_without_errors_first() {
+        let retention = Retention {
+            max_total_bytes: Some(100),
+            ..Retention::default()
+        };
+        let sessions = vec![
+            ended("oldest-errors", 5., 40, true),
+            ended("old", 4., 40, false),
+            ended("newer", 3., 40, false),
+            ended("newest", 2., 40, false),
+        ];
+        assert_eq!(
+            purged(&plan(&retention, NOW, sessions)),
+            [
+                ("old", PurgeReason::OverSize),
+                ("newer", PurgeReason::OverSize)
+            ]
+        );
+    }
+
+    #[test]
+    fn sessions_still_recording_arent_purged_for_size() {
+        let retention = Retention {
+            max_total_bytes: Some(10),
+            ..Retention::default()
+        };
+        let recording = StoredSession::new(
+            "recording".to_string(),
+            Some((NOW - 60_000., NOW - 1_000.)),
+            NOW - 60_000.,
+            100,
+            false,
+        );
+        assert!(plan(&retention, NOW, vec![recording]).is_empty());
+    }
+
+    #[test]
+    fn projects_override_the_servers_retention() {
+        let projects = Projects::from_json(
+            r#"[
+                {"id": "shop", "api_key": "shop-api", "retention": {"max_age_days": 7}},
+                {"id": "blog", "api_key": "blog-api"}
+            ]"#,
+        )
+        .unwrap();
+        let sessions = || vec![ended("week-old", 8., 10, false)];
+        assert_eq!(
+            purged(&plan(&projects.retention("shop"), NOW, sessions())),
+            [("week-old", PurgeReason::Expired)]
+        );
+        // without their own, projects keep what the server keeps, by default everything.
+        assert!(plan(&projects.retention("blog"), NOW, sessions()).is_empty());
+        assert!(plan(&Retention::default(), NOW, sessions()).is_empty());
+    }
+}